@@ -0,0 +1,582 @@
+//! Coroutines against threads and a minimal async executor
+//!
+//! Runs every scenario on each of the three and prints one CSV line per run:
+//!
+//! * `spawn`: start a task which returns right away, run it to completion and drop it
+//! * `ping_pong`: hand control back and forth between two parties, one round trip per op
+//! * `echo`: echo a message over each of many Unix socket pairs, one message per op
+//! * `producer_consumer`: pass values from one party to another, one value per op
+//!
+//! Run with `cargo bench --bench compare`, optionally followed by the names of the scenarios
+//! to run. The threads use channels and blocking sockets, the async baseline polls boxed
+//! futures in turn and sleeps in `poll(2)` on behalf of those waiting for a socket.
+
+extern crate coroutine;
+extern crate libc;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::env;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coroutine::Options;
+use coroutine::asymmetric::{Coroutine, Handle};
+use coroutine::fd::{self, Trigger};
+
+const SPAWNS: usize = 10_000;
+const PING_PONGS: usize = 100_000;
+const CONNECTIONS: usize = 10_000;
+const ECHO_ROUNDS: usize = 10;
+const VALUES: usize = 1_000_000;
+const MESSAGE: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+fn report(scenario: &str, runtime: &str, ops: usize, elapsed: Duration) {
+    println!("{},{},{},{},{:.1}",
+             scenario,
+             runtime,
+             ops,
+             elapsed.as_nanos(),
+             elapsed.as_nanos() as f64 / ops as f64);
+}
+
+fn time<F: FnOnce()>(f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+// As many socket pairs as the limit on open files allows, up to `CONNECTIONS`
+fn connections() -> Vec<(UnixStream, UnixStream)> {
+    let limit = unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        limit.rlim_cur = limit.rlim_max;
+        libc::setrlimit(libc::RLIMIT_NOFILE, &limit);
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+        limit.rlim_cur as usize
+    };
+    let n = CONNECTIONS.min(limit.saturating_sub(64) / 2);
+    if n < CONNECTIONS {
+        eprintln!("echo: {} connections only, raise the limit on open files", n);
+    }
+    (0..n).map(|_| UnixStream::pair().unwrap()).collect()
+}
+
+// Write a message to every client and read back all the echoes
+fn echo_round(clients: &[UnixStream]) {
+    let mut buf = [0u8; 32];
+    for mut client in clients {
+        client.write_all(MESSAGE).unwrap();
+    }
+    for mut client in clients {
+        client.read_exact(&mut buf).unwrap();
+    }
+}
+
+mod coroutines {
+    use super::*;
+
+    pub fn spawn() -> Duration {
+        time(|| {
+            for i in 0..SPAWNS {
+                let mut coro = Coroutine::spawn(|_, data| data + 1);
+                assert_eq!(coro.resume(i).unwrap(), i + 1);
+            }
+        })
+    }
+
+    pub fn ping_pong() -> Duration {
+        let mut coro = Coroutine::spawn(|coro, mut data| loop {
+            data = coro.yield_with(data + 1);
+        });
+        time(|| {
+            for i in 0..PING_PONGS {
+                assert_eq!(coro.resume(i).unwrap(), i + 1);
+            }
+        })
+    }
+
+    pub fn echo(pairs: Vec<(UnixStream, UnixStream)>) -> (usize, Duration) {
+        let (clients, servers): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        let n = clients.len();
+        let mut coros = servers.into_iter()
+            .map(|server| {
+                Coroutine::spawn_opts(move |coro, _| {
+                    let mut buf = [0u8; 32];
+                    loop {
+                        fd::wait_readable(coro, server.as_raw_fd(), Trigger::Level).unwrap();
+                        match (&server).read(&mut buf).unwrap() {
+                            0 => return 0,
+                            len => (&server).write_all(&buf[..len]).unwrap(),
+                        }
+                    }
+                },
+                                      Options {
+                                          stack_size: 32 * 1024,
+                                          ..Options::default()
+                                      })
+            })
+            .collect::<Vec<_>>();
+        for coro in &mut coros {
+            coro.resume(0).unwrap();
+        }
+
+        let elapsed = time(|| {
+            for _ in 0..ECHO_ROUNDS {
+                for mut client in &clients {
+                    client.write_all(MESSAGE).unwrap();
+                }
+                drive(&mut coros, n);
+                let mut buf = [0u8; 32];
+                for mut client in &clients {
+                    client.read_exact(&mut buf).unwrap();
+                }
+            }
+        });
+        drop(clients);
+        drive(&mut coros, n);
+        (n * ECHO_ROUNDS, elapsed)
+    }
+
+    // Poll for the parked coroutines until `wakeups` of them have been resumed
+    fn drive(coros: &mut [Handle], mut wakeups: usize) {
+        let mut pollfds = Vec::with_capacity(coros.len());
+        while wakeups > 0 {
+            pollfds.clear();
+            for coro in coros.iter_mut() {
+                let fd = fd::pending(coro).map_or(-1, |wait| wait.fds()[0].fd);
+                pollfds.push(libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            }
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+
+            for (coro, pollfd) in coros.iter_mut().zip(&pollfds) {
+                if pollfd.revents != 0 {
+                    let ready = fd::Ready::from_revents(pollfd.revents);
+                    fd::pending(coro).unwrap().set_ready(0, ready);
+                    coro.resume(0).unwrap();
+                    wakeups -= 1;
+                }
+            }
+        }
+    }
+
+    pub fn producer_consumer() -> Duration {
+        let mut producer = Coroutine::spawn(|coro, _| {
+            for i in 0..VALUES {
+                coro.yield_with(i);
+            }
+            VALUES
+        });
+        time(|| {
+            let mut sum = 0;
+            for value in &mut producer {
+                sum += value.unwrap();
+            }
+            assert!(sum > 0);
+        })
+    }
+}
+
+mod threads {
+    use super::*;
+
+    pub fn spawn() -> Duration {
+        time(|| {
+            for i in 0..SPAWNS {
+                assert_eq!(thread::spawn(move || i + 1).join().unwrap(), i + 1);
+            }
+        })
+    }
+
+    pub fn ping_pong() -> Duration {
+        let (ping, pings) = mpsc::sync_channel::<usize>(0);
+        let (pong, pongs) = mpsc::sync_channel::<usize>(0);
+        let peer = thread::spawn(move || {
+            for data in pings {
+                pong.send(data + 1).unwrap();
+            }
+        });
+        let elapsed = time(|| {
+            for i in 0..PING_PONGS {
+                ping.send(i).unwrap();
+                assert_eq!(pongs.recv().unwrap(), i + 1);
+            }
+        });
+        drop(ping);
+        peer.join().unwrap();
+        elapsed
+    }
+
+    pub fn echo(pairs: Vec<(UnixStream, UnixStream)>) -> (usize, Duration) {
+        let (clients, servers): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        let n = clients.len();
+        let peers = servers.into_iter()
+            .map(|mut server| {
+                thread::Builder::new()
+                    .stack_size(64 * 1024)
+                    .spawn(move || {
+                        let mut buf = [0u8; 32];
+                        loop {
+                            match server.read(&mut buf).unwrap() {
+                                0 => return,
+                                len => server.write_all(&buf[..len]).unwrap(),
+                            }
+                        }
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let elapsed = time(|| {
+            for _ in 0..ECHO_ROUNDS {
+                echo_round(&clients);
+            }
+        });
+        drop(clients);
+        for peer in peers {
+            peer.join().unwrap();
+        }
+        (n * ECHO_ROUNDS, elapsed)
+    }
+
+    pub fn producer_consumer() -> Duration {
+        let (tx, rx) = mpsc::sync_channel(1024);
+        time(|| {
+            let producer = thread::spawn(move || {
+                for i in 0..VALUES {
+                    tx.send(i).unwrap();
+                }
+            });
+            assert!(rx.iter().sum::<usize>() > 0);
+            producer.join().unwrap();
+        })
+    }
+}
+
+mod baseline {
+    use super::*;
+
+    type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+    thread_local! {
+        static CURRENT: Cell<usize> = const { Cell::new(0) };
+        // Tasks to poll again right away
+        static YIELDED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+        // Tasks to poll again once their file descriptor is readable
+        static WAITING: RefCell<Vec<(RawFd, usize)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    // Have the current task polled again after the others
+    fn yield_now<T>() -> Poll<T> {
+        let task = CURRENT.with(|current| current.get());
+        YIELDED.with(|yielded| yielded.borrow_mut().push(task));
+        Poll::Pending
+    }
+
+    // Have the current task polled again once `fd` is readable
+    fn wait_readable<T>(fd: RawFd) -> Poll<T> {
+        let task = CURRENT.with(|current| current.get());
+        WAITING.with(|waiting| waiting.borrow_mut().push((fd, task)));
+        Poll::Pending
+    }
+
+    // Poll the tasks until all of them are done
+    fn run(tasks: Vec<Task>) {
+        let mut tasks = tasks.into_iter().map(Some).collect::<Vec<_>>();
+        let mut ready = (0..tasks.len()).collect::<VecDeque<_>>();
+        let mut left = tasks.len();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut pollfds = Vec::new();
+
+        while left > 0 {
+            while let Some(id) = ready.pop_front() {
+                CURRENT.with(|current| current.set(id));
+                if tasks[id].as_mut().unwrap().as_mut().poll(&mut cx).is_ready() {
+                    tasks[id] = None;
+                    left -= 1;
+                }
+                YIELDED.with(|yielded| ready.extend(yielded.borrow_mut().drain(..)));
+            }
+            if left == 0 {
+                break;
+            }
+
+            let waiting = WAITING.with(|waiting| waiting.replace(Vec::new()));
+            pollfds.clear();
+            pollfds.extend(waiting.iter().map(|&(fd, _)| {
+                libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                }
+            }));
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) };
+            for (&(fd, task), pollfd) in waiting.iter().zip(&pollfds) {
+                if pollfd.revents != 0 {
+                    ready.push_back(task);
+                } else {
+                    WAITING.with(|waiting| waiting.borrow_mut().push((fd, task)));
+                }
+            }
+        }
+    }
+
+    struct Once {
+        out: Rc<Cell<usize>>,
+        value: usize,
+    }
+
+    impl Future for Once {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            self.out.set(self.value + 1);
+            Poll::Ready(())
+        }
+    }
+
+    pub fn spawn() -> Duration {
+        time(|| {
+            for i in 0..SPAWNS {
+                let out = Rc::new(Cell::new(0));
+                run(vec![Box::pin(Once {
+                             out: out.clone(),
+                             value: i,
+                         })]);
+                assert_eq!(out.get(), i + 1);
+            }
+        })
+    }
+
+    // Takes its turn `left` times, handing it over to the other player
+    struct Player {
+        turn: Rc<Cell<bool>>,
+        mine: bool,
+        left: usize,
+    }
+
+    impl Future for Player {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            if self.left == 0 {
+                return Poll::Ready(());
+            }
+            if self.turn.get() == self.mine {
+                self.turn.set(!self.mine);
+                self.left -= 1;
+            }
+            yield_now()
+        }
+    }
+
+    pub fn ping_pong() -> Duration {
+        let turn = Rc::new(Cell::new(true));
+        let player = |mine| -> Task {
+            Box::pin(Player {
+                turn: turn.clone(),
+                mine,
+                left: PING_PONGS,
+            })
+        };
+        let tasks = vec![player(true), player(false)];
+        time(|| run(tasks))
+    }
+
+    struct EchoServer {
+        server: UnixStream,
+        left: usize,
+    }
+
+    impl Future for EchoServer {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            let mut buf = [0u8; 32];
+            while self.left > 0 {
+                match (&self.server).read(&mut buf) {
+                    Ok(len) => {
+                        (&self.server).write_all(&buf[..len]).unwrap();
+                        self.left -= 1;
+                    }
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        return wait_readable(self.server.as_raw_fd());
+                    }
+                    Err(err) => panic!("{}", err),
+                }
+            }
+            Poll::Ready(())
+        }
+    }
+
+    // Writes a message to every client, then reads back all the echoes, round after round
+    struct EchoClients {
+        clients: Vec<UnixStream>,
+        rounds: usize,
+        // Client whose echo is awaited, `None` before the messages are written
+        reading: Option<usize>,
+    }
+
+    impl Future for EchoClients {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            let mut buf = [0u8; 32];
+            while self.rounds > 0 {
+                let mut next = match self.reading {
+                    Some(next) => next,
+                    None => {
+                        for mut client in &self.clients {
+                            client.write_all(MESSAGE).unwrap();
+                        }
+                        0
+                    }
+                };
+                while next < self.clients.len() {
+                    match (&self.clients[next]).read(&mut buf) {
+                        Ok(len) => {
+                            assert_eq!(len, MESSAGE.len());
+                            next += 1;
+                        }
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            self.reading = Some(next);
+                            return wait_readable(self.clients[next].as_raw_fd());
+                        }
+                        Err(err) => panic!("{}", err),
+                    }
+                }
+                self.reading = None;
+                self.rounds -= 1;
+            }
+            Poll::Ready(())
+        }
+    }
+
+    pub fn echo(pairs: Vec<(UnixStream, UnixStream)>) -> (usize, Duration) {
+        let (clients, servers): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        let n = clients.len();
+        for stream in clients.iter().chain(&servers) {
+            stream.set_nonblocking(true).unwrap();
+        }
+
+        let mut tasks = servers.into_iter()
+            .map(|server| -> Task {
+                Box::pin(EchoServer {
+                    server,
+                    left: ECHO_ROUNDS,
+                })
+            })
+            .collect::<Vec<_>>();
+        tasks.push(Box::pin(EchoClients {
+            clients,
+            rounds: ECHO_ROUNDS,
+            reading: None,
+        }));
+
+        (n * ECHO_ROUNDS, time(|| run(tasks)))
+    }
+
+    struct Producer {
+        queue: Rc<RefCell<VecDeque<usize>>>,
+        next: usize,
+    }
+
+    impl Future for Producer {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            while self.next < VALUES {
+                if self.queue.borrow().len() == 1024 {
+                    return yield_now();
+                }
+                let value = self.next;
+                self.queue.borrow_mut().push_back(value);
+                self.next += 1;
+            }
+            Poll::Ready(())
+        }
+    }
+
+    struct Consumer {
+        queue: Rc<RefCell<VecDeque<usize>>>,
+        received: usize,
+        sum: usize,
+    }
+
+    impl Future for Consumer {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<()> {
+            while self.received < VALUES {
+                let value = self.queue.borrow_mut().pop_front();
+                match value {
+                    Some(value) => {
+                        self.sum += value;
+                        self.received += 1;
+                    }
+                    None => return yield_now(),
+                }
+            }
+            assert!(self.sum > 0);
+            Poll::Ready(())
+        }
+    }
+
+    pub fn producer_consumer() -> Duration {
+        let queue = Rc::new(RefCell::new(VecDeque::with_capacity(1024)));
+        let tasks: Vec<Task> = vec![Box::pin(Producer {
+                                        queue: queue.clone(),
+                                        next: 0,
+                                    }),
+                                    Box::pin(Consumer {
+                                        queue,
+                                        received: 0,
+                                        sum: 0,
+                                    })];
+        time(|| run(tasks))
+    }
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`
+    let only = env::args().skip(1).filter(|arg| !arg.starts_with('-')).collect::<Vec<_>>();
+    let enabled = |scenario: &str| only.is_empty() || only.iter().any(|name| name == scenario);
+
+    println!("scenario,runtime,ops,total_ns,ns_per_op");
+    if enabled("spawn") {
+        report("spawn", "coroutine", SPAWNS, coroutines::spawn());
+        report("spawn", "thread", SPAWNS, threads::spawn());
+        report("spawn", "async", SPAWNS, baseline::spawn());
+    }
+    if enabled("ping_pong") {
+        report("ping_pong", "coroutine", PING_PONGS, coroutines::ping_pong());
+        report("ping_pong", "thread", PING_PONGS, threads::ping_pong());
+        report("ping_pong", "async", PING_PONGS, baseline::ping_pong());
+    }
+    if enabled("echo") {
+        let (ops, elapsed) = coroutines::echo(connections());
+        report("echo", "coroutine", ops, elapsed);
+        let (ops, elapsed) = threads::echo(connections());
+        report("echo", "thread", ops, elapsed);
+        let (ops, elapsed) = baseline::echo(connections());
+        report("echo", "async", ops, elapsed);
+    }
+    if enabled("producer_consumer") {
+        report("producer_consumer", "coroutine", VALUES, coroutines::producer_consumer());
+        report("producer_consumer", "thread", VALUES, threads::producer_consumer());
+        report("producer_consumer", "async", VALUES, baseline::producer_consumer());
+    }
+}
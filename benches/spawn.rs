@@ -0,0 +1,57 @@
+//! Spawn cost benchmark
+//!
+//! Counts the heap allocations performed by `Coroutine::spawn` and measures the time of a
+//! spawn / resume / drop cycle. Run with `cargo bench --bench spawn`.
+
+extern crate coroutine;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use coroutine::asymmetric::Coroutine;
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ITERATIONS: usize = 10_000;
+
+fn main() {
+    // Captures something so the closure is not zero sized
+    let payload = [1usize; 16];
+
+    // The first spawn sets up the metadata arena
+    drop(Coroutine::spawn(move |_, data| payload[0] + data));
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let coro = Coroutine::spawn(move |_, data| payload[0] + data);
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    drop(coro);
+
+    println!("heap allocations per spawn: {}", after - before);
+
+    let start = Instant::now();
+    for i in 0..ITERATIONS {
+        let mut coro = Coroutine::spawn(move |_, data| payload[0] + data);
+        assert_eq!(coro.resume(i).unwrap(), i + 1);
+    }
+    let elapsed = start.elapsed();
+
+    println!("spawn + resume + drop: {:?} per iteration",
+             elapsed / ITERATIONS as u32);
+}
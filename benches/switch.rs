@@ -0,0 +1,196 @@
+//! Regression benchmarks of the context switch path
+//!
+//! Covers the round trip of `resume` and `yield_with`, the same through the typed `Gen`,
+//! and spawning with and without the `StackPool`. Each benchmark is warmed up, then timed in
+//! 50 samples of a batch of iterations sized to take about 10ms, and reported with the median
+//! and the spread of the samples per iteration.
+//!
+//! Run with `cargo bench --bench switch`. Like criterion, `--save-baseline <name>` stores the
+//! medians under `target/switch-bench`, and `--baseline <name>` compares against such a run
+//! and fails if a median has grown by more than 10%:
+//!
+//! ```text
+//! git checkout master && cargo bench --bench switch -- --save-baseline master
+//! git checkout my-change && cargo bench --bench switch -- --baseline master
+//! ```
+//!
+//! The names of benchmarks given as well restrict the run to those.
+
+extern crate coroutine;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::hint;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process;
+use std::time::{Duration, Instant};
+
+use coroutine::Options;
+use coroutine::asymmetric::Coroutine;
+use coroutine::generator::{Gen, Generator, GeneratorState};
+use coroutine::stack::StackPool;
+
+const SAMPLES: usize = 50;
+const SAMPLE_TIME: Duration = Duration::from_millis(10);
+const WARM_UP: Duration = Duration::from_millis(200);
+// Past this the median counts as regressed
+const THRESHOLD: f64 = 0.10;
+
+struct Summary {
+    median: f64,
+    low: f64,
+    high: f64,
+}
+
+// Time `f`, which runs the benchmark the given number of times, in nanoseconds per iteration
+fn measure<F>(mut f: F) -> Summary
+    where F: FnMut(u64)
+{
+    // Double the batch until it takes long enough to time
+    let mut batch = 1;
+    let start = Instant::now();
+    while start.elapsed() < WARM_UP {
+        let sample = Instant::now();
+        f(batch);
+        if sample.elapsed() < SAMPLE_TIME {
+            batch *= 2;
+        }
+    }
+
+    let mut samples = (0..SAMPLES)
+        .map(|_| {
+            let sample = Instant::now();
+            f(batch);
+            sample.elapsed().as_nanos() as f64 / batch as f64
+        })
+        .collect::<Vec<_>>();
+    samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Summary {
+        median: samples[SAMPLES / 2],
+        low: samples[SAMPLES / 20],
+        high: samples[SAMPLES - 1 - SAMPLES / 20],
+    }
+}
+
+fn resume_yield(n: u64) {
+    let mut coro = Coroutine::spawn(|coro, mut data| loop {
+        data = coro.yield_with(hint::black_box(data));
+    });
+    for i in 0..n {
+        hint::black_box(coro.resume(i as usize).unwrap());
+    }
+}
+
+fn resume_yield_typed(n: u64) {
+    let mut gen = Gen::<(u64, u64), (u64, u64)>::new(|y, mut data| loop {
+        data = y.yield_(hint::black_box(data));
+    });
+    for i in 0..n {
+        match Pin::new(&mut gen).resume((i, i)) {
+            GeneratorState::Yielded(data) => {
+                hint::black_box(data);
+            }
+            GeneratorState::Complete(()) => unreachable!(),
+        }
+    }
+}
+
+fn spawn(n: u64) {
+    let opts = || {
+        Options {
+            stack_size: 128 * 1024,
+            ..Options::default()
+        }
+    };
+    for i in 0..n as usize {
+        let mut coro = Coroutine::spawn_opts(|_, data| data + 1, opts());
+        assert_eq!(coro.resume(i).unwrap(), i + 1);
+    }
+}
+
+fn spawn_pooled(n: u64) {
+    spawn(n)
+}
+
+fn spawn_fresh(n: u64) {
+    StackPool::set_capacity(0);
+    spawn(n);
+    StackPool::set_capacity(64);
+}
+
+type Bench = (&'static str, fn(u64));
+
+const BENCHES: &[Bench] = &[("resume_yield", resume_yield),
+                            ("resume_yield_typed", resume_yield_typed),
+                            ("spawn_pooled", spawn_pooled),
+                            ("spawn_fresh", spawn_fresh)];
+
+fn baseline_path(name: &str) -> PathBuf {
+    let target = env::var("CARGO_TARGET_DIR").unwrap_or_else(|_| "target".to_owned());
+    PathBuf::from(target).join("switch-bench").join(format!("{}.csv", name))
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut save = None;
+    let mut compare = None;
+    let mut only = Vec::new();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--save-baseline" => save = args.next(),
+            "--baseline" => compare = args.next(),
+            // `cargo bench` passes `--bench`
+            arg if arg.starts_with('-') => {}
+            _ => only.push(arg),
+        }
+    }
+
+    let previous = compare.as_ref().map(|name| {
+        let path = baseline_path(name);
+        let csv = fs::read_to_string(&path)
+            .unwrap_or_else(|err| panic!("cannot read {}: {}", path.display(), err));
+        csv.lines()
+            .filter_map(|line| {
+                let mut fields = line.split(',');
+                Some((fields.next()?.to_owned(), fields.next()?.parse::<f64>().ok()?))
+            })
+            .collect::<HashMap<_, _>>()
+    });
+
+    let mut saved = String::new();
+    let mut regressed = false;
+    for &(name, f) in BENCHES {
+        if !only.is_empty() && !only.iter().any(|only| only == name) {
+            continue;
+        }
+
+        let summary = measure(f);
+        print!("{:<20} {:>10.1} ns/iter  [{:.1} .. {:.1}]",
+               name,
+               summary.median,
+               summary.low,
+               summary.high);
+        if let Some(&before) = previous.as_ref().and_then(|previous| previous.get(name)) {
+            let change = summary.median / before - 1.0;
+            print!("  {:+.1}%", change * 100.0);
+            if change > THRESHOLD {
+                print!(" regressed");
+                regressed = true;
+            }
+        }
+        println!();
+        saved.push_str(&format!("{},{}\n", name, summary.median));
+    }
+
+    if let Some(name) = save {
+        let path = baseline_path(&name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, saved).unwrap();
+        println!("saved baseline `{}`", name);
+    }
+    if regressed {
+        process::exit(1);
+    }
+}
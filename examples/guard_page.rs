@@ -0,0 +1,48 @@
+//! Self-test checking that overflowing a coroutine stack faults on its guard page
+//!
+//! The overflow happens in a forked child, the parent only looks at how the child died. Runs
+//! the same on static musl builds, as the stacks are plain `mmap` and `mprotect` allocations.
+
+extern crate coroutine;
+extern crate libc;
+
+use std::process;
+
+use coroutine::Options;
+use coroutine::asymmetric::Coroutine;
+
+#[inline(never)]
+#[allow(unconditional_recursion)]
+fn recurse(depth: usize) -> usize {
+    let buf = [depth as u8; 1024];
+    // Keep the buffer alive across the call so every frame really takes that much stack
+    recurse(depth + 1) + std::hint::black_box(&buf)[0] as usize
+}
+
+fn main() {
+    let pid = unsafe { libc::fork() };
+    assert!(pid >= 0, "fork failed");
+
+    if pid == 0 {
+        let opts = Options { stack_size: 64 * 1024, ..Options::default() };
+        let mut coro = Coroutine::spawn_opts(|_, _| recurse(0), opts);
+        let _ = coro.resume(0);
+        // Only reached if the overflow went undetected
+        unsafe { libc::_exit(0) };
+    }
+
+    let mut status = 0;
+    unsafe {
+        libc::waitpid(pid, &mut status, 0);
+    }
+
+    let faulted = libc::WIFSIGNALED(status) &&
+                  matches!(libc::WTERMSIG(status), libc::SIGSEGV | libc::SIGBUS);
+    if faulted {
+        println!("guard page ok: overflow killed the child with signal {}",
+                 libc::WTERMSIG(status));
+    } else {
+        println!("guard page FAILED: child exited with status {}", status);
+        process::exit(1);
+    }
+}
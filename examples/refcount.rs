@@ -1,7 +1,6 @@
 extern crate coroutine;
 extern crate env_logger;
 
-use std::usize;
 use std::rc::Rc;
 use std::cell::RefCell;
 use coroutine::asymmetric::Coroutine;
@@ -1,7 +1,6 @@
 extern crate coroutine;
 extern crate env_logger;
 
-use std::usize;
 use coroutine::asymmetric::Coroutine;
 
 fn main() {
@@ -0,0 +1,234 @@
+//! Soak test running spawn / resume / drop cycles until told to stop
+//!
+//! Every thread picks cycles at random: running a coroutine to the end, dropping it while it
+//! is suspended so it is force-unwound, letting it panic, spawning from inside of it, each
+//! with stacks of a few sizes so the `StackPool` is reused all the time. Every few seconds
+//! the live coroutines, the stacks and the resident memory of the process are checked against
+//! what they were after the warm-up, and the test fails as soon as one of them keeps growing.
+//!
+//! ```text
+//! cargo run --release --example soak -- [seconds, 60] [threads, 4]
+//! ```
+
+extern crate coroutine;
+
+use std::env;
+use std::fs;
+use std::panic;
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use coroutine::{Error, Options, stack};
+use coroutine::asymmetric::{self, Coroutine, Handle};
+use coroutine::stack::StackPool;
+
+const REPORT_EVERY: Duration = Duration::from_secs(5);
+const WARM_UP: Duration = Duration::from_secs(10);
+// Resident memory may grow by this much past the warm-up, for allocator noise
+const RSS_SLACK: usize = 64 * 1024 * 1024;
+const STACK_SIZES: [usize; 3] = [32 * 1024, 64 * 1024, 128 * 1024];
+
+static CYCLES: AtomicUsize = AtomicUsize::new(0);
+static CREATED: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+// Panic payload of the cycles which panic on purpose, kept out of the output
+struct Expected;
+
+// Counts its drops, to tell whether force unwinding has run the destructors
+struct Tracked;
+
+impl Tracked {
+    fn new() -> Tracked {
+        CREATED.fetch_add(1, Ordering::Relaxed);
+        Tracked
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// xorshift, the cycles only need to be varied
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+fn opts(rng: &mut Rng) -> Options {
+    Options {
+        stack_size: STACK_SIZES[rng.below(STACK_SIZES.len())],
+        ..Options::default()
+    }
+}
+
+fn cycle(rng: &mut Rng) {
+    match rng.below(4) {
+        // To the end
+        0 => {
+            let mut coro = Coroutine::spawn_opts(|coro, data| {
+                                                     let _tracked = Tracked::new();
+                                                     coro.yield_with(data) + 1
+                                                 },
+                                                 opts(rng));
+            assert_eq!(coro.resume(1).unwrap(), 1);
+            assert_eq!(coro.resume(2).unwrap(), 3);
+            assert!(coro.is_finished());
+        }
+        // Dropped while suspended
+        1 => {
+            let mut coro = Coroutine::spawn_opts(|coro, _| {
+                                                     let _tracked = Tracked::new();
+                                                     loop {
+                                                         coro.yield_with(0);
+                                                     }
+                                                 },
+                                                 opts(rng));
+            for _ in 0..rng.below(3) + 1 {
+                coro.resume(0).unwrap();
+            }
+        }
+        // Panics
+        2 => {
+            let mut coro = Coroutine::spawn_opts(|coro, _| {
+                                                     let _tracked = Tracked::new();
+                                                     coro.yield_with(0);
+                                                     panic::panic_any(Expected)
+                                                 },
+                                                 opts(rng));
+            coro.resume(0).unwrap();
+            match coro.resume(0) {
+                Err(Error::Panicking(err)) => assert!(err.is::<Expected>()),
+                other => panic!("panic got lost: {:?}", other),
+            }
+        }
+        // Spawns a child which outlives a yield of the parent
+        _ => {
+            let child_opts = opts(rng);
+            let mut coro = Coroutine::spawn_opts(move |coro, _| {
+                                                     let mut child = spawn_child(child_opts);
+                                                     let first = child.resume(0).unwrap();
+                                                     coro.yield_with(first);
+                                                     child.resume(0).unwrap()
+                                                 },
+                                                 opts(rng));
+            assert_eq!(coro.resume(0).unwrap(), 1);
+            if rng.below(2) == 0 {
+                coro.resume(0).unwrap();
+            }
+        }
+    }
+}
+
+fn spawn_child(opts: Options) -> Handle {
+    Coroutine::spawn_opts(|coro, _| {
+                              let _tracked = Tracked::new();
+                              coro.yield_with(1)
+                          },
+                          opts)
+}
+
+// Resident memory in bytes, where `/proc` tells
+fn rss() -> Option<usize> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(pages * 4096)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let seconds = args.next().map_or(60, |arg| arg.parse().expect("seconds"));
+    let threads = args.next().map_or(4, |arg| arg.parse().expect("threads"));
+    let duration = Duration::from_secs(seconds);
+
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| if !info.payload().is::<Expected>() {
+        previous(info)
+    }));
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let workers = (0..threads)
+        .map(|i| {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (i as u64 + 1));
+                while !stop.load(Ordering::Relaxed) {
+                    cycle(&mut rng);
+                    CYCLES.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut baseline = None;
+    let mut failure = None;
+    while start.elapsed() < duration && failure.is_none() {
+        thread::sleep(REPORT_EVERY.min(duration));
+        let (live, stacks, rss) = (asymmetric::live(), stack::allocated(), rss());
+        println!("{:>6}s {:>12} cycles, {:>3} live, {:>8} KiB of stacks, {:>3} pooled, \
+                  {} KiB resident",
+                 start.elapsed().as_secs(),
+                 CYCLES.load(Ordering::Relaxed),
+                 live,
+                 stacks / 1024,
+                 StackPool::len(),
+                 rss.map_or(0, |rss| rss / 1024));
+
+        // A thread holds two coroutines at a time, three while its last one is being dropped
+        if live > threads * 3 {
+            failure = Some(format!("{} coroutines alive", live));
+        }
+        if stacks > threads * 3 * STACK_SIZES[STACK_SIZES.len() - 1] {
+            failure = Some(format!("{} bytes of stacks allocated", stacks));
+        }
+        match (baseline, rss) {
+            (None, _) if start.elapsed() >= WARM_UP => baseline = rss,
+            (Some(baseline), Some(rss)) if rss > baseline + RSS_SLACK => {
+                failure = Some(format!("resident memory grew from {} to {} KiB",
+                                       baseline / 1024,
+                                       rss / 1024));
+            }
+            _ => {}
+        }
+        if workers.iter().any(|worker| worker.is_finished()) {
+            failure = Some("a worker has died".to_owned());
+        }
+    }
+
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        if worker.join().is_err() && failure.is_none() {
+            failure = Some("a worker has panicked".to_owned());
+        }
+    }
+
+    let (created, dropped) = (CREATED.load(Ordering::Relaxed), DROPPED.load(Ordering::Relaxed));
+    if failure.is_none() && created != dropped {
+        failure = Some(format!("{} of {} values leaked", created - dropped, created));
+    }
+    if failure.is_none() && (asymmetric::live() != 0 || stack::allocated() != 0) {
+        failure = Some(format!("{} coroutines and {} bytes of stacks left after the run",
+                               asymmetric::live(),
+                               stack::allocated()));
+    }
+
+    match failure {
+        None => println!("soak ok: {} cycles", CYCLES.load(Ordering::Relaxed)),
+        Some(failure) => {
+            println!("soak FAILED: {}", failure);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Random sequences of operations on the asymmetric API, checked against a model
+//!
+//! Every input byte picks an operation: spawn a coroutine with a random behaviour, resume one
+//! with random data, drop one wherever it is, or check a weak handle. The model knows what
+//! each resume has to return and in which state it leaves the coroutine, and the number of
+//! live coroutines has to drop back to zero at the end.
+//!
+//! ```text
+//! cargo +nightly fuzz run ops
+//! ```
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate coroutine;
+
+use std::panic;
+use std::sync::Once;
+
+use coroutine::{Error, Options};
+use coroutine::asymmetric::{self, Coroutine, Handle, State, WeakHandle};
+
+// Upper bound for coroutines held at once, the inputs stay cheap to run
+const MAX_HANDLES: usize = 16;
+
+// Panic payloads of all kinds of types, kept out of the output
+#[derive(Debug, PartialEq)]
+struct Payload(u8);
+
+#[derive(Debug, Clone, Copy)]
+enum Ending {
+    Return,
+    Panic(u8),
+    // Runs a child of its own to the end and returns what it got
+    Nested,
+    // Parks with the data, then returns what it is resumed with
+    Park,
+}
+
+struct Model {
+    handle: Handle,
+    yields: usize,
+    ending: Ending,
+    resumes: usize,
+}
+
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let (&first, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(first)
+    }
+}
+
+fn spawn(yields: usize, ending: Ending, stack_size: usize) -> Handle {
+    let opts = Options {
+        stack_size,
+        ..Options::default()
+    };
+    Coroutine::spawn_opts(move |coro, mut data| {
+        for i in 0..yields {
+            data = coro.yield_with(data ^ i);
+        }
+        match ending {
+            Ending::Return => data + 1,
+            Ending::Panic(0) => panic::panic_any(Payload(data as u8)),
+            Ending::Panic(1) => panic::panic_any(data),
+            Ending::Panic(2) => panic::panic_any(format!("{}", data).into_boxed_str()),
+            Ending::Panic(_) => panic::panic_any(vec![data; 3]),
+            Ending::Nested => {
+                let mut child = Coroutine::spawn(|coro, data| coro.yield_with(data) * 2);
+                assert_eq!(child.resume(data).unwrap(), data);
+                let result = child.resume(data).unwrap();
+                assert!(child.is_finished());
+                result
+            }
+            Ending::Park => coro.park_with(data),
+        }
+    }, opts)
+}
+
+fn check_panic(err: Box<dyn std::any::Any + Send>, kind: u8, data: usize) {
+    match kind {
+        0 => assert_eq!(*err.downcast::<Payload>().unwrap(), Payload(data as u8)),
+        1 => assert_eq!(*err.downcast::<usize>().unwrap(), data),
+        2 => assert_eq!(&**err.downcast::<Box<str>>().unwrap(), &format!("{}", data)[..]),
+        _ => assert_eq!(*err.downcast::<Vec<usize>>().unwrap(), vec![data; 3]),
+    }
+}
+
+// Resume `model` once and check the outcome, whether it has finished
+fn resume(model: &mut Model, data: usize) -> bool {
+    let k = model.resumes;
+    model.resumes += 1;
+    let result = model.handle.resume(data);
+
+    if k < model.yields {
+        assert_eq!(result.unwrap(), data ^ k);
+        assert_eq!(model.handle.state(), State::Suspended);
+        return false;
+    }
+
+    match (model.ending, k - model.yields) {
+        (Ending::Return, _) => assert_eq!(result.unwrap(), data + 1),
+        (Ending::Panic(kind), _) => {
+            match result {
+                Err(Error::Panicking(err)) => check_panic(err, kind, data),
+                other => panic!("panic not reported: {:?}", other),
+            }
+            assert_eq!(model.handle.state(), State::Panicked);
+        }
+        (Ending::Nested, _) => assert_eq!(result.unwrap(), data * 2),
+        (Ending::Park, 0) => {
+            assert_eq!(result.unwrap(), data);
+            assert_eq!(model.handle.state(), State::Parked);
+            return false;
+        }
+        (Ending::Park, _) => assert_eq!(result.unwrap(), data),
+    }
+    assert!(model.handle.is_finished());
+    true
+}
+
+fuzz_target!(|data: &[u8]| {
+    static QUIET: Once = Once::new();
+    QUIET.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let expected = info.payload().is::<Payload>() || info.payload().is::<usize>() ||
+                           info.payload().is::<Box<str>>() ||
+                           info.payload().is::<Vec<usize>>();
+            if !expected {
+                previous(info)
+            }
+        }));
+    });
+
+    let live = asymmetric::live();
+    let mut input = Input(data);
+    let mut models: Vec<Model> = Vec::new();
+    let mut weak: Vec<WeakHandle> = Vec::new();
+
+    while let Some(op) = input.byte() {
+        let arg = input.byte().unwrap_or(0);
+        match op % 4 {
+            0 if models.len() < MAX_HANDLES => {
+                let ending = match arg >> 4 {
+                    0..=5 => Ending::Return,
+                    6..=9 => Ending::Panic(arg & 3),
+                    10..=12 => Ending::Nested,
+                    _ => Ending::Park,
+                };
+                let yields = (arg & 7) as usize;
+                let stack_size = [32 * 1024, 64 * 1024, 256 * 1024][op as usize / 4 % 3];
+                let handle = spawn(yields, ending, stack_size);
+                assert_eq!(handle.state(), State::Suspended);
+                weak.push(handle.downgrade());
+                models.push(Model {
+                    handle,
+                    yields,
+                    ending,
+                    resumes: 0,
+                });
+            }
+            1 if !models.is_empty() => {
+                let index = arg as usize % models.len();
+                let data = input.byte().unwrap_or(0) as usize;
+                if resume(&mut models[index], data) {
+                    let model = models.swap_remove(index);
+                    let weak = model.handle.downgrade();
+                    assert!(!weak.is_alive() && weak.state().is_some());
+                    drop(model);
+                    assert!(weak.state().is_none());
+                }
+            }
+            // Mid-way, the coroutine is force unwound
+            2 if !models.is_empty() => {
+                let index = arg as usize % models.len();
+                drop(models.swap_remove(index));
+            }
+            3 if !weak.is_empty() => {
+                let index = arg as usize % weak.len();
+                let alive = models.iter().any(|model| weak[index].ptr_eq(&model.handle));
+                assert_eq!(weak[index].is_alive(), alive);
+                if !alive {
+                    assert!(weak[index].state().is_none());
+                    weak.swap_remove(index);
+                }
+            }
+            _ => {}
+        }
+        assert_eq!(asymmetric::live(), live + models.len());
+    }
+
+    drop(models);
+    assert_eq!(asymmetric::live(), live);
+    assert!(weak.iter().all(|weak| !weak.is_alive()));
+});
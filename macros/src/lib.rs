@@ -0,0 +1,182 @@
+//! Attribute macros of the `coroutine` crate
+//!
+//! Enabled through its `macros` feature and used as `#[coroutine::test]` and
+//! `#[coroutine::main]`, see there.
+
+extern crate proc_macro;
+
+use std::iter::FromIterator;
+
+use proc_macro::{Delimiter, Group, TokenStream, TokenTree};
+
+/// Time limit of a test waiting in `fd`, unless it sets `timeout_ms`
+const DEFAULT_TIMEOUT_MS: u64 = 60_000;
+
+/// Run the test body as a coroutine, driven by `coroutine::fd::run_timeout`
+///
+/// The body may take a `&mut Coroutine` to park on, sleep in `fd::sleep`, receive from
+/// channels and so on. The test fails once it waits in `fd` past `timeout_ms` after its
+/// start, 60 seconds if not given. The limit is checked by `fd::run_timeout` only: code which
+/// does not wait in `fd`, a busy loop or a blocking call, is not interrupted and runs past it.
+///
+/// ```rust,ignore
+/// #[coroutine::test(timeout_ms = 1000)]
+/// fn sleeps(coro: &mut Coroutine) {
+///     coroutine::fd::sleep(coro, Duration::from_millis(10)).unwrap();
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn test(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let timeout_ms = match parse_arg(attr, "timeout_ms") {
+        Ok(timeout_ms) => timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS),
+        Err(msg) => return compile_error(msg),
+    };
+    let function = match Function::parse(item) {
+        Ok(function) => function,
+        Err(msg) => return compile_error(msg),
+    };
+
+    let runner = format!("::coroutine::fd::__run_test(__coroutine_body, \
+                          ::std::time::Duration::from_millis({}))",
+                         timeout_ms);
+    let mut out = tokens("#[test]");
+    out.extend(function.wrap(&runner));
+    out
+}
+
+/// Run the body of `main` as the root coroutine
+///
+/// Plain `#[coroutine::main]` drives it on the main thread with `coroutine::fd::run`, so it
+/// may wait in `fd` like a `#[coroutine::test]`. With `threads = N` it runs on a
+/// `ThreadPoolRunner` of `N` threads instead, which is shut down once `main` returns; the
+/// body then must be `Send` and should yield rather than wait in `fd`.
+///
+/// ```rust,ignore
+/// #[coroutine::main(threads = 4)]
+/// fn main(coro: &mut Coroutine) {
+///     coro.yield_with(0);
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn main(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let threads = match parse_arg(attr, "threads") {
+        Ok(Some(0)) => return compile_error("a thread pool needs at least one thread"),
+        Ok(threads) => threads,
+        Err(msg) => return compile_error(msg),
+    };
+    let function = match Function::parse(item) {
+        Ok(function) => function,
+        Err(msg) => return compile_error(msg),
+    };
+
+    let runner = match threads {
+        Some(threads) => {
+            format!("::coroutine::thread_pool::__run_main(__coroutine_body, {})", threads)
+        }
+        None => "::coroutine::fd::__run_main(__coroutine_body)".to_owned(),
+    };
+    function.wrap(&runner)
+}
+
+// The parts of the annotated function, kept as they are
+struct Function {
+    // Attributes and visibility
+    prefix: Vec<TokenTree>,
+    name: TokenTree,
+    args: Group,
+    // `-> T` or nothing
+    ret: Vec<TokenTree>,
+    body: Group,
+}
+
+impl Function {
+    fn parse(item: TokenStream) -> Result<Function, &'static str> {
+        let mut tokens = item.into_iter().collect::<Vec<_>>();
+        let body = match tokens.pop() {
+            Some(TokenTree::Group(ref body)) if body.delimiter() == Delimiter::Brace => {
+                body.clone()
+            }
+            _ => return Err("expected a function"),
+        };
+
+        let fn_pos = tokens.iter()
+            .position(|t| match *t {
+                TokenTree::Ident(ref ident) => ident.to_string() == "fn",
+                _ => false,
+            })
+            .ok_or("expected a function")?;
+
+        let mut rest = tokens.split_off(fn_pos).into_iter().skip(1);
+        let name = match rest.next() {
+            Some(name @ TokenTree::Ident(..)) => name,
+            _ => return Err("expected a function name"),
+        };
+        let args = match rest.next() {
+            Some(TokenTree::Group(ref args)) if args.delimiter() == Delimiter::Parenthesis => {
+                args.clone()
+            }
+            _ => return Err("generic functions are not supported"),
+        };
+
+        Ok(Function {
+            prefix: tokens,
+            name,
+            args,
+            ret: rest.collect(),
+            body,
+        })
+    }
+
+    // `fn name() -> T { fn __coroutine_body(args) -> T body; <runner> }`
+    fn wrap(self, runner: &str) -> TokenStream {
+        let args = if self.args.stream().is_empty() {
+            Group::new(Delimiter::Parenthesis,
+                       tokens("_: &mut ::coroutine::asymmetric::Coroutine"))
+        } else {
+            self.args
+        };
+
+        let mut inner = tokens("fn __coroutine_body");
+        inner.extend(Some(TokenTree::Group(args)));
+        inner.extend(self.ret.iter().cloned());
+        inner.extend(Some(TokenTree::Group(self.body)));
+        inner.extend(tokens(runner));
+
+        let mut out = TokenStream::from_iter(self.prefix);
+        out.extend(tokens("fn"));
+        out.extend(Some(self.name));
+        out.extend(tokens("()"));
+        out.extend(self.ret);
+        out.extend(Some(TokenTree::Group(Group::new(Delimiter::Brace, inner))));
+        out
+    }
+}
+
+// Empty, or `name = N`
+fn parse_arg(attr: TokenStream, name: &str) -> Result<Option<u64>, &'static str> {
+    let tokens = attr.into_iter().collect::<Vec<_>>();
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    match (tokens.first(), tokens.get(1), tokens.get(2), tokens.len()) {
+        (Some(TokenTree::Ident(key)),
+         Some(TokenTree::Punct(eq)),
+         Some(TokenTree::Literal(value)),
+         3) if key.to_string() == name && eq.as_char() == '=' => {
+            let value = value.to_string().replace('_', "");
+            let value = value.trim_end_matches(|c: char| !c.is_ascii_digit());
+            value.parse().map(Some).map_err(|_| "expected an integer")
+        }
+        _ if name == "threads" => Err("expected `threads = N`"),
+        _ => Err("expected `timeout_ms = N`"),
+    }
+}
+
+fn tokens(code: &str) -> TokenStream {
+    code.parse().unwrap()
+}
+
+fn compile_error(msg: &str) -> TokenStream {
+    tokens(&format!("compile_error!({:?});", msg))
+}
@@ -0,0 +1,100 @@
+//! Calls into code which depends on the platform ABI from inside coroutines
+//!
+//! A misaligned initial stack pointer usually goes unnoticed until some code spills SSE
+//! registers with aligned moves or walks a varargs list, so these run exactly that kind of
+//! code on a fresh coroutine stack, on every target the test suite runs on.
+
+use std::ffi::CStr;
+
+use libc;
+
+use asymmetric::Coroutine;
+
+fn on_coroutine<F: FnOnce() + 'static>(f: F) {
+    let mut coro = Coroutine::spawn(move |_, _| {
+        f();
+        0
+    });
+    coro.resume(0).unwrap();
+    assert!(coro.is_finished());
+}
+
+#[test]
+fn varargs_with_doubles() {
+    on_coroutine(|| {
+        let mut buf = [0 as libc::c_char; 64];
+        let len = unsafe {
+            libc::snprintf(buf.as_mut_ptr(),
+                           buf.len(),
+                           b"%.2f %d %.3e\0".as_ptr() as *const libc::c_char,
+                           1.5f64,
+                           42 as libc::c_int,
+                           -2.25f64)
+        };
+        assert!(len > 0);
+
+        let formatted = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        assert_eq!(formatted.to_str().unwrap(), "1.50 42 -2.250e+00");
+    });
+}
+
+#[test]
+fn libc_float_parsing() {
+    on_coroutine(|| {
+        let value = unsafe { libc::strtod(b"2.71875\0".as_ptr() as *const libc::c_char, ::std::ptr::null_mut()) };
+        assert!((value - 2.71875).abs() < 1e-12);
+    });
+}
+
+#[repr(align(16))]
+#[derive(Clone, Copy)]
+struct Aligned([f64; 2]);
+
+#[inline(never)]
+fn spill(depth: usize, acc: Aligned) -> Aligned {
+    let addr = &acc as *const Aligned as usize;
+    assert_eq!(addr % 16, 0);
+
+    if depth == 0 {
+        acc
+    } else {
+        let next = Aligned([acc.0[0] * 1.5, acc.0[1] + depth as f64]);
+        let res = spill(depth - 1, next);
+        Aligned([res.0[0] + acc.0[0], res.0[1]])
+    }
+}
+
+#[test]
+fn aligned_spills() {
+    on_coroutine(|| {
+        let res = spill(32, Aligned([1.0, 0.0]));
+        assert!(res.0[0].is_finite());
+        assert_eq!(res.0[1], (1..33).sum::<usize>() as f64);
+    });
+}
+
+extern "C" fn compare(a: *const libc::c_void, b: *const libc::c_void) -> libc::c_int {
+    let (a, b) = unsafe { (*(a as *const f64), *(b as *const f64)) };
+    a.partial_cmp(&b).unwrap() as libc::c_int
+}
+
+#[test]
+fn callbacks_from_libc() {
+    on_coroutine(|| {
+        let mut values = [3.5f64, -1.0, 2.25, 0.0, 10.0];
+        unsafe {
+            libc::qsort(values.as_mut_ptr() as *mut libc::c_void,
+                        values.len(),
+                        ::std::mem::size_of::<f64>(),
+                        Some(compare));
+        }
+        assert_eq!(values, [-1.0, 0.0, 2.25, 3.5, 10.0]);
+    });
+}
+
+#[test]
+fn formatting_floats() {
+    on_coroutine(|| {
+        assert_eq!(format!("{:.3} {:e}", 1.0f64 / 3.0, 1e300f64), "0.333 1e300");
+    });
+}
@@ -0,0 +1,275 @@
+//! Actors: coroutines receiving typed messages from a bounded mailbox
+//!
+//! Actors are driven by whoever talks to them: `Addr::send` only queues a message, while
+//! `Addr::run` and `Addr::call` resume the actor until it has drained its mailbox. Everything
+//! happens on the current thread.
+//!
+//! ```rust
+//! use coroutine::actor::{self, Reply};
+//!
+//! enum Msg {
+//!     Add(u32),
+//!     Get(Reply<u32>),
+//! }
+//!
+//! let addr = actor::spawn(16, |mailbox| {
+//!     let mut total = 0;
+//!     while let Some(msg) = mailbox.recv() {
+//!         match msg {
+//!             Msg::Add(n) => total += n,
+//!             Msg::Get(reply) => reply.send(total),
+//!         }
+//!     }
+//! });
+//!
+//! addr.send(Msg::Add(1)).unwrap();
+//! addr.send(Msg::Add(2)).unwrap();
+//! assert_eq!(addr.call(Msg::Get).unwrap(), 3);
+//! addr.stop();
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+use asymmetric::{Coroutine, Handle};
+
+struct Inner<T> {
+    handle: RefCell<Option<Handle>>,
+    queue: RefCell<VecDeque<T>>,
+    capacity: usize,
+    closed: Cell<bool>,
+}
+
+/// Error returned by `Addr::send`, hands the message back
+pub enum SendError<T> {
+    /// The mailbox is at capacity, run the actor to make room
+    Full(T),
+    /// The actor has been stopped or has panicked
+    Stopped(T),
+}
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Full(..) => write!(f, "Full(..)"),
+            SendError::Stopped(..) => write!(f, "Stopped(..)"),
+        }
+    }
+}
+
+/// Error returned by `Addr::call`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CallError {
+    /// The mailbox is at capacity
+    Full,
+    /// The actor has been stopped or has panicked
+    Stopped,
+    /// The actor is already running, e.g. it tried to call itself
+    Busy,
+    /// The actor dropped the `Reply` without answering
+    NoReply,
+}
+
+/// One-shot channel carrying the answer of a `call`
+pub struct Reply<R>(Rc<RefCell<Option<R>>>);
+
+impl<R> Reply<R> {
+    /// Answer the call
+    pub fn send(self, value: R) {
+        *self.0.borrow_mut() = Some(value);
+    }
+}
+
+/// The receiving side of an actor, handed to its body
+pub struct Mailbox<T> {
+    coro: *mut Coroutine,
+    inner: Weak<Inner<T>>,
+}
+
+impl<T> Mailbox<T> {
+    /// Take the next message, parking the actor while the mailbox is empty
+    ///
+    /// Returns `None` once the actor has been stopped and all queued messages are delivered.
+    pub fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let inner = self.inner.upgrade()?;
+                if let Some(msg) = inner.queue.borrow_mut().pop_front() {
+                    return Some(msg);
+                }
+                if inner.closed.get() {
+                    return None;
+                }
+            }
+
+            let coro = unsafe { &mut *self.coro };
+            coro.park_with(0);
+        }
+    }
+}
+
+/// Address of an actor, dropping the last one unwinds the actor
+pub struct Addr<T> {
+    inner: Rc<Inner<T>>,
+}
+
+impl<T> Clone for Addr<T> {
+    fn clone(&self) -> Addr<T> {
+        Addr { inner: self.inner.clone() }
+    }
+}
+
+/// Spawn an actor whose mailbox holds at most `capacity` messages
+#[track_caller]
+pub fn spawn<T, F>(capacity: usize, f: F) -> Addr<T>
+    where T: 'static,
+          F: FnOnce(&mut Mailbox<T>) + 'static
+{
+    let inner = Rc::new(Inner {
+        handle: RefCell::new(None),
+        queue: RefCell::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        closed: Cell::new(false),
+    });
+
+    let weak = Rc::downgrade(&inner);
+    let handle = Coroutine::spawn(move |coro, _| {
+        let mut mailbox = Mailbox { coro, inner: weak };
+        f(&mut mailbox);
+        0
+    });
+    *inner.handle.borrow_mut() = Some(handle);
+
+    Addr { inner }
+}
+
+impl<T> Addr<T> {
+    /// Queue a message without running the actor
+    pub fn send(&self, msg: T) -> Result<(), SendError<T>> {
+        if !self.is_alive() {
+            return Err(SendError::Stopped(msg));
+        }
+
+        let mut queue = self.inner.queue.borrow_mut();
+        if queue.len() >= self.inner.capacity {
+            return Err(SendError::Full(msg));
+        }
+        queue.push_back(msg);
+        Ok(())
+    }
+
+    /// Whether the actor still accepts messages
+    pub fn is_alive(&self) -> bool {
+        !self.inner.closed.get()
+    }
+
+    /// Resume the actor until its mailbox is empty or it has finished
+    ///
+    /// Returns `false` if the actor is already running on this thread.
+    pub fn run(&self) -> bool {
+        let mut handle = match self.inner.handle.try_borrow_mut() {
+            Ok(handle) => handle,
+            Err(..) => return false,
+        };
+
+        while let Some(ref mut coro) = *handle {
+            if self.inner.queue.borrow().is_empty() && !self.inner.closed.get() {
+                break;
+            }
+
+            if let Err(err) = coro.resume(0) {
+                error!("Coroutine `{}`: actor panicked: {:?}", coro.debug_name(), err);
+            }
+
+            if coro.is_finished() {
+                self.inner.closed.set(true);
+                *handle = None;
+                self.inner.queue.borrow_mut().clear();
+            }
+        }
+        true
+    }
+
+    /// Send a request built around a `Reply` and run the actor until it answers
+    pub fn call<R, M>(&self, make: M) -> Result<R, CallError>
+        where M: FnOnce(Reply<R>) -> T
+    {
+        let slot = Rc::new(RefCell::new(None));
+        match self.send(make(Reply(slot.clone()))) {
+            Ok(()) => {}
+            Err(SendError::Full(..)) => return Err(CallError::Full),
+            Err(SendError::Stopped(..)) => return Err(CallError::Stopped),
+        }
+
+        if !self.run() {
+            return Err(CallError::Busy);
+        }
+
+        let value = slot.borrow_mut().take();
+        match value {
+            Some(value) => Ok(value),
+            None if self.is_alive() => Err(CallError::NoReply),
+            None => Err(CallError::Stopped),
+        }
+    }
+
+    /// Stop accepting messages and let the actor finish the queued ones
+    pub fn stop(&self) {
+        self.inner.closed.set(true);
+        self.run();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backpressure() {
+        let addr = spawn(2, |mailbox: &mut Mailbox<u32>| while mailbox.recv().is_some() {});
+
+        addr.send(1).unwrap();
+        addr.send(2).unwrap();
+        match addr.send(3) {
+            Err(SendError::Full(3)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        addr.run();
+        addr.send(3).unwrap();
+    }
+
+    #[test]
+    fn graceful_stop() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+
+        let seen2 = seen.clone();
+        let addr = spawn(8, move |mailbox| {
+            while let Some(msg) = mailbox.recv() {
+                seen2.borrow_mut().push(msg);
+            }
+            seen2.borrow_mut().push(0);
+        });
+
+        addr.send(1).unwrap();
+        addr.send(2).unwrap();
+        addr.stop();
+
+        assert_eq!(*seen.borrow(), [1, 2, 0]);
+        assert!(!addr.is_alive());
+        assert!(addr.send(3).is_err());
+    }
+
+    #[test]
+    fn panicked_actor() {
+        let addr = spawn(8, |mailbox: &mut Mailbox<Reply<u32>>| {
+            mailbox.recv();
+            panic!("actor failed");
+        });
+
+        assert_eq!(addr.call(|reply| reply), Err(CallError::Stopped));
+        assert!(!addr.is_alive());
+    }
+}
@@ -0,0 +1,148 @@
+//! Slab storage for coroutine metadata
+//!
+//! Slots are allocated in fixed size chunks which are never moved or freed, so a pointer to a
+//! slot stays dereferenceable for the whole lifetime of the process. Every slot carries a
+//! generation counter which is bumped when the slot is released, which lets an
+//! `(index, generation)` pair tell whether it still refers to the same value.
+//!
+//! The pointers handed out are only good while the slot holds the value, it is up to the
+//! caller to check the generation before dereferencing one it has kept around.
+
+use std::cell::UnsafeCell;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const CHUNK_SIZE: usize = 256;
+
+struct Slot<T> {
+    generation: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+/// Chunked slab with stable addresses
+pub struct Arena<T> {
+    chunks: Vec<Box<[Slot<T>]>>,
+    free: Vec<usize>,
+    // Number of slots ever handed out, occupied or free
+    allocated: usize,
+}
+
+impl<T> Arena<T> {
+    /// Creates an empty arena, no memory is allocated until the first insert
+    pub const fn new() -> Arena<T> {
+        Arena {
+            chunks: Vec::new(),
+            free: Vec::new(),
+            allocated: 0,
+        }
+    }
+
+    #[inline]
+    fn slot(&self, index: usize) -> &Slot<T> {
+        &self.chunks[index / CHUNK_SIZE][index % CHUNK_SIZE]
+    }
+
+    /// Stores `value` and returns its index, generation and a pointer to it
+    pub fn insert(&mut self, value: T) -> (usize, usize, NonNull<T>) {
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                if self.allocated == self.chunks.len() * CHUNK_SIZE {
+                    let chunk = (0..CHUNK_SIZE)
+                        .map(|_| {
+                            Slot {
+                                generation: AtomicUsize::new(0),
+                                value: UnsafeCell::new(None),
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    self.chunks.push(chunk.into_boxed_slice());
+                }
+                self.allocated += 1;
+                self.allocated - 1
+            }
+        };
+
+        let slot = self.slot(index);
+        let value_ref = unsafe { &mut *slot.value.get() };
+        debug_assert!(value_ref.is_none());
+        *value_ref = Some(value);
+
+        let ptr = NonNull::from(value_ref.as_mut().unwrap());
+        (index, slot.generation.load(Ordering::Acquire), ptr)
+    }
+
+    /// Takes the value out of the slot and invalidates all outstanding `(index, generation)` pairs
+    ///
+    /// The caller must guarantee that nobody holds a reference into the slot anymore.
+    pub unsafe fn remove(&mut self, index: usize) -> Option<T> {
+        let value = {
+            let slot = self.slot(index);
+            let value = (*slot.value.get()).take();
+            if value.is_some() {
+                slot.generation.fetch_add(1, Ordering::Release);
+            }
+            value
+        };
+
+        if value.is_some() {
+            self.free.push(index);
+        }
+        value
+    }
+
+    /// Pointer to the value if `generation` is still the current generation of the slot
+    pub fn get(&self, index: usize, generation: usize) -> Option<NonNull<T>> {
+        if index >= self.allocated {
+            return None;
+        }
+
+        let slot = self.slot(index);
+        if slot.generation.load(Ordering::Acquire) != generation {
+            return None;
+        }
+
+        unsafe { (*slot.value.get()).as_mut().map(NonNull::from) }
+    }
+
+    /// Index, generation and pointer of every occupied slot
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, NonNull<T>)> + '_ {
+        (0..self.allocated).filter_map(move |index| {
+            let slot = self.slot(index);
+            let generation = slot.generation.load(Ordering::Acquire);
+            unsafe { (*slot.value.get()).as_mut().map(|v| (index, generation, NonNull::from(v))) }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generation() {
+        let mut arena = Arena::new();
+
+        let (index, generation, ptr) = arena.insert(1);
+        assert_eq!(arena.get(index, generation), Some(ptr));
+        assert_eq!(unsafe { arena.remove(index) }, Some(1));
+        assert_eq!(arena.get(index, generation), None);
+
+        let (index2, generation2, _) = arena.insert(2);
+        assert_eq!(index2, index);
+        assert!(generation2 != generation);
+    }
+
+    #[test]
+    fn stable_address() {
+        let mut arena = Arena::new();
+
+        let (_, _, first) = arena.insert(0usize);
+        for i in 1..CHUNK_SIZE * 3 {
+            arena.insert(i);
+        }
+
+        assert_eq!(unsafe { *first.as_ref() }, 0);
+        assert_eq!(arena.iter().count(), CHUNK_SIZE * 3);
+    }
+}
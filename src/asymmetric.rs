@@ -21,69 +21,191 @@
 //  DEALINGS IN THE SOFTWARE.
 
 //! Asymmetric coroutines
+//!
+//! # Drop order
+//!
+//! Dropping a `Handle` of an unfinished coroutine unwinds that coroutine on the spot, its
+//! frames are dropped from the innermost one outwards before `drop` returns. A `Handle` owned
+//! by another coroutine is no exception: when the parent is unwound, the handle is dropped
+//! with the other locals of its frame in the usual reverse declaration order, and the child,
+//! together with all the coroutines it owns in turn, has been unwound completely before the
+//! parent drops the locals declared before the handle and leaves that frame.
+//!
+//! # Cancellation
+//!
+//! Coroutines remember which coroutine they have been spawned from. Dropping the handle of
+//! an unfinished coroutine cancels the children whose handles live elsewhere, e.g. in a
+//! queue or on a thread pool, and their children in turn: each of them is unwound the next
+//! time it is resumed, and that `resume` fails with `Error::Cancelled`. Detached children
+//! are left alone, they run to completion.
+//!
+//! # `panic = "abort"`
+//!
+//! Without unwinding a panic inside a coroutine aborts the process like anywhere else, so
+//! `Error::Panicking` and `Coroutine::catching` never come into play. Dropping the handle of
+//! an unfinished coroutine cannot unwind it either: its stack is released as it is, the values
+//! owned by its frames are leaked instead of dropped.
 
 use std::fmt;
-use std::usize;
+use std::marker::PhantomData;
 use std::panic;
 use std::mem;
+use std::panic::Location;
+use std::ptr::{self, NonNull};
 use std::iter::Iterator;
 use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use context::{Context, Transfer};
-use context::stack::ProtectedFixedSizeStack;
+use context::stack::{ProtectedFixedSizeStack, Stack, StackError};
 
-use options::Options;
+use arena::Arena;
+use hooks::{self, Kind};
+#[cfg(feature = "latency-histogram")]
+use latency::{self, Direction};
+use monitor::{self, Record};
+use options::{self, MIN_STACK_SIZE, Options, SandboxOptions};
+use stack;
+use SpawnError;
+use rand::Rng;
+use replay;
+use watchdog;
+
+/// Metadata of all coroutines, kept apart from their stacks
+static COROUTINES: Mutex<Arena<Coroutine>> = Mutex::new(Arena::new());
+
+/// Source of coroutine ids, in spawn order
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Coroutines whose handles have not been dropped yet, and the cap on them
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static MAX_LIVE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// The coroutine running on this thread, null if none
+thread_local!(static CURRENT: Cell<*mut Coroutine> = const { Cell::new(ptr::null_mut()) });
+
+thread_local!(static DETACHED: RefCell<VecDeque<Handle>> = const { RefCell::new(VecDeque::new()) });
 
 #[derive(Debug)]
 struct ForceUnwind;
 
+// Unwinds out of the `with_deadline` section at this depth of nesting
+#[derive(Debug)]
+struct DeadlineExceeded(usize);
+
+// Bytes below the stack pointer a leaf function may use without moving it
+const STACK_RED_ZONE: usize = 128;
+
+// Bytes the switch itself pushes below the stack pointer recorded right before it,
+// `jump_fcontext` takes 64 on x86-64 and 176 on AArch64
+const SWITCH_FRAME: usize = 512;
+
+thread_local!(static SHARED_STACK: RefCell<Weak<SharedStack>> = const { RefCell::new(Weak::new()) });
+
+/// Everything the new coroutine needs to start running.
+///
+/// `spawn` writes it to the top of the coroutine's own stack, no code runs on that stack until
+/// the first `resume`, which passes its address and fills in `data` with the resume argument.
+/// Coroutines on a shared stack own no stack.
+#[repr(C)]
+struct InitData<F> {
+    data: usize,
+    coro: NonNull<Coroutine>,
+    stack: Option<ProtectedFixedSizeStack>,
+    callback: F,
+}
+
+type Entry = extern "C" fn(Transfer) -> !;
 
-trait FnBox {
-    fn call_box(self: Box<Self>, meta_ref: &mut Coroutine, data: usize) -> usize;
+/// Stack of a thread which all its shared stack coroutines run on, one at a time
+#[derive(Debug)]
+struct SharedStack {
+    stack: ProtectedFixedSizeStack,
+    // Whose frames are on the stack right now, null if nobody's
+    occupant: Cell<*mut Coroutine>,
 }
 
+impl SharedStack {
+    fn current() -> Result<Rc<SharedStack>, SpawnError> {
+        SHARED_STACK.with(|shared| {
+            if let Some(stack) = shared.borrow().upgrade() {
+                return Ok(stack);
+            }
+
+            let stack = ProtectedFixedSizeStack::new(options::SHARED_STACK_SIZE).map_err(|err| {
+                match err {
+                    StackError::ExceedsMaximumSize(max) => {
+                        SpawnError::StackTooLarge {
+                            size: options::SHARED_STACK_SIZE,
+                            max,
+                        }
+                    }
+                    StackError::IoError(err) => SpawnError::Io(err),
+                }
+            })?;
+            let stack = Rc::new(SharedStack {
+                stack,
+                occupant: Cell::new(ptr::null_mut()),
+            });
+            *shared.borrow_mut() = Rc::downgrade(&stack);
+            Ok(stack)
+        })
+    }
 
-impl<F: FnOnce(&mut Coroutine, usize) -> usize> FnBox for F {
-    fn call_box(self: Box<F>, meta_ref: &mut Coroutine, data: usize) -> usize {
-        (*self)(meta_ref, data)
+    fn contains(&self, addr: usize) -> bool {
+        self.stack.bottom() as usize <= addr && addr < self.stack.top() as usize
     }
 }
 
-type Thunk<'a> = Box<FnBox + 'a>;
-
-struct InitData {
-    stack: ProtectedFixedSizeStack,
-    callback: Thunk<'static>,
+/// The frames of a coroutine on a shared stack while another one occupies it
+#[derive(Debug)]
+struct SharedFrames {
+    stack: Rc<SharedStack>,
+    // Copy of the top of the stack, from the saved stack pointer upwards
+    saved: Vec<u8>,
+    // Until the context is created on the first restore
+    entry: Option<Entry>,
 }
 
-extern "C" fn coroutine_entry(t: Transfer) -> ! {
-    // Take over the data from Coroutine::spawn_opts
-    let InitData { stack, callback } = unsafe {
-        let data_opt_ref = &mut *(t.data as *mut Option<InitData>);
-        data_opt_ref.take().expect("failed to acquire InitData")
-    };
+// Only coroutines with a `Local` handle run on a shared stack, so this never leaves its thread
+unsafe impl Send for SharedFrames {}
 
-    // This block will ensure the `meta` will be destroied before dropping the stack
-    let (ctx, result) = {
-        let mut meta = Coroutine {
-            context: None,
-            name: None,
-            state: State::Suspended,
-            panicked_error: None,
-        };
+extern "C" fn coroutine_entry<F>(t: Transfer) -> !
+    where F: FnOnce(&mut Coroutine, usize) -> usize
+{
+    #[cfg(feature = "latency-histogram")]
+    latency::finish();
+
+    // Move the data written by Coroutine::spawn_opts onto the frame, the space it occupied
+    // at the top of the stack is never read again
+    let InitData { data, coro, stack, callback } =
+        unsafe { ptr::read(t.data as *const InitData<F>) };
+
+    // Lives as long as the coroutine, `abandon` takes the stack from here
+    let mut stack = stack;
+    unsafe {
+        (*coro.as_ptr()).stack_slot = &mut stack as *mut Option<ProtectedFixedSizeStack> as usize;
+    }
 
-        // Yield back after take out the callback function
-        // Now the Coroutine is initialized
-        let meta_ptr = &mut meta as *mut _ as usize;
+    let result = {
+        let meta_ptr = coro.as_ptr() as usize;
         let result = unsafe {
             ::try(move || {
-                let Transfer { context, data } = t.context.resume(meta_ptr);
                 let meta_ref = &mut *(meta_ptr as *mut Coroutine);
-                meta_ref.context = Some(context);
+                meta_ref.context = Some(t.context);
+
+                // Dropped before it has ever been resumed
+                if meta_ref.force_unwinding {
+                    unwind(meta_ref);
+                }
 
                 // Take out the callback and run it
-                // let result = callback.call_box((meta_ref, data));
-                let result = callback.call_box(meta_ref, data);
+                let result = callback(meta_ref, data);
 
                 trace!("Coroutine `{}`: returned from callback with result {}",
                        meta_ref.debug_name(),
@@ -92,74 +214,107 @@ extern "C" fn coroutine_entry(t: Transfer) -> ! {
             })
         };
 
-        let mut loc_data = match result {
+        let meta = unsafe { &mut *coro.as_ptr() };
+        match result {
             Ok(d) => {
-                meta.state = State::Finished;
+                meta.state.set(State::Finished);
                 d
             }
             Err(err) => {
                 if err.is::<ForceUnwind>() {
-                    meta.state = State::Finished
+                    meta.state.set(State::Finished)
                 } else {
-                    meta.state = State::Panicked;
+                    meta.state.set(State::Panicked);
                     meta.panicked_error = Some(err);
                 }
                 usize::MAX
             }
-        };
-
-        trace!("Coroutine `{}`: exited with {:?}",
-               meta.debug_name(),
-               meta.state);
+        }
+    };
 
-        loop {
-            let Transfer { context, data } = meta.context.take().unwrap().resume(loc_data);
-            meta.context = Some(context);
-            loc_data = data;
+    leave(unsafe { &mut *coro.as_ptr() }, stack.take(), result)
+}
 
-            if meta.state == State::Finished {
-                break;
-            }
+/// Switch back to the resumer of the finished coroutine until `exit` is called, then release
+/// the stack
+fn leave(meta: &mut Coroutine, stack: Option<ProtectedFixedSizeStack>, result: usize) -> ! {
+    trace!("Coroutine `{}` (spawned at {}): exited with {:?}",
+           meta.debug_name(),
+           meta.location,
+           meta.state.get());
+
+    let mut loc_data = result;
+    loop {
+        // Switched back into once more to release the stack
+        meta.saved_sp = stack_pointer();
+        let Transfer { context, data } = meta.context.take().unwrap().resume(loc_data);
+        meta.context = Some(context);
+        loc_data = data;
+
+        if meta.state.get() == State::Finished {
+            break;
         }
+    }
 
-        trace!("Coroutine `{}`: finished => dropping stack",
-               meta.debug_name());
+    trace!("Coroutine `{}`: finished => dropping stack",
+           meta.debug_name());
 
-        // If panicked inside, the meta.context stores the actual return Context
-        (meta.take_context(), loc_data)
-    };
+    // If panicked inside, the meta.context stores the actual return Context
+    let ctx = meta.take_context();
 
     // Drop the stack after it is finished
-    let mut stack_opt = Some((stack, result));
+    let mut stack_opt = Some((stack, loc_data));
     ctx.resume_ontop(&mut stack_opt as *mut _ as usize, coroutine_exit);
 
     unreachable!();
 }
 
+/// Stop the coroutine which is being dropped, from its own stack
+#[cfg(not(panic = "abort"))]
+fn unwind(_: &mut Coroutine) -> ! {
+    panic::resume_unwind(Box::new(ForceUnwind))
+}
+
+/// Stop the coroutine which is being dropped, from its own stack
+///
+/// Unwinding would abort the process, so the frames are left as they are and the stack is
+/// released right away, leaking whatever they own.
+#[cfg(panic = "abort")]
+fn unwind(meta: &mut Coroutine) -> ! {
+    meta.state.set(State::Finished);
+    let stack = unsafe { (*(meta.stack_slot as *mut Option<ProtectedFixedSizeStack>)).take() };
+    leave(meta, stack, usize::MAX)
+}
+
+// Handed to `run_ontop` through the data of the switch, lives on the stack switched away from
+struct Ontop<'a> {
+    f: &'a mut dyn FnMut(usize) -> usize,
+    data: usize,
+}
+
+extern "C" fn run_ontop(mut t: Transfer) -> Transfer {
+    let ontop = unsafe { &mut *(t.data as *mut Ontop) };
+    t.data = (ontop.f)(ontop.data);
+    t
+}
+
 extern "C" fn coroutine_exit(mut t: Transfer) -> Transfer {
     let data = unsafe {
-        // Drop the stack
-        let stack_ref = &mut *(t.data as *mut Option<(ProtectedFixedSizeStack, usize)>);
-        let (_, result) = stack_ref.take().unwrap();
+        // Hand the stack over to the pool
+        let stack_ref = &mut *(t.data as *mut Option<(Option<ProtectedFixedSizeStack>, usize)>);
+        let (stack, result) = stack_ref.take().unwrap();
+        if let Some(stack) = stack {
+            stack::recycle(stack);
+        }
         result
     };
 
+    // The returned context belongs to the stack we have just released,
+    // the resumer must never switch to it again.
     t.data = data;
-    t.context = unsafe { mem::transmute(0usize) };
     t
 }
 
-extern "C" fn coroutine_unwind(t: Transfer) -> Transfer {
-    // Save the Context in the Coroutine object
-    // because the `t` won't be able to be passed to the caller
-    let coro = unsafe { &mut *(t.data as *mut Coroutine) };
-
-    coro.context = Some(t.context);
-
-    trace!("Coroutine `{}`: unwinding", coro.debug_name());
-    panic::resume_unwind(Box::new(ForceUnwind));
-}
-
 /// Coroutine state
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum State {
@@ -177,54 +332,681 @@ pub enum State {
     Panicked,
 }
 
+const STATES: [State; 5] =
+    [State::Suspended, State::Running, State::Parked, State::Finished, State::Panicked];
+
+// Written by the thread running the coroutine, read through `WeakHandle` on the thread it was
+// spawned on, which is another one once a `Handle<Sendable>` has moved
+#[derive(Debug)]
+struct StateCell(AtomicU8);
+
+impl StateCell {
+    fn new(state: State) -> StateCell {
+        StateCell(AtomicU8::new(state as u8))
+    }
+
+    #[inline]
+    fn get(&self) -> State {
+        STATES[self.0.load(Ordering::Acquire) as usize]
+    }
+
+    #[inline]
+    fn set(&self, state: State) {
+        self.0.store(state as u8, Ordering::Release);
+    }
+}
+
+// Stack pointer of the running code
+#[inline(always)]
+fn stack_pointer() -> usize {
+    let sp: usize;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        ::std::arch::asm!("mov {}, rsp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        ::std::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let marker = 0u8;
+        sp = ::std::hint::black_box(&marker) as *const u8 as usize;
+    }
+    sp
+}
+
+// Where the running code is, and its frame pointer which starts the chain `backtrace::walk`
+// follows
+#[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+#[inline(always)]
+fn frame() -> (usize, usize) {
+    let (ip, fp): (usize, usize);
+    unsafe {
+        ::std::arch::asm!("lea {}, [rip]", "mov {}, rbp",
+                          out(reg) ip,
+                          out(reg) fp,
+                          options(nomem, nostack, preserves_flags));
+    }
+    (ip, fp)
+}
+
 /// Coroutine context representation
 #[derive(Debug)]
 pub struct Coroutine {
+    index: usize,
+    generation: usize,
+    id: usize,
+    // Address of the `InitData` until the coroutine is resumed for the first time
+    init: usize,
     context: Option<Context>,
     name: Option<String>,
-    state: State,
-    panicked_error: Option<Box<Any + Send + 'static>>,
+    location: &'static Location<'static>,
+    state: StateCell,
+    panicked_error: Option<Box<dyn Any + Send + 'static>>,
+    force_unwinding: bool,
+    // Raised by the watchdog when the deadline of `resume_timeout` passes
+    preempt: Option<Arc<AtomicBool>>,
+    preempted: bool,
+    sandbox: Option<Sandbox>,
+    // Only when spawned while the monitor is enabled
+    monitor: Option<Arc<Mutex<Record>>>,
+    // Seeded on the first use of `rand::local_rng`
+    rng: Option<Rng>,
+    // Address of the `FdWait` the coroutine is parked on, 0 if none
+    fd_wait: usize,
+    // Stack pointer of the last switch away from the coroutine, where its frames end while
+    // it is suspended
+    saved_sp: usize,
+    #[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+    saved_frame: (usize, usize),
+    // Values left to yield as announced by `hint_len`
+    len_hint: Option<usize>,
+    // Accounted in `stack::allocated` until the handle is dropped
+    stack_size: usize,
+    stack_bottom: usize,
+    shared: Option<SharedFrames>,
+    // Owned by a `RawHandle` rather than a `Handle`
+    raw: bool,
+    // Address of the stack on the entry frame once the coroutine has started
+    stack_slot: usize,
+    // Of the `with_deadline` sections the coroutine is in, outermost first
+    deadlines: Vec<Instant>,
+    // Slot of the coroutine this one has been spawned from, never changes
+    parent: Option<(usize, usize)>,
+    // Coroutines spawned from this one whose handles are still alive
+    children: AtomicUsize,
+    detached: AtomicBool,
+    // Set when the parent is cancelled, the coroutine is unwound on its next resume
+    cancelled: AtomicBool,
+    // Ran into the guard page under `overflow::run_with_overflow_recovery`, its frames are lost
+    overflowed: bool,
+}
+
+#[derive(Debug)]
+struct Sandbox {
+    options: SandboxOptions,
+    switches: usize,
+}
+
+/// Marks a coroutine as the current one of this thread for as long as it is alive
+struct Enter(*mut Coroutine);
+
+impl Enter {
+    fn new(coro: *mut Coroutine) -> Enter {
+        Enter(CURRENT.with(|current| current.replace(coro)))
+    }
+}
+
+impl Drop for Enter {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+/// Number of coroutines which have been spawned and whose handles are still alive
+pub fn live() -> usize {
+    LIVE.load(Ordering::Relaxed)
+}
+
+/// Cap the number of live coroutines, `None` to lift the cap
+///
+/// Spawning beyond it fails with `SpawnError::CoroutineLimit` from `try_spawn` and panics
+/// from `spawn`, which keeps a misbehaving handler from spawning coroutines without bound.
+/// Lowering the cap below `live()` does not affect the coroutines which are already there.
+///
+/// ```rust
+/// use coroutine::SpawnError;
+/// use coroutine::asymmetric::{self, Coroutine};
+///
+/// asymmetric::set_max_live(Some(asymmetric::live() + 1));
+///
+/// let first = Coroutine::try_spawn(|_, _| 0).unwrap();
+/// match Coroutine::try_spawn(|_, _| 0) {
+///     Err(SpawnError::CoroutineLimit { .. }) => {}
+///     _ => panic!("limit not enforced"),
+/// }
+///
+/// drop(first);
+/// assert!(Coroutine::try_spawn(|_, _| 0).is_ok());
+/// asymmetric::set_max_live(None);
+/// ```
+pub fn set_max_live(limit: Option<usize>) {
+    MAX_LIVE.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// The current cap on live coroutines, `None` if unlimited
+pub fn max_live() -> Option<usize> {
+    match MAX_LIVE.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        limit => Some(limit),
+    }
+}
+
+fn reserve_live() -> Result<(), SpawnError> {
+    let limit = MAX_LIVE.load(Ordering::Relaxed);
+    LIVE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| {
+            Some(live + 1).filter(|&live| live <= limit)
+        })
+        .map(|_| ())
+        .map_err(|_| SpawnError::CoroutineLimit { limit })
+}
+
+/// Slot of the coroutine running on this thread, which is about to spawn a child
+fn link_parent() -> Option<(usize, usize)> {
+    with_current(|current| {
+        current.map(|parent| {
+            parent.children.fetch_add(1, Ordering::Relaxed);
+            (parent.index, parent.generation)
+        })
+    })
+}
+
+/// Mark the coroutines spawned from the given one as cancelled, along with the ones spawned
+/// from them in turn
+///
+/// Detached coroutines and everything below them are left alone.
+fn cancel_descendants(index: usize, generation: usize) {
+    let coros = COROUTINES.lock().unwrap();
+
+    let mut children = HashMap::<(usize, usize), Vec<(usize, usize, NonNull<Coroutine>)>>::new();
+    for (index, generation, coro) in coros.iter() {
+        if let Some(parent) = unsafe { coro.as_ref().parent } {
+            children.entry(parent).or_default().push((index, generation, coro));
+        }
+    }
+
+    let mut pending = vec![(index, generation)];
+    while let Some(parent) = pending.pop() {
+        for &(index, generation, coro) in children.get(&parent).into_iter().flatten() {
+            let coro = unsafe { coro.as_ref() };
+            if !coro.detached.load(Ordering::Acquire) {
+                coro.cancelled.store(true, Ordering::Release);
+                pending.push((index, generation));
+            }
+        }
+    }
+}
+
+/// Switch from a fault at `addr` in the guard page of the running coroutine back to its
+/// resumer, returns only if the fault is not such an overflow
+///
+/// Called from the signal handler, on the alternate signal stack.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn recover_overflow(addr: usize) -> bool {
+    let current = CURRENT.with(|current| current.get());
+    if current.is_null() {
+        return false;
+    }
+
+    let coro = &mut *current;
+    let guard = options::page_size();
+    if coro.shared.is_some() || coro.stack_slot == 0 || addr >= coro.stack_bottom ||
+       addr < coro.stack_bottom - guard {
+        return false;
+    }
+    let context = match coro.context.take() {
+        Some(context) => context,
+        None => return false,
+    };
+
+    coro.overflowed = true;
+    coro.state.set(State::Panicked);
+    // Checked by the resumer once it is back, the sections are gone with the frames
+    coro.deadlines.clear();
+    context.resume(0);
+    unreachable!()
+}
+
+/// Run `f` with the coroutine running on this thread, `None` outside of any coroutine
+pub(crate) fn with_current<R, F>(f: F) -> R
+    where F: FnOnce(Option<&mut Coroutine>) -> R
+{
+    let current = CURRENT.with(|current| current.get());
+    if current.is_null() {
+        f(None)
+    } else {
+        f(Some(unsafe { &mut *current }))
+    }
 }
 
 impl Coroutine {
     /// Spawn a coroutine with `Options`
+    ///
+    /// Panics if the coroutine cannot be spawned, see `try_spawn_opts`.
+    ///
+    /// With `Options::shared_stack` the coroutine gets no stack of its own, it runs on a stack
+    /// shared by all such coroutines of the current thread. Switching into one of them copies
+    /// the frames of the coroutine that ran there before out to the heap and its own frames
+    /// back in, so its memory is only as large as its deepest suspended call chain, at the
+    /// cost of a copy on every switch between different shared coroutines. While it is
+    /// suspended its locals do not stay at their address: nothing may point into its stack
+    /// from the outside, and it cannot resume another shared coroutine of its thread
+    /// (which panics). `spawn_send` ignores the option.
     #[inline]
+    #[track_caller]
     pub fn spawn_opts<F>(f: F, opts: Options) -> Handle
         where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
     {
-        Self::spawn_opts_impl(Box::new(f) as Thunk<'static>, opts)
+        match Self::spawn_opts_impl(f, opts, true, Location::caller()) {
+            Ok(handle) => handle,
+            Err(err) => panic!("{}", err),
+        }
     }
 
     /// Spawn a coroutine with default options
     #[inline]
+    #[track_caller]
     pub fn spawn<F>(f: F) -> Handle
         where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
     {
-        Self::spawn_opts_impl(Box::new(f), Options::default())
+        Self::spawn_opts(f, Options::default())
+    }
+
+    /// Spawn a coroutine with a stack of `SIZE` bytes, checked at compile time
+    ///
+    /// `SIZE` has to be a multiple of 4 KiB, large enough for `MIN_STACK_SIZE` on top of the
+    /// closure, or the program does not build. Panics if the stack cannot be mapped.
+    ///
+    /// ```rust
+    /// use coroutine::asymmetric::Coroutine;
+    ///
+    /// let mut coro = Coroutine::spawn_const_stack::<{ 64 * 1024 }>(|_, data| data + 1);
+    /// assert_eq!(coro.resume(1).unwrap(), 2);
+    /// ```
+    ///
+    /// ```compile_fail
+    /// use coroutine::asymmetric::Coroutine;
+    ///
+    /// Coroutine::spawn_const_stack::<1000>(|_, _| 0);
+    /// ```
+    #[inline]
+    #[track_caller]
+    pub fn spawn_const_stack<const SIZE: usize>(f: impl FnOnce(&mut Coroutine, usize) -> usize +
+                                                       'static)
+                                                -> Handle {
+        Self::spawn_sized::<SIZE, _>(f)
+    }
+
+    #[inline]
+    #[track_caller]
+    fn spawn_sized<const SIZE: usize, F>(f: F) -> Handle
+        where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
+    {
+        const {
+            assert!(SIZE.is_multiple_of(4096), "the stack size is not a multiple of 4 KiB");
+            assert!(SIZE >= MIN_STACK_SIZE + mem::size_of::<InitData<F>>() +
+                           mem::align_of::<InitData<F>>(),
+                    "the stack is too small for the coroutine");
+        }
+
+        let opts = Options {
+            stack_size: SIZE,
+            ..Options::default()
+        };
+        Self::spawn_opts(f, opts)
+    }
+
+    /// Spawn a coroutine with `Options`, failing instead of panicking
+    ///
+    /// The stack size is rounded up to a multiple of `page_size`, and has to hold at least
+    /// `MIN_STACK_SIZE` bytes on top of the closure.
+    #[inline]
+    #[track_caller]
+    pub fn try_spawn_opts<F>(f: F, opts: Options) -> Result<Handle, SpawnError>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
+    {
+        Self::spawn_opts_impl(f, opts, true, Location::caller())
     }
 
-    fn spawn_opts_impl(f: Thunk<'static>, opts: Options) -> Handle {
-        let data = InitData {
-            stack: ProtectedFixedSizeStack::new(opts.stack_size).expect("failed to acquire stack"),
-            callback: f,
+    /// Spawn a coroutine with default options, failing instead of panicking
+    #[inline]
+    #[track_caller]
+    pub fn try_spawn<F>(f: F) -> Result<Handle, SpawnError>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
+    {
+        Self::spawn_opts_impl(f, Options::default(), true, Location::caller())
+    }
+
+    /// Spawn a coroutine which may be resumed from other threads, with `Options`
+    ///
+    /// Panics if the coroutine cannot be spawned, see `try_spawn_send_opts`.
+    #[inline]
+    #[track_caller]
+    pub fn spawn_send_opts<F>(f: F, opts: Options) -> Handle<Sendable>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + Send + 'static
+    {
+        match Self::spawn_opts_impl(f, opts, false, Location::caller()) {
+            Ok(handle) => handle,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Spawn a coroutine which may be resumed from other threads, with default options
+    #[inline]
+    #[track_caller]
+    pub fn spawn_send<F>(f: F) -> Handle<Sendable>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + Send + 'static
+    {
+        Self::spawn_send_opts(f, Options::default())
+    }
+
+    /// Spawn a coroutine which may be resumed from other threads, failing instead of panicking
+    #[inline]
+    #[track_caller]
+    pub fn try_spawn_send_opts<F>(f: F, opts: Options) -> Result<Handle<Sendable>, SpawnError>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + Send + 'static
+    {
+        Self::spawn_opts_impl(f, opts, false, Location::caller())
+    }
+
+    fn spawn_opts_impl<F, K>(f: F,
+                             opts: Options,
+                             local: bool,
+                             location: &'static Location<'static>)
+                             -> Result<Handle<K>, SpawnError>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
+    {
+        let denied = CURRENT.with(|current| {
+            let current = current.get();
+            !current.is_null() &&
+            unsafe { (*current).sandbox.as_ref().is_some_and(|s| s.options.deny_spawn) }
+        });
+        if denied {
+            return Err(SpawnError::Denied);
+        }
+
+        reserve_live()?;
+        let handle = if opts.shared_stack && local {
+            Self::spawn_shared(f, opts, location)
+        } else {
+            Self::spawn_own_stack(f, opts, location)
+        };
+        if handle.is_err() {
+            LIVE.fetch_sub(1, Ordering::Relaxed);
+        }
+        handle
+    }
+
+    fn spawn_own_stack<F, K>(f: F,
+                             opts: Options,
+                             location: &'static Location<'static>)
+                             -> Result<Handle<K>, SpawnError>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
+    {
+        let min = options::round_to_page(MIN_STACK_SIZE + mem::size_of::<InitData<F>>() +
+                                         mem::align_of::<InitData<F>>());
+
+        let stack_size = match opts.stack_size {
+            // The floor may be below the minimum on systems with huge pages
+            0 => options::auto_stack_size(mem::size_of::<F>()).max(min),
+            size => size,
+        };
+        let stack_size = match opts.sandbox {
+            Some(ref sandbox) => stack_size.min(sandbox.max_stack_size),
+            None => stack_size,
         };
+        if stack_size < min {
+            return Err(SpawnError::StackTooSmall {
+                size: stack_size,
+                min,
+            });
+        }
 
-        let context = Context::new(&data.stack, coroutine_entry);
+        let stack_size = options::round_to_page(stack_size);
+        stack::reserve(stack_size)?;
+        let cached = stack::take(stack_size);
+        let stack = match cached.map_or_else(|| ProtectedFixedSizeStack::new(stack_size), Ok) {
+            Ok(stack) => stack,
+            Err(err) => {
+                stack::release(stack_size);
+                return Err(match err {
+                    StackError::ExceedsMaximumSize(max) => {
+                        SpawnError::StackTooLarge {
+                            size: stack_size,
+                            max,
+                        }
+                    }
+                    StackError::IoError(err) => SpawnError::Io(err),
+                });
+            }
+        };
 
-        // Give him the initialization data
-        let mut data_opt = Some(data);
-        let t = context.resume(&mut data_opt as *mut _ as usize);
-        debug_assert!(data_opt.is_none());
+        let (index, generation, coro) = COROUTINES.lock().unwrap().insert(Coroutine {
+            index: 0,
+            generation: 0,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            init: 0,
+            context: None,
+            name: opts.name,
+            location,
+            state: StateCell::new(State::Suspended),
+            panicked_error: None,
+            force_unwinding: false,
+            preempt: None,
+            preempted: false,
+            sandbox: opts.sandbox.map(|options| {
+                Sandbox {
+                    options,
+                    switches: 0,
+                }
+            }),
+            monitor: None,
+            rng: None,
+            fd_wait: 0,
+            saved_sp: 0,
+            #[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+            saved_frame: (0, 0),
+            len_hint: None,
+            stack_size,
+            stack_bottom: stack.bottom() as usize,
+            shared: None,
+            raw: false,
+            overflowed: false,
+            stack_slot: 0,
+            deadlines: Vec::new(),
+            parent: link_parent(),
+            children: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        });
 
-        let coro_ref = unsafe { &mut *(t.data as *mut Coroutine) };
-        coro_ref.context = Some(t.context);
+        // Reserve room for the InitData at the top of the stack,
+        // the context starts right below it
+        let init_addr = (stack.top() as usize - mem::size_of::<InitData<F>>()) &
+                        !(mem::align_of::<InitData<F>>().max(16) - 1);
+        debug_assert!(init_addr > stack.bottom() as usize + MIN_STACK_SIZE);
+        let context = Context::new(&Stack::new(init_addr as *mut _, stack.bottom()),
+                                   coroutine_entry::<F>);
+
+        unsafe {
+            ptr::write(init_addr as *mut InitData<F>,
+                       InitData {
+                           data: 0,
+                           coro,
+                           stack: Some(stack),
+                           callback: f,
+                       });
+        }
 
-        if let Some(name) = opts.name {
-            coro_ref.set_name(name);
+        let coro_ref = unsafe { &mut *coro.as_ptr() };
+        coro_ref.monitor = monitor::track(coro_ref.name.as_ref(), location);
+        coro_ref.index = index;
+        coro_ref.generation = generation;
+        coro_ref.init = init_addr;
+        coro_ref.context = Some(context);
+        hooks::fire(Kind::Spawn, coro_ref);
+        if replay::is_recording() && CURRENT.with(|current| current.get().is_null()) {
+            replay::spawned(coro_ref);
         }
 
         // Done!
-        Handle(coro_ref)
+        Ok(Handle {
+            index,
+            generation,
+            coro,
+            _kind: PhantomData,
+        })
+    }
+
+    fn spawn_shared<F, K>(f: F,
+                          opts: Options,
+                          location: &'static Location<'static>)
+                          -> Result<Handle<K>, SpawnError>
+        where F: FnOnce(&mut Coroutine, usize) -> usize + 'static
+    {
+        let shared = SharedStack::current()?;
+        let top = shared.stack.top() as usize;
+        let bottom = shared.stack.bottom() as usize;
+
+        // Same layout as on a stack of its own, but the InitData is written to the saved
+        // frames and only lands on the shared stack when the coroutine is switched in
+        let init_addr = (top - mem::size_of::<InitData<F>>()) &
+                        !(mem::align_of::<InitData<F>>().max(16) - 1);
+        if init_addr < bottom + MIN_STACK_SIZE {
+            return Err(SpawnError::StackTooSmall {
+                size: top - bottom,
+                min: options::round_to_page(MIN_STACK_SIZE + top - init_addr),
+            });
+        }
+
+        let mut saved = vec![0u8; top - init_addr];
+        let (index, generation, coro) = COROUTINES.lock().unwrap().insert(Coroutine {
+            index: 0,
+            generation: 0,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            init: 0,
+            context: None,
+            name: opts.name,
+            location,
+            state: StateCell::new(State::Suspended),
+            panicked_error: None,
+            force_unwinding: false,
+            preempt: None,
+            preempted: false,
+            sandbox: opts.sandbox.map(|options| {
+                Sandbox {
+                    options,
+                    switches: 0,
+                }
+            }),
+            monitor: None,
+            rng: None,
+            fd_wait: 0,
+            saved_sp: 0,
+            #[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+            saved_frame: (0, 0),
+            len_hint: None,
+            stack_size: 0,
+            stack_bottom: bottom,
+            shared: None,
+            raw: false,
+            overflowed: false,
+            stack_slot: 0,
+            deadlines: Vec::new(),
+            parent: link_parent(),
+            children: AtomicUsize::new(0),
+            detached: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+        });
+
+        unsafe {
+            ptr::write_unaligned(saved.as_mut_ptr() as *mut InitData<F>,
+                                 InitData {
+                                     data: 0,
+                                     coro,
+                                     stack: None,
+                                     callback: f,
+                                 });
+        }
+
+        let coro_ref = unsafe { &mut *coro.as_ptr() };
+        coro_ref.monitor = monitor::track(coro_ref.name.as_ref(), location);
+        coro_ref.index = index;
+        coro_ref.generation = generation;
+        coro_ref.init = init_addr;
+        coro_ref.shared = Some(SharedFrames {
+            stack: shared,
+            saved,
+            entry: Some(coroutine_entry::<F>),
+        });
+        hooks::fire(Kind::Spawn, coro_ref);
+        if replay::is_recording() && CURRENT.with(|current| current.get().is_null()) {
+            replay::spawned(coro_ref);
+        }
+
+        Ok(Handle {
+            index,
+            generation,
+            coro,
+            _kind: PhantomData,
+        })
+    }
+
+    /// Put the frames of a shared stack coroutine back onto the shared stack before switching
+    /// into it, saving those of the coroutine which has been running there
+    fn enter_shared(&mut self) {
+        let this = self as *mut Coroutine;
+        let frames = match self.shared {
+            Some(ref mut frames) => frames,
+            None => return,
+        };
+
+        let shared = frames.stack.clone();
+        let occupant = shared.occupant.get();
+        if occupant == this {
+            return;
+        }
+
+        let here = &occupant as *const _ as usize;
+        assert!(!shared.contains(here),
+                "a coroutine on the shared stack cannot resume another one of its thread");
+
+        let top = shared.stack.top() as usize;
+        let bottom = shared.stack.bottom() as usize;
+        if let Some(occupant) = unsafe { occupant.as_mut() } {
+            assert!(occupant.state.get() != State::Running,
+                    "the shared stack is still used by a running coroutine");
+            let start = occupant.saved_sp
+                .saturating_sub(SWITCH_FRAME + STACK_RED_ZONE)
+                .max(bottom);
+            let saved = &mut occupant.shared.as_mut().unwrap().saved;
+            saved.clear();
+            saved.extend_from_slice(unsafe {
+                ::std::slice::from_raw_parts(start as *const u8, top - start)
+            });
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(frames.saved.as_ptr(),
+                                     (top - frames.saved.len()) as *mut u8,
+                                     frames.saved.len());
+        }
+        if let Some(entry) = frames.entry.take() {
+            self.context = Some(Context::new(&Stack::new(self.init as *mut _, bottom as *mut _),
+                                             entry));
+        }
+        shared.occupant.set(this);
     }
 
     fn take_context(&mut self) -> Context {
@@ -234,7 +1016,7 @@ impl Coroutine {
     /// Gets state of Coroutine
     #[inline]
     pub fn state(&self) -> State {
-        self.state
+        self.state.get()
     }
 
     /// Gets name of Coroutine
@@ -243,12 +1025,40 @@ impl Coroutine {
         self.name.as_ref()
     }
 
+    /// Unique id of Coroutine, ids are handed out in spawn order starting from 1
+    #[inline]
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub(crate) fn rng_mut(&mut self) -> &mut Option<Rng> {
+        &mut self.rng
+    }
+
+    pub(crate) fn set_fd_wait(&mut self, wait: usize) {
+        self.fd_wait = wait;
+    }
+
     /// Set name of Coroutine
     #[inline]
     pub fn set_name(&mut self, name: String) {
+        if let Some(ref record) = self.monitor {
+            record.lock().unwrap().set_name(&name);
+        }
         self.name = Some(name);
     }
 
+    /// Source location of the `spawn` call that created this Coroutine
+    #[inline]
+    pub fn spawn_location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// Lowest address of the stack the coroutine runs on
+    pub(crate) fn stack_bottom(&self) -> usize {
+        self.stack_bottom
+    }
+
     /// Name for debugging
     #[inline]
     pub fn debug_name(&self) -> String {
@@ -258,33 +1068,105 @@ impl Coroutine {
         }
     }
 
-    #[inline(never)]
-    fn inner_yield_with_state(&mut self, state: State, data: usize) -> usize {
+    /// Switch to the saved context, running `ontop` on its stack first if there is one.
+    ///
+    /// The very first switch into a coroutine hands over the address of its `InitData`
+    /// instead, with `data` stored inside of it.
+    #[inline]
+    fn switch(&mut self, data: usize, ontop: Option<&mut dyn FnMut(usize) -> usize>) -> Transfer {
         let context = self.take_context();
 
         trace!("Coroutine `{}`: yielding to {:?}",
                self.debug_name(),
                &context);
 
-        self.state = state;
+        if self.init != 0 {
+            // There is no frame to return to on a fresh stack, so `ontop` runs right here
+            let data = ontop.map_or(data, |f| f(data));
+            let init = mem::replace(&mut self.init, 0);
+            unsafe {
+                // `data` is the first field of the #[repr(C)] InitData
+                *(init as *mut usize) = data;
+            }
+            return context.resume(init);
+        }
 
-        let Transfer { context, data } = context.resume(data);
+        if self.state.get() != State::Running {
+            self.saved_sp = stack_pointer();
+            #[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+            {
+                self.saved_frame = frame();
+            }
+        }
 
-        if unsafe { mem::transmute_copy::<_, usize>(&context) } != 0usize {
-            self.context = Some(context);
+        match ontop {
+            Some(f) => {
+                let mut ontop = Ontop { f, data };
+                context.resume_ontop(&mut ontop as *mut Ontop as usize, run_ontop)
+            }
+            None => context.resume(data),
         }
-        data
     }
 
     #[inline]
-    fn yield_with_state(&mut self, state: State, data: usize) -> ::Result<usize> {
-        let data = self.inner_yield_with_state(state, data);
+    fn inner_yield_with_state(&mut self, state: State, data: usize) -> usize {
+        self.inner_yield_ontop(state, data, None)
+    }
+
+    #[inline(never)]
+    fn inner_yield_ontop(&mut self,
+                         state: State,
+                         data: usize,
+                         ontop: Option<&mut dyn FnMut(usize) -> usize>)
+                         -> usize {
+        self.state.set(state);
+        if let Some(ref record) = self.monitor {
+            record.lock().unwrap().update(state);
+        }
+
+        #[cfg(feature = "latency-histogram")]
+        latency::start(match state {
+            State::Running => Direction::Resume,
+            _ => Direction::Yield,
+        });
+
+        let Transfer { context, data } = self.switch(data, ontop);
+        self.context = Some(context);
+
+        #[cfg(feature = "latency-histogram")]
+        latency::finish();
+
+        if self.force_unwinding {
+            unwind(self);
+        }
+        if !self.deadlines.is_empty() {
+            self.check_deadlines();
+        }
+        data
+    }
 
-        if self.state() == State::Panicked {
+    #[inline]
+    fn yield_with_state(&mut self,
+                        state: State,
+                        data: usize,
+                        ontop: Option<&mut dyn FnMut(usize) -> usize>)
+                        -> ::Result<usize> {
+        self.enter_shared();
+        self.state.set(state);
+        hooks::fire(Kind::Resume, self);
+        let data = self.inner_yield_ontop(state, data, ontop);
+        hooks::fire(Kind::Yield, self);
+
+        if self.overflowed {
+            Err(::Error::StackOverflow)
+        } else if self.state() == State::Panicked {
             match self.panicked_error.take() {
                 Some(err) => Err(::Error::Panicking(err)),
                 None => Err(::Error::Panicked),
             }
+        } else if let Some(err) = self.panicked_error.take() {
+            // Caught by `catching`, the coroutine is still alive
+            Err(::Error::Panicking(err))
         } else {
             Ok(data)
         }
@@ -293,128 +1175,743 @@ impl Coroutine {
     /// Yield the current coroutine with `Suspended` state
     #[inline]
     pub fn yield_with(&mut self, data: usize) -> usize {
+        if let Some(ref mut n) = self.len_hint {
+            *n = n.saturating_sub(1);
+        }
         self.inner_yield_with_state(State::Suspended, data)
     }
 
-    /// Yield the current coroutine with `Parked` state
+    /// Yield the current coroutine with `Suspended` state, running `f` on the stack of the
+    /// resumer before its `resume` returns
+    ///
+    /// `f` gets `data` and its result is what `resume` returns. By then the coroutine is off
+    /// its stack, which makes this the place to hand over or free whatever must not be touched
+    /// while the coroutine is still running on it. `f` must not panic, a panic during the
+    /// switch aborts the process.
     #[inline]
-    pub fn park_with(&mut self, data: usize) -> usize {
-        self.inner_yield_with_state(State::Parked, data)
-    }
-
-    fn force_unwind(&mut self) {
-        trace!("Coroutine `{}`: force unwinding", self.debug_name());
-
-        let ctx = self.take_context();
-        let Transfer { context, .. } =
-            ctx.resume_ontop(self as *mut Coroutine as usize, coroutine_unwind);
-        self.context = Some(context);
-
-        trace!("Coroutine `{}`: force unwound", self.debug_name());
+    pub fn yield_ontop<F>(&mut self, data: usize, f: F) -> usize
+        where F: FnOnce(usize) -> usize
+    {
+        if let Some(ref mut n) = self.len_hint {
+            *n = n.saturating_sub(1);
+        }
+        let mut f = Some(f);
+        let mut ontop = |data| (f.take().unwrap())(data);
+        self.inner_yield_ontop(State::Suspended, data, Some(&mut ontop))
     }
-}
-
-/// Handle for a Coroutine
-#[derive(Eq, PartialEq)]
-pub struct Handle(*mut Coroutine);
 
-impl Handle {
-    #[doc(hidden)]
+    /// Announce how many more times the coroutine is going to `yield_with`
+    ///
+    /// The count goes down with every yield and shows up in the `size_hint` of the handle,
+    /// which lets `collect` allocate the right amount up front. It is only a hint: a wrong
+    /// count costs some memory, nothing else.
     #[inline]
-    pub fn into_raw(self) -> *mut Coroutine {
-        let coro = self.0;
-        mem::forget(self);
-        coro
+    pub fn hint_len(&mut self, n: usize) {
+        self.len_hint = Some(n);
     }
 
-    #[doc(hidden)]
+    /// Values left to yield as announced by `hint_len`
     #[inline]
-    pub unsafe fn from_raw(coro: *mut Coroutine) -> Handle {
-        assert!(!coro.is_null());
-        Handle(coro)
+    pub fn len_hint(&self) -> Option<usize> {
+        self.len_hint
     }
 
-    /// Check if the Coroutine is already finished
+    /// Yield the current coroutine with `Parked` state
     #[inline]
-    pub fn is_finished(&self) -> bool {
-        match self.state() {
-            State::Finished | State::Panicked => true,
-            _ => false,
+    pub fn park_with(&mut self, data: usize) -> usize {
+        self.inner_yield_with_state(State::Parked, data)
+    }
+
+    /// Yield the current coroutine with `Parked` state and tell the monitor what it waits for
+    #[inline]
+    pub fn park_with_reason(&mut self, data: usize, reason: &'static str) -> usize {
+        if let Some(ref record) = self.monitor {
+            record.lock().unwrap().set_reason(reason);
+        }
+        self.inner_yield_with_state(State::Parked, data)
+    }
+
+    /// Run `f`, turning a panic inside of it into an `Err` of the current `resume`
+    ///
+    /// The panic is caught here rather than unwinding the whole coroutine: the resumer gets
+    /// `Err(Error::Panicking(..))` while the coroutine stays suspended. When it is resumed
+    /// again, `catching` returns `Err` with the data of that `resume` and execution goes on
+    /// right after it. This allows hosts to survive a failing command of a long-lived plugin
+    /// or REPL coroutine. Whatever `f` has done on the stack before panicking is lost, only the
+    /// frames of the caller survive.
+    ///
+    /// This is a section the coroutine opts into rather than an option of `spawn_opts`: the
+    /// frames a panic has unwound cannot be rolled back to the last yield, so only the code
+    /// around the section knows where to go on from.
+    pub fn catching<R, F>(&mut self, f: F) -> Result<R, usize>
+        where F: FnOnce(&mut Coroutine) -> R
+    {
+        let coro = self as *mut Coroutine as usize;
+        let result = unsafe { ::try(move || f(&mut *(coro as *mut Coroutine))) };
+
+        match result {
+            Ok(r) => Ok(r),
+            Err(err) => {
+                if err.is::<ForceUnwind>() || err.is::<DeadlineExceeded>() {
+                    panic::resume_unwind(err);
+                }
+
+                // The resumer gets the panic instead of the data yielded
+                self.panicked_error = Some(err);
+                Err(self.inner_yield_with_state(State::Suspended, 0))
+            }
+        }
+    }
+
+    /// Run `f`, giving up on it when the coroutine is resumed after `timeout` has passed
+    ///
+    /// The deadline is checked whenever the coroutine is switched back in after yielding or
+    /// parking inside of `f`. Once it has passed, `f` is unwound from the point where it
+    /// yielded, dropping its locals, and `Err(Elapsed)` is returned. Code which never yields
+    /// is not interrupted. Sections nest: an expired outer deadline unwinds the inner sections
+    /// as well, and an inner one cannot extend it. Resumers learn about the earliest deadline
+    /// through `Handle::deadline`, `fd::poll_fds` for one never waits past it.
+    ///
+    /// Relies on unwinding, with `panic = "abort"` the process aborts instead.
+    pub fn with_deadline<R, F>(&mut self, timeout: Duration, f: F) -> Result<R, ::Elapsed>
+        where F: FnOnce(&mut Coroutine) -> R
+    {
+        let level = self.deadlines.len();
+        self.deadlines.push(Instant::now() + timeout);
+
+        let coro = self as *mut Coroutine as usize;
+        let result = unsafe { ::try(move || f(&mut *(coro as *mut Coroutine))) };
+        self.deadlines.truncate(level);
+
+        match result {
+            Ok(r) => Ok(r),
+            Err(err) => {
+                match err.downcast::<DeadlineExceeded>() {
+                    Ok(exceeded) if exceeded.0 == level => Err(::Elapsed),
+                    Ok(exceeded) => panic::resume_unwind(exceeded),
+                    Err(err) => panic::resume_unwind(err),
+                }
+            }
+        }
+    }
+
+    /// Earliest deadline of the `with_deadline` sections the coroutine is in
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadlines.iter().min().cloned()
+    }
+
+    fn check_deadlines(&mut self) {
+        let now = Instant::now();
+        if let Some(level) = self.deadlines.iter().position(|&d| d <= now) {
+            panic::resume_unwind(Box::new(DeadlineExceeded(level)));
+        }
+    }
+
+    /// Check whether the resumer's deadline has passed and the coroutine should yield
+    ///
+    /// This is a cheap flag check, meant to be called regularly from long running code.
+    #[inline]
+    pub fn should_yield(&self) -> bool {
+        match self.preempt {
+            Some(ref flag) => flag.load(Ordering::Acquire),
+            None => false,
+        }
+    }
+
+    /// Yield back to the resumer if its deadline has passed, which makes `resume_timeout`
+    /// return `Err(Error::Timeout)`. Returns whether the coroutine was preempted.
+    #[inline]
+    pub fn checkpoint(&mut self) -> bool {
+        if self.should_yield() {
+            self.preempted = true;
+            self.inner_yield_with_state(State::Suspended, 0);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn force_unwind(&mut self) {
+        trace!("Coroutine `{}`: force unwinding", self.debug_name());
+
+        // The coroutine checks this flag right after it is switched back in
+        // and starts unwinding from its own stack
+        self.force_unwinding = true;
+        self.enter_shared();
+        let Transfer { context, .. } = self.switch(0, None);
+        self.context = Some(context);
+
+        trace!("Coroutine `{}`: force unwound", self.debug_name());
+    }
+
+    /// Let the finished coroutine leave its loop and release the stack.
+    fn exit(&mut self) {
+        self.state.set(State::Finished);
+        if self.overflowed {
+            // There is nothing to switch back to, unmap the stack right away
+            let stack = unsafe { &mut *(self.stack_slot as *mut Option<ProtectedFixedSizeStack>) };
+            drop(stack.take());
+            return;
+        }
+        self.enter_shared();
+        self.switch(0, None);
+
+        let this = self as *mut Coroutine;
+        if let Some(frames) = self.shared.take() {
+            if frames.stack.occupant.get() == this {
+                frames.stack.occupant.set(ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Marks a `Handle` which must stay on the thread it has been spawned on
+///
+/// ```compile_fail
+/// use std::thread;
+/// use coroutine::asymmetric::Coroutine;
+///
+/// let mut coro = Coroutine::spawn(|_, _| 0);
+/// thread::spawn(move || coro.resume(0));
+/// ```
+#[derive(Debug, Eq, PartialEq)]
+pub enum Local {}
+
+/// Marks a `Handle` of a coroutine spawned from a `Send` closure, which may move between
+/// threads
+///
+/// Only the captures of the closure are checked. Values the coroutine creates while running
+/// move along with it, so it must neither keep `!Send` values nor depend on thread-local
+/// storage across yields.
+///
+/// ```compile_fail,E0277
+/// use std::rc::Rc;
+/// use coroutine::asymmetric::Coroutine;
+///
+/// let rc = Rc::new(1);
+/// Coroutine::spawn_send(move |_, _| *rc);
+/// ```
+#[derive(Debug, Eq, PartialEq)]
+pub enum Sendable {}
+
+/// Handle for a Coroutine
+///
+/// Refers to the metadata by its slot in the coroutine arena, the pointer is only a shortcut
+/// to avoid looking the slot up on every access. It stays good for as long as the handle, the
+/// slot is only released by its drop. `K` is `Local` for coroutines spawned by
+/// `spawn`, whose handles are neither `Send` nor `Sync`, and `Sendable` for those spawned by
+/// `spawn_send`, whose handles are `Send`.
+#[derive(Eq, PartialEq)]
+pub struct Handle<K = Local> {
+    index: usize,
+    generation: usize,
+    coro: NonNull<Coroutine>,
+    _kind: PhantomData<K>,
+}
+
+unsafe impl Send for Handle<Sendable> {}
+
+impl Handle<Sendable> {
+    /// Forget that the coroutine may move between threads
+    pub fn into_local(self) -> Handle<Local> {
+        self.erase()
+    }
+}
+
+impl<K> Handle<K> {
+    fn erase(self) -> Handle<Local> {
+        let handle = Handle {
+            index: self.index,
+            generation: self.generation,
+            coro: self.coro,
+            _kind: PhantomData,
+        };
+        mem::forget(self);
+        handle
+    }
+
+    /// Give up the handle without dropping the coroutine, e.g. to store it in foreign code
+    ///
+    /// The coroutine stays alive until `from_raw` turns the `RawHandle` back into a handle.
+    pub fn into_raw(self) -> RawHandle<K> {
+        let raw = RawHandle {
+            index: self.index,
+            generation: self.generation,
+            _kind: PhantomData,
+        };
+        unsafe {
+            (*self.coro.as_ptr()).raw = true;
+        }
+        mem::forget(self);
+        raw
+    }
+
+    /// Take back ownership of a coroutine given up with `into_raw`
+    ///
+    /// Returns `None` if `raw` is stale: the coroutine has already been reclaimed through
+    /// another copy of `raw`, and maybe dropped since.
+    pub fn from_raw(raw: RawHandle<K>) -> Option<Handle<K>> {
+        let coros = COROUTINES.lock().unwrap();
+        let coro = coros.get(raw.index, raw.generation)?;
+        unsafe {
+            if !mem::replace(&mut (*coro.as_ptr()).raw, false) {
+                return None;
+            }
+        }
+
+        Some(Handle {
+            index: raw.index,
+            generation: raw.generation,
+            coro,
+            _kind: PhantomData,
+        })
+    }
+
+    /// Reference which does not keep the coroutine alive
+    #[inline]
+    pub fn downgrade(&self) -> WeakHandle {
+        WeakHandle {
+            index: self.index,
+            generation: self.generation,
+            coro: self.coro,
         }
     }
 
+    /// Whether the slot still holds the coroutine this handle was created for
+    fn is_valid(&self) -> bool {
+        COROUTINES.lock().unwrap().get(self.index, self.generation) == Some(self.coro)
+    }
+
+    #[inline]
+    fn coro(&self) -> &Coroutine {
+        debug_assert!(self.is_valid());
+        unsafe { self.coro.as_ref() }
+    }
+
+    #[inline]
+    fn coro_mut(&mut self) -> &mut Coroutine {
+        debug_assert!(self.is_valid());
+        unsafe { self.coro.as_mut() }
+    }
+
+    /// Give up ownership and let the coroutine run to completion
+    ///
+    /// The coroutine is moved to a registry of the current thread instead of being unwound,
+    /// and will be resumed (with `0`) by `run_detached` until it finishes. A panic inside a
+    /// detached coroutine is logged and swallowed, it never reaches the thread driving it.
+    /// Coroutines still detached when the thread exits are unwound at that point.
+    pub fn detach(self) {
+        self.coro().detached.store(true, Ordering::Release);
+        let handle = self.erase();
+        DETACHED.with(|detached| detached.borrow_mut().push_back(handle));
+    }
+
+    /// Check if the Coroutine is already finished
     #[inline]
-    fn yield_with_state(&mut self, state: State, data: usize) -> ::Result<usize> {
-        let coro = unsafe { &mut *self.0 };
-        coro.yield_with_state(state, data)
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state(), State::Finished | State::Panicked)
+    }
+
+    #[inline]
+    fn yield_with_state(&mut self,
+                        state: State,
+                        data: usize,
+                        ontop: Option<&mut dyn FnMut(usize) -> usize>)
+                        -> ::Result<usize> {
+        if replay::is_recording() && CURRENT.with(|current| current.get().is_null()) {
+            let woken_from = self.state();
+            let result = {
+                let _enter = Enter::new(self.coro.as_ptr());
+                self.coro_mut().yield_with_state(state, data, ontop)
+            };
+            replay::resumed(self.coro(), woken_from, data, &result);
+            return result;
+        }
+
+        let _enter = Enter::new(self.coro.as_ptr());
+        self.coro_mut().yield_with_state(state, data, ontop)
     }
 
     /// Resume the Coroutine
     #[inline]
     pub fn resume(&mut self, data: usize) -> ::Result<usize> {
+        self.resume_impl(data, None, None)
+    }
+
+    /// Resume the Coroutine, running `f` on its stack before the coroutine itself continues
+    ///
+    /// `f` gets `data` and its result is what the coroutine receives from the call it yielded
+    /// with. `f` runs in the context of the coroutine but before any of its code, see
+    /// `Coroutine::yield_ontop` for the other direction. A coroutine which has not been resumed
+    /// before has no frames yet, `f` runs on the stack of the caller then and its result is
+    /// the argument of the closure. `f` must not panic, a panic during the switch aborts the
+    /// process.
+    pub fn resume_ontop<F>(&mut self, data: usize, f: F) -> ::Result<usize>
+        where F: FnOnce(usize) -> usize
+    {
+        let mut f = Some(f);
+        let mut ontop = |data| (f.take().unwrap())(data);
+        self.resume_impl(data, None, Some(&mut ontop))
+    }
+
+    /// Resume the Coroutine up to `n` times, passing the items of `inputs` in order
+    ///
+    /// `0` is passed once `inputs` runs out. Stops early when the coroutine finishes or a
+    /// resume fails. Much cheaper than calling `resume` in a loop: the handle is validated and
+    /// the coroutine entered only once for the whole batch.
+    pub fn resume_n<I>(&mut self, n: usize, inputs: I) -> Vec<::Result<usize>>
+        where I: IntoIterator<Item = usize>
+    {
+        let mut out = Vec::with_capacity(n);
+        self.resume_n_into(n, inputs, &mut out);
+        out
+    }
+
+    /// Same as `resume_n`, appending to `out` so the buffer can be reused between batches
+    pub fn resume_n_into<I>(&mut self, n: usize, inputs: I, out: &mut Vec<::Result<usize>>)
+        where I: IntoIterator<Item = usize>
+    {
+        let mut inputs = inputs.into_iter();
+        out.reserve(n);
+
+        // Sandboxes are accounted on every single resume, recordings log them
+        if self.coro().sandbox.is_some() || replay::is_recording() ||
+           self.coro().cancelled.load(Ordering::Acquire) {
+            for _ in 0..n {
+                if self.is_finished() {
+                    break;
+                }
+                let result = self.resume(inputs.next().unwrap_or(0));
+                let failed = result.is_err();
+                out.push(result);
+                if failed {
+                    break;
+                }
+            }
+            return;
+        }
+
+        let _enter = Enter::new(self.coro.as_ptr());
+        let coro = self.coro_mut();
+        for _ in 0..n {
+            if matches!(coro.state.get(), State::Finished | State::Panicked) {
+                break;
+            }
+            let result = coro.yield_with_state(State::Running, inputs.next().unwrap_or(0), None);
+            let failed = result.is_err();
+            out.push(result);
+            if failed {
+                break;
+            }
+        }
+    }
+
+    /// Let the operating system reclaim the unused part of the stack of the suspended coroutine
+    ///
+    /// Everything below the saved stack pointer is dead while the coroutine is suspended, so
+    /// the whole pages down there are released with `madvise` and only faulted back in as the
+    /// coroutine needs them again. Meant for long-lived and mostly idle coroutines which once
+    /// ran deep calls. Returns the number of bytes released, nothing is done for coroutines
+    /// that are running, finished or have never been resumed.
+    pub fn discard_cold_stack(&mut self) -> usize {
+        let coro = self.coro();
+        if coro.init != 0 || coro.shared.is_some() ||
+           !matches!(coro.state.get(), State::Suspended | State::Parked) {
+            return 0;
+        }
+
+        // Stay clear of the switch and the red zone below the stack pointer
+        let page = options::page_size();
+        let end = coro.saved_sp.saturating_sub(SWITCH_FRAME + STACK_RED_ZONE) / page * page;
+        if end <= coro.stack_bottom {
+            return 0;
+        }
+
+        stack::discard(coro.stack_bottom, end - coro.stack_bottom);
+        end - coro.stack_bottom
+    }
+
+    /// Frames of the suspended coroutine, from where it has switched away outwards
+    ///
+    /// `None` for coroutines that are running, finished, have never been resumed or run on a
+    /// shared stack. See `coroutine::backtrace` for what it takes to get the whole chain.
+    #[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+    pub fn backtrace(&self) -> Option<::backtrace::Backtrace> {
+        let coro = self.coro();
+        if coro.init != 0 || coro.shared.is_some() ||
+           !matches!(coro.state.get(), State::Suspended | State::Parked) {
+            return None;
+        }
+
+        let top = coro.stack_bottom + coro.stack_size;
+        let (ip, fp) = coro.saved_frame;
+        Some(unsafe { ::backtrace::walk(ip, fp, coro.saved_sp, top) })
+    }
+
+    /// Resume the Coroutine and ask it to yield within `timeout`
+    ///
+    /// Preemption is cooperative: after `timeout` a flag is raised, and the coroutine
+    /// yields the next time it calls `checkpoint`, in which case `Err(Error::Timeout)` is
+    /// returned and the coroutine can be resumed again later. Coroutines which never call
+    /// `checkpoint` cannot be interrupted.
+    pub fn resume_timeout(&mut self, data: usize, timeout: Duration) -> ::Result<usize> {
+        self.resume_impl(data, Some(timeout), None)
+    }
+
+    fn resume_impl(&mut self,
+                   data: usize,
+                   timeout: Option<Duration>,
+                   ontop: Option<&mut dyn FnMut(usize) -> usize>)
+                   -> ::Result<usize> {
         assert!(!self.is_finished());
-        self.yield_with_state(State::Running, data)
+
+        if self.coro().cancelled.load(Ordering::Acquire) {
+            self.cancel();
+            return Err(::Error::Cancelled);
+        }
+
+        let mut timeout = timeout;
+        if let Some(ref mut sandbox) = self.coro_mut().sandbox {
+            if let Some(max) = sandbox.options.max_switches {
+                if sandbox.switches >= max {
+                    return Err(::Error::SwitchLimit);
+                }
+            }
+            sandbox.switches += 1;
+
+            if let Some(slice) = sandbox.options.max_slice {
+                timeout = Some(timeout.map_or(slice, |t| t.min(slice)));
+            }
+        }
+
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.yield_with_state(State::Running, data, ontop),
+        };
+
+        self.coro_mut().preempt = Some(watchdog::arm(Instant::now() + timeout));
+        let result = self.yield_with_state(State::Running, data, ontop);
+
+        let coro = self.coro_mut();
+        coro.preempt = None;
+        if mem::replace(&mut coro.preempted, false) && result.is_ok() {
+            return Err(::Error::Timeout);
+        }
+        result
+    }
+
+    // Unwind the unfinished coroutine, then cancel the children which have outlived it
+    fn cancel(&mut self) {
+        let _enter = Enter::new(self.coro.as_ptr());
+        self.coro_mut().force_unwind();
+
+        // Those owned by its frames have been dropped while unwinding
+        if self.coro().children.load(Ordering::Relaxed) != 0 {
+            cancel_descendants(self.index, self.generation);
+        }
     }
 
     /// Gets state of Coroutine
     #[inline]
     pub fn state(&self) -> State {
-        let coro = unsafe { &*self.0 };
-        coro.state()
+        self.coro().state()
     }
 
     /// Gets name of Coroutine
     #[inline]
     pub fn name(&self) -> Option<&String> {
-        let coro = unsafe { &*self.0 };
-        coro.name()
+        self.coro().name()
+    }
+
+    /// Unique id of Coroutine
+    #[inline]
+    pub fn id(&self) -> usize {
+        self.coro().id()
+    }
+
+    pub(crate) fn fd_wait(&self) -> usize {
+        self.coro().fd_wait
+    }
+
+    /// Values left to yield as announced by `Coroutine::hint_len`
+    #[inline]
+    pub fn len_hint(&self) -> Option<usize> {
+        self.coro().len_hint()
+    }
+
+    /// When the coroutine wants to be resumed at the latest, see `Coroutine::with_deadline`
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.coro().deadline()
     }
 
     /// Set name of Coroutine
     #[inline]
     pub fn set_name(&mut self, name: String) {
-        let coro = unsafe { &mut *self.0 };
-        coro.set_name(name)
+        self.coro_mut().set_name(name)
+    }
+
+    /// Source location of the `spawn` call that created this Coroutine
+    #[inline]
+    pub fn spawn_location(&self) -> &'static Location<'static> {
+        self.coro().spawn_location()
     }
 
     /// Name for debugging
     #[inline]
     pub fn debug_name(&self) -> String {
-        let coro = unsafe { &*self.0 };
-        coro.debug_name()
+        self.coro().debug_name()
     }
 }
 
-impl Drop for Handle {
+impl<K> Drop for Handle<K> {
     fn drop(&mut self) {
         trace!("Coroutine `{}`: dropping with {:?}",
                self.debug_name(),
                self.state());
 
-        let coro = unsafe { &mut *self.0 };
+        hooks::fire(Kind::Exit, self.coro());
+
+        let _enter = Enter::new(self.coro.as_ptr());
 
         if !self.is_finished() {
-            coro.force_unwind()
+            self.cancel();
+        }
+
+        self.coro_mut().exit();
+
+        stack::release(self.coro().stack_size);
+        LIVE.fetch_sub(1, Ordering::Relaxed);
+
+        // Nothing refers to the metadata now that the coroutine has released its stack
+        let parent = self.coro().parent;
+        let mut coros = COROUTINES.lock().unwrap();
+        if let Some((index, generation)) = parent {
+            if let Some(parent) = coros.get(index, generation) {
+                unsafe {
+                    parent.as_ref().children.fetch_sub(1, Ordering::Relaxed);
+                }
+            }
+        }
+        unsafe {
+            coros.remove(self.index);
+        }
+    }
+}
+
+/// A coroutine given up by `Handle::into_raw`
+///
+/// Plain data which can be copied and stored anywhere, `Handle::from_raw` checks that it is
+/// still good. Only `RawHandle<Sendable>` may cross threads.
+///
+/// ```compile_fail
+/// use std::thread;
+/// use coroutine::asymmetric::{Coroutine, Handle};
+///
+/// let raw = Coroutine::spawn(|_, _| 0).into_raw();
+/// thread::spawn(move || Handle::from_raw(raw));
+/// ```
+#[repr(C)]
+#[derive(Debug)]
+pub struct RawHandle<K = Local> {
+    index: usize,
+    generation: usize,
+    _kind: PhantomData<Handle<K>>,
+}
+
+impl<K> Clone for RawHandle<K> {
+    fn clone(&self) -> RawHandle<K> {
+        *self
+    }
+}
+
+impl<K> Copy for RawHandle<K> {}
+
+/// Non-owning reference to a coroutine, see `Handle::downgrade`
+///
+/// The owning `Handle` stays the only way to resume or drop the coroutine, there is no way to
+/// turn a `WeakHandle` back into one. Every query checks whether the coroutine still exists
+/// and returns `None` once its handle has been dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WeakHandle {
+    index: usize,
+    generation: usize,
+    coro: NonNull<Coroutine>,
+}
+
+impl WeakHandle {
+    fn with<R, F>(&self, f: F) -> Option<R>
+        where F: FnOnce(&Coroutine) -> R
+    {
+        // Holding the lock keeps the handle from releasing the slot in the meantime
+        let coroutines = COROUTINES.lock().unwrap();
+        if coroutines.get(self.index, self.generation) == Some(self.coro) {
+            Some(f(unsafe { self.coro.as_ref() }))
+        } else {
+            None
+        }
+    }
+
+    /// Check if the coroutine still exists and has not finished
+    pub fn is_alive(&self) -> bool {
+        self.state().is_some_and(|state| !matches!(state, State::Finished | State::Panicked))
+    }
+
+    /// State of the coroutine, `None` if its handle has been dropped
+    pub fn state(&self) -> Option<State> {
+        self.with(|coro| coro.state())
+    }
+
+    /// Id of the coroutine, `None` if its handle has been dropped
+    pub fn id(&self) -> Option<usize> {
+        self.with(|coro| coro.id())
+    }
+
+    /// Name for debugging, `None` if its handle has been dropped
+    pub fn debug_name(&self) -> Option<String> {
+        self.with(|coro| coro.debug_name())
+    }
+
+    /// Check if this refers to the coroutine of `handle`
+    pub fn ptr_eq<K>(&self, handle: &Handle<K>) -> bool {
+        self.index == handle.index && self.generation == handle.generation
+    }
+}
+
+/// Resume all coroutines detached on this thread until every one of them has finished
+///
+/// Coroutines are resumed in round-robin order, regardless of whether they yielded as
+/// `Suspended` or `Parked`. Coroutines detached while this is running are driven as well.
+pub fn run_detached() {
+    while let Some(mut coro) = DETACHED.with(|detached| detached.borrow_mut().pop_front()) {
+        if let Err(err) = coro.resume(0) {
+            error!("Coroutine `{}` (spawned at {}): detached coroutine panicked: {:?}",
+                   coro.debug_name(),
+                   coro.spawn_location(),
+                   err);
         }
 
-        coro.inner_yield_with_state(State::Finished, 0);
+        if !coro.is_finished() {
+            DETACHED.with(|detached| detached.borrow_mut().push_back(coro));
+        }
     }
 }
 
-impl fmt::Debug for Handle {
+impl<K> fmt::Debug for Handle<K> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.is_finished() {
             write!(f, "Coroutine(None, Finished)")
         } else {
             write!(f,
-                   "Coroutine(Some({}), {:?})",
+                   "Coroutine(Some({}), {:?}, spawned at {})",
                    self.debug_name(),
-                   self.state())
+                   self.state(),
+                   self.spawn_location())
         }
     }
 }
 
-impl Iterator for Handle {
+impl<K> Iterator for Handle<K> {
     type Item = ::Result<usize>;
     fn next(&mut self) -> Option<Self::Item> {
         if self.is_finished() {
@@ -424,6 +1921,18 @@ impl Iterator for Handle {
             Some(x)
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.is_finished() {
+            return (0, Some(0));
+        }
+
+        // The return value of the coroutine is the last item
+        match self.len_hint() {
+            Some(n) => (n + 1, None),
+            None => (1, None),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -516,6 +2025,267 @@ mod test {
         assert_eq!(orig.load(Ordering::SeqCst), 1);
     }
 
+    #[test]
+    fn lazy_start() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        let started = Rc::new(Cell::new(false));
+
+        let started2 = started.clone();
+        let mut coro = Coroutine::spawn(move |_, data| {
+            started2.set(true);
+            data
+        });
+
+        assert!(!started.get());
+        assert_eq!(coro.resume(5).unwrap(), 5);
+        assert!(started.get());
+    }
+
+    #[test]
+    fn spawn_location() {
+        let coro = Coroutine::spawn(|_, _| 0);
+        let line = line!() - 1;
+
+        assert_eq!(coro.spawn_location().file(), file!());
+        assert_eq!(coro.spawn_location().line(), line);
+        assert!(format!("{:?}", coro).contains(&format!("{}:{}", file!(), line)));
+    }
+
+    #[test]
+    fn resume_timeout() {
+        use std::time::Duration;
+
+        let mut coro = Coroutine::spawn(|coro, _| {
+            while !coro.checkpoint() {}
+            1
+        });
+
+        match coro.resume_timeout(0, Duration::from_millis(10)) {
+            Err(::Error::Timeout) => {}
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert_eq!(coro.state(), State::Suspended);
+        assert_eq!(coro.resume(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn resume_timeout_in_time() {
+        use std::time::Duration;
+
+        let mut coro = Coroutine::spawn(|coro, data| {
+            assert!(!coro.checkpoint());
+            data
+        });
+
+        assert_eq!(coro.resume_timeout(3, Duration::from_secs(10)).unwrap(), 3);
+    }
+
+    #[test]
+    fn sandbox_switch_limit() {
+        let opts = Options {
+            sandbox: Some(SandboxOptions {
+                max_switches: Some(2),
+                ..SandboxOptions::default()
+            }),
+            ..Options::default()
+        };
+        let mut coro = Coroutine::spawn_opts(|coro, _| {
+            loop {
+                coro.yield_with(0);
+            }
+        }, opts);
+
+        assert!(coro.resume(0).is_ok());
+        assert!(coro.resume(0).is_ok());
+        match coro.resume(0) {
+            Err(::Error::SwitchLimit) => {}
+            other => panic!("expected the switch limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sandbox_deny_spawn() {
+        let opts = Options {
+            sandbox: Some(SandboxOptions::default()),
+            ..Options::default()
+        };
+        let mut coro = Coroutine::spawn_opts(|_, _| {
+            Coroutine::spawn(|_, _| 0);
+            0
+        }, opts);
+
+        assert!(coro.resume(0).is_err());
+
+        // Not sandboxed anymore after leaving the coroutine
+        let mut coro = Coroutine::spawn(|_, _| 1);
+        assert_eq!(coro.resume(0).unwrap(), 1);
+    }
+
+    #[test]
+    fn drop_before_resume() {
+        use std::rc::Rc;
+
+        let captured = Rc::new(());
+        {
+            let captured = captured.clone();
+            let _coro = Coroutine::spawn(move |_, _| {
+                let _captured = captured;
+                0
+            });
+            // Let it drop without resuming
+        }
+
+        assert_eq!(Rc::strong_count(&captured), 1);
+    }
+
+    #[test]
+    fn nested_drop_order() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Guard(&'static str, Rc<RefCell<Vec<&'static str>>>);
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.1.borrow_mut().push(self.0);
+            }
+        }
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let (l1, l2, l3) = (log.clone(), log.clone(), log.clone());
+        let mut root = Coroutine::spawn(move |coro, _| {
+            let _before = Guard("root before", l1.clone());
+            let mut child = Coroutine::spawn(move |coro, _| {
+                let _before = Guard("child before", l2.clone());
+                let mut grandchild = Coroutine::spawn(move |coro, _| {
+                    let _guard = Guard("grandchild", l3);
+                    coro.yield_with(0)
+                });
+                let _after = Guard("child after", l2);
+                grandchild.resume(0).unwrap();
+                coro.yield_with(0)
+            });
+            let _after = Guard("root after", l1);
+            child.resume(0).unwrap();
+            coro.yield_with(0)
+        });
+
+        root.resume(0).unwrap();
+        assert!(log.borrow().is_empty());
+        drop(root);
+
+        assert_eq!(*log.borrow(),
+                   ["root after",
+                    "child after",
+                    "grandchild",
+                    "child before",
+                    "root before"]);
+    }
+
+    #[test]
+    fn drop_child_while_panicking() {
+        use std::rc::Rc;
+
+        let captured = Rc::new(());
+        let inner = captured.clone();
+        let mut parent = Coroutine::spawn(move |_, _| {
+            let mut child = Coroutine::spawn(move |coro, _| {
+                let _captured = inner;
+                coro.yield_with(0)
+            });
+            child.resume(0).unwrap();
+            panic!("parent failed");
+        });
+
+        assert!(parent.resume(0).is_err());
+        assert_eq!(parent.state(), State::Panicked);
+        assert_eq!(Rc::strong_count(&captured), 1);
+    }
+
+    #[test]
+    fn detach() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        let counter = Rc::new(Cell::new(0));
+
+        for _ in 0..3 {
+            let counter = counter.clone();
+            let coro = Coroutine::spawn(move |coro, _| {
+                for _ in 0..10 {
+                    counter.set(counter.get() + 1);
+                    coro.yield_with(0);
+                }
+                0
+            });
+            coro.detach();
+        }
+
+        let coro = Coroutine::spawn(|_, _| panic!("detached"));
+        coro.detach();
+
+        run_detached();
+        assert_eq!(counter.get(), 30);
+    }
+
+    #[test]
+    fn cancel_children() {
+        let escaped = Rc::new(RefCell::new(Vec::new()));
+        let completed = Rc::new(Cell::new(0));
+        let detached = |completed: Rc<Cell<usize>>| {
+            Coroutine::spawn(move |coro, _| {
+                    coro.yield_with(0);
+                    completed.set(completed.get() + 1);
+                    0
+                })
+                .detach()
+        };
+
+        let queue = escaped.clone();
+        let counter = completed.clone();
+        let mut parent = Coroutine::spawn(move |coro, _| {
+            let inner = queue.clone();
+            let child = Coroutine::spawn(move |coro, _| {
+                // Spawned from the child, cancelled with it
+                inner.borrow_mut().push(Coroutine::spawn(|coro, _| coro.yield_with(0)));
+                coro.yield_with(0)
+            });
+            queue.borrow_mut().push(child);
+            detached(counter);
+            coro.yield_with(0)
+        });
+        parent.resume(0).unwrap();
+        let mut child = escaped.borrow_mut().pop().unwrap();
+        child.resume(0).unwrap();
+        escaped.borrow_mut().push(child);
+        assert_eq!(escaped.borrow().len(), 2);
+
+        // Finishing does not cancel anything
+        let counter = completed.clone();
+        let mut done = Coroutine::spawn(move |_, _| {
+            detached(counter);
+            0
+        });
+        done.resume(0).unwrap();
+        drop(done);
+
+        drop(parent);
+        for child in escaped.borrow_mut().iter_mut() {
+            match child.resume(0) {
+                Err(::Error::Cancelled) => {}
+                r => panic!("{:?}", r),
+            }
+            assert!(child.is_finished());
+        }
+
+        // The detached children are still alive and run to completion
+        run_detached();
+        assert_eq!(completed.get(), 2);
+    }
+
     #[test]
     #[should_panic]
     fn resume_after_finished() {
@@ -541,10 +2311,395 @@ mod test {
         assert_eq!(coro.state(), State::Finished);
     }
 
+    #[test]
+    fn catching() {
+        let mut coro = Coroutine::spawn(|coro, mut cmd| {
+            loop {
+                let result = coro.catching(|_| {
+                    assert!(cmd != 0, "bad command");
+                    cmd * 2
+                });
+                cmd = match result {
+                    Ok(result) => coro.yield_with(result),
+                    // Goes on with the command resumed with after the panic
+                    Err(cmd) => cmd,
+                };
+            }
+        });
+
+        assert_eq!(coro.resume(1).unwrap(), 2);
+        match coro.resume(0) {
+            Err(::Error::Panicking(err)) => {
+                assert_eq!(err.downcast_ref::<&str>(), Some(&"bad command"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(coro.state(), State::Suspended);
+
+        // Resumes right after the failed `catching`
+        assert_eq!(coro.resume(5).unwrap(), 10);
+        assert_eq!(coro.resume(3).unwrap(), 6);
+    }
+
+    #[test]
+    fn stack_size_auto() {
+        let mut coro = Coroutine::spawn_opts(|_, data| data + 1, Options::stack_size_auto());
+        assert_eq!(coro.resume(1).unwrap(), 2);
+    }
+
+    #[test]
+    fn stack_too_small() {
+        let opts = Options {
+            stack_size: MIN_STACK_SIZE / 2,
+            ..Options::default()
+        };
+        match Coroutine::try_spawn_opts(|_, _| 0, opts) {
+            Err(SpawnError::StackTooSmall { size, min }) => {
+                assert_eq!(size, MIN_STACK_SIZE / 2);
+                assert!(min >= MIN_STACK_SIZE && min % options::page_size() == 0);
+            }
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+
+        // Odd sizes are rounded up
+        let opts = Options {
+            stack_size: 3 * MIN_STACK_SIZE + 1,
+            ..Options::default()
+        };
+        let mut coro = Coroutine::try_spawn_opts(|_, data| data, opts).unwrap();
+        assert_eq!(coro.resume(7).unwrap(), 7);
+    }
+
+    #[test]
+    fn size_hint() {
+        let mut coro = Coroutine::spawn(|coro, _| {
+            coro.hint_len(3);
+            for i in 0..3 {
+                coro.yield_with(i);
+            }
+            3
+        });
+        assert_eq!(coro.size_hint(), (1, None));
+
+        coro.next();
+        assert_eq!(coro.size_hint(), (3, None));
+
+        let rest = coro.map(|x| x.unwrap()).collect::<Vec<_>>();
+        assert!(rest.capacity() >= 3);
+        assert_eq!(rest, [1, 2, 3]);
+    }
+
+    #[test]
+    fn resume_n() {
+        let mut coro = Coroutine::spawn(|coro, mut data| {
+            for _ in 0..4 {
+                data = coro.yield_with(data * 10);
+            }
+            data
+        });
+
+        let results = coro.resume_n(3, vec![1, 2]);
+        let values = results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(values, [10, 20, 0]);
+
+        // Stops once finished
+        let mut out = Vec::new();
+        coro.resume_n_into(10, 5.., &mut out);
+        assert_eq!(out.len(), 2);
+        assert!(coro.is_finished());
+    }
+
+    #[test]
+    fn ontop() {
+        use std::rc::Rc;
+
+        fn here() -> usize {
+            let marker = 0u8;
+            &marker as *const u8 as usize
+        }
+        fn near(a: usize, b: usize) -> bool {
+            a.abs_diff(b) < 64 * 1024
+        }
+
+        let coro_sp = Rc::new(Cell::new(0));
+        let sp = coro_sp.clone();
+        let mut coro = Coroutine::spawn(move |coro, data| {
+            sp.set(here());
+            // Finishes with what `f` of the second resume has found
+            coro.yield_ontop(data + 1, |data| {
+                assert!(!near(here(), sp.get()));
+                data * 10
+            })
+        });
+
+        // The closure gets the result of `f`, as does the resumer in the other direction
+        assert_eq!(coro.resume_ontop(1, |data| data + 1).unwrap(), 30);
+        assert!(coro_sp.get() != 0);
+        assert_eq!(coro.resume_ontop(0, |_| near(here(), coro_sp.get()) as usize).unwrap(),
+                   1);
+        assert!(coro.is_finished());
+    }
+
+    #[test]
+    fn sendable() {
+        use std::thread;
+
+        let mut coro = Coroutine::spawn_send(|coro, data| coro.yield_with(data + 1));
+        assert_eq!(coro.resume(1).unwrap(), 2);
+
+        let coro = thread::spawn(move || {
+            assert_eq!(coro.resume(5).unwrap(), 5);
+            coro
+        })
+        .join()
+        .unwrap();
+        assert!(coro.into_local().is_finished());
+    }
+
+    #[test]
+    fn weak_handle_across_threads() {
+        use std::thread;
+
+        let coro = Coroutine::spawn_send(|coro, mut data| {
+            for _ in 0..1000 {
+                data = coro.yield_with(data);
+            }
+            data
+        });
+        let weak = coro.downgrade();
+
+        // Watched through the weak handle while another thread switches it back and forth
+        let runner = thread::spawn(move || {
+            let mut coro = coro;
+            while !coro.is_finished() {
+                coro.resume(0).unwrap();
+            }
+            coro
+        });
+        while !runner.is_finished() {
+            assert!(weak.state().is_some());
+        }
+        let coro = runner.join().unwrap();
+        assert_eq!(weak.state(), Some(State::Finished));
+        drop(coro);
+        assert_eq!(weak.state(), None);
+    }
+
+    #[test]
+    fn weak_handle() {
+        let mut coro = Coroutine::spawn(|coro, _| coro.yield_with(0));
+        let weak = coro.downgrade();
+        assert!(weak.ptr_eq(&coro));
+        assert!(weak.is_alive());
+        assert_eq!(weak.id(), Some(coro.id()));
+
+        coro.resume(0).unwrap();
+        assert_eq!(weak.state(), Some(State::Suspended));
+        coro.resume(0).unwrap();
+        assert!(!weak.is_alive());
+        assert_eq!(weak.state(), Some(State::Finished));
+
+        drop(coro);
+        assert_eq!(weak.state(), None);
+
+        // The slot is reused by a different coroutine
+        let other = Coroutine::spawn(|_, _| 0);
+        assert!(!weak.ptr_eq(&other));
+        assert_eq!(weak.id(), None);
+    }
+
+    #[test]
+    fn discard_cold_stack() {
+        #[inline(never)]
+        fn deep(depth: usize) -> usize {
+            let buf = [depth as u8; 1024];
+            if depth == 0 {
+                0
+            } else {
+                deep(depth - 1) + ::std::hint::black_box(&buf)[0] as usize
+            }
+        }
+
+        let mut coro = Coroutine::spawn(|coro, _| {
+            let mut total = 0;
+            loop {
+                total += deep(64);
+                let marker = [7u8; 64];
+                coro.yield_with(total);
+                assert_eq!(::std::hint::black_box(marker), [7u8; 64]);
+            }
+        });
+        assert_eq!(coro.discard_cold_stack(), 0);
+
+        let first = coro.resume(0).unwrap();
+        assert!(coro.discard_cold_stack() > 0);
+
+        // Still works with the released pages faulted back in
+        assert_eq!(coro.resume(0).unwrap(), first * 2);
+        assert!(coro.discard_cold_stack() > 0);
+        assert_eq!(coro.resume(0).unwrap(), first * 3);
+    }
+
+    #[test]
+    fn with_deadline() {
+        let dropped = Rc::new(Cell::new(false));
+        let guard_dropped = dropped.clone();
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let outer = coro.with_deadline(Duration::from_secs(3600), |coro| {
+                let inner = coro.with_deadline(Duration::from_millis(5), |coro| {
+                    coro.park_with(1);
+                    unreachable!();
+                });
+                assert_eq!(inner, Err(::Elapsed));
+                // The inner section has left, only the outer deadline is left
+                assert!(coro.deadline().unwrap() > Instant::now() + Duration::from_secs(60));
+                coro.yield_with(2)
+            });
+            assert_eq!(outer, Ok(0));
+
+            let outer = coro.with_deadline(Duration::from_millis(5), |coro| {
+                coro.with_deadline(Duration::from_secs(3600), |coro| {
+                    let _guard = Guard(guard_dropped);
+                    coro.yield_with(3);
+                })
+            });
+            assert_eq!(outer, Err(::Elapsed));
+            4
+        });
+
+        assert_eq!(coro.resume(0).unwrap(), 1);
+        assert!(coro.deadline().unwrap() <= Instant::now() + Duration::from_millis(5));
+        ::std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(coro.resume(0).unwrap(), 2);
+        assert_eq!(coro.resume(0).unwrap(), 3);
+        ::std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(coro.resume(0).unwrap(), 4);
+        assert!(dropped.get());
+        assert_eq!(coro.deadline(), None);
+
+        struct Guard(Rc<Cell<bool>>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+    }
+
+    #[test]
+    fn raw_handle() {
+        let coro = Coroutine::spawn(|coro, _| coro.yield_with(1));
+        let id = coro.id();
+
+        let raw = coro.into_raw();
+        let copy = raw;
+        let mut coro = Handle::from_raw(raw).unwrap();
+        assert_eq!(coro.id(), id);
+        assert_eq!(coro.resume(0).unwrap(), 1);
+
+        // Reclaimed already
+        assert!(Handle::from_raw(copy).is_none());
+
+        // And dropped, even when the slot is reused
+        let raw = coro.into_raw();
+        drop(Handle::from_raw(raw));
+        let _other = Coroutine::spawn(|_, _| 0);
+        assert!(Handle::from_raw(raw).is_none());
+    }
+
+    #[test]
+    fn shared_stack() {
+        #[inline(never)]
+        fn deep(coro: &mut Coroutine, depth: usize, tag: usize) -> usize {
+            let buf = [(depth + tag) as u8; 512];
+            if depth == 0 {
+                coro.yield_with(tag)
+            } else {
+                let sum = deep(coro, depth - 1, tag) + 1;
+                assert_eq!(::std::hint::black_box(&buf)[0], (depth + tag) as u8);
+                sum
+            }
+        }
+
+        let opts = || {
+            Options {
+                shared_stack: true,
+                ..Options::default()
+            }
+        };
+        let mut coros = (0..4)
+            .map(|tag| {
+                Coroutine::spawn_opts(move |coro, _| {
+                    let local = [tag; 16];
+                    let depth = deep(coro, tag * 10, tag);
+                    coro.yield_with(local.iter().sum::<usize>());
+                    depth
+                },
+                                      opts())
+            })
+            .collect::<Vec<_>>();
+
+        // Interleave them so every switch swaps the frames on the shared stack
+        for (tag, coro) in coros.iter_mut().enumerate().rev() {
+            assert_eq!(coro.resume(0).unwrap(), tag);
+            assert_eq!(coro.discard_cold_stack(), 0);
+        }
+        for (tag, coro) in coros.iter_mut().enumerate() {
+            assert_eq!(coro.resume(0).unwrap(), tag * 16);
+        }
+        for (tag, coro) in coros.iter_mut().enumerate().skip(2) {
+            assert_eq!(coro.resume(0).unwrap(), tag * 10);
+            assert!(coro.is_finished());
+        }
+
+        // Plus an unstarted one, all dropped while their frames are saved away
+        coros.push(Coroutine::spawn_opts(|_, _| 0, opts()));
+        let dropped = Rc::new(Cell::new(0));
+        let guard = Guard(dropped.clone());
+        coros.push(Coroutine::spawn_opts(move |coro, _| {
+            let _guard = guard;
+            coro.yield_with(0)
+        },
+                                         opts()));
+        coros.last_mut().unwrap().resume(0).unwrap();
+        coros[0].resume(0).unwrap();
+        drop(coros);
+        assert_eq!(dropped.get(), 1);
+
+        struct Guard(Rc<Cell<usize>>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_stack_nested() {
+        let opts = || {
+            Options {
+                shared_stack: true,
+                ..Options::default()
+            }
+        };
+        let inner = Rc::new(RefCell::new(Coroutine::spawn_opts(|_, _| 1, opts())));
+
+        // A coroutine on the shared stack cannot resume another one
+        let nested = inner.clone();
+        let mut outer = Coroutine::spawn_opts(move |_, _| nested.borrow_mut().resume(0).unwrap(),
+                                              opts());
+        match outer.resume(0) {
+            Err(::Error::Panicking(_)) => {}
+            _ => panic!("resuming from the shared stack must panic"),
+        }
+
+        // But anything off the shared stack can
+        assert_eq!(inner.borrow_mut().resume(0).unwrap(), 1);
+    }
+
     #[test]
     fn panicking() {
         let mut coro = Coroutine::spawn(|_, _| {
-            panic!(1010);
+            ::std::panic::panic_any(1010);
         });
 
         let result = coro.resume(0);
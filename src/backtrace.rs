@@ -0,0 +1,152 @@
+//! Backtraces of suspended coroutines
+//!
+//! Compiled in with the `frame-pointers` feature on x86-64. A suspended coroutine has no
+//! thread to ask for its stack, but it has recorded its frame pointer right before switching
+//! away, and from there `Handle::backtrace` follows the chain of frame pointers up to the top
+//! of the stack. This only works for code which keeps its frame pointers, so build with
+//! `RUSTFLAGS="-C force-frame-pointers=yes"`; otherwise the walk stops early, usually after the
+//! first frame.
+//!
+//! ```rust
+//! use coroutine::asymmetric::Coroutine;
+//!
+//! let mut coro = Coroutine::spawn(|coro, _| coro.yield_with(0));
+//! coro.resume(0).unwrap();
+//! let backtrace = coro.backtrace().unwrap();
+//! assert!(!backtrace.frames().is_empty());
+//! println!("{}", backtrace);
+//! ```
+//!
+//! Symbols are resolved with `dladdr`, which only knows the dynamic symbol table. Functions of
+//! an executable show up with their address and object unless it is linked with
+//! `-C link-args=-rdynamic`.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::mem;
+
+use libc;
+
+// Gives up on chains longer than this, they are garbage
+const MAX_FRAMES: usize = 256;
+
+/// One return address on the stack of a coroutine
+#[derive(Debug, Clone)]
+pub struct Frame {
+    ip: usize,
+    symbol: Option<String>,
+    object: Option<String>,
+}
+
+impl Frame {
+    /// The return address
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Name of the dynamic symbol containing `ip`, mangled
+    pub fn symbol(&self) -> Option<&str> {
+        self.symbol.as_ref().map(|s| &s[..])
+    }
+
+    /// Path of the executable or library containing `ip`
+    pub fn object(&self) -> Option<&str> {
+        self.object.as_ref().map(|s| &s[..])
+    }
+
+    fn resolve(ip: usize) -> Frame {
+        let mut frame = Frame {
+            ip,
+            symbol: None,
+            object: None,
+        };
+        unsafe {
+            let mut info: libc::Dl_info = mem::zeroed();
+            // Look up the call rather than the instruction following it
+            if libc::dladdr((ip - 1) as *const libc::c_void, &mut info) != 0 {
+                if !info.dli_sname.is_null() {
+                    frame.symbol =
+                        Some(CStr::from_ptr(info.dli_sname).to_string_lossy().into_owned());
+                }
+                if !info.dli_fname.is_null() {
+                    frame.object =
+                        Some(CStr::from_ptr(info.dli_fname).to_string_lossy().into_owned());
+                }
+            }
+        }
+        frame
+    }
+}
+
+/// Frames of a suspended coroutine, innermost first
+#[derive(Debug, Clone)]
+pub struct Backtrace {
+    frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    /// The frames, the first one is where the coroutine has switched away
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "{:>4}: {:#018x}", i, frame.ip)?;
+            match (frame.symbol(), frame.object()) {
+                (Some(symbol), _) => writeln!(f, " - {}", symbol)?,
+                (None, Some(object)) => writeln!(f, " in {}", object)?,
+                (None, None) => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Walk the frames of a coroutine which has switched away at `ip`, from `fp` up
+///
+/// The coroutine is suspended at `sp` on a stack ending at `top`. Every address read lies within
+/// `[sp, top)`, whatever the stack holds.
+pub(crate) unsafe fn walk(ip: usize, fp: usize, sp: usize, top: usize) -> Backtrace {
+    let mut frames = vec![Frame::resolve(ip)];
+    let mut fp = fp;
+    let mut lowest = sp;
+    while frames.len() < MAX_FRAMES && fp >= lowest && fp.is_multiple_of(8) && fp + 16 <= top {
+        let ip = *((fp + 8) as *const usize);
+        if ip == 0 {
+            break;
+        }
+        frames.push(Frame::resolve(ip));
+        lowest = fp + 16;
+        fp = *(fp as *const usize);
+    }
+    Backtrace { frames }
+}
+
+#[cfg(test)]
+mod test {
+    use asymmetric::Coroutine;
+
+    #[inline(never)]
+    fn park(coro: &mut Coroutine) -> usize {
+        coro.yield_with(1)
+    }
+
+    #[test]
+    fn suspended_only() {
+        let mut coro = Coroutine::spawn(|coro, _| park(coro));
+        assert!(coro.backtrace().is_none());
+
+        coro.resume(0).unwrap();
+        let backtrace = coro.backtrace().unwrap();
+        assert!(!backtrace.frames().is_empty());
+        assert!(backtrace.frames().iter().all(|frame| frame.ip() != 0));
+        assert!(backtrace.to_string().lines().count() == backtrace.frames().len());
+
+        coro.resume(0).unwrap();
+        assert!(coro.is_finished());
+        assert!(coro.backtrace().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+//! Process-wide pool of threads for blocking work
+//!
+//! Code which blocks its thread, legacy iterators, synchronous file or database calls, must
+//! not run on the threads driving coroutines. `spawn` queues it for one of at most
+//! `max_threads` threads, 32 by default, which are started on demand and exit after being idle
+//! for 10 seconds. Once all of them are busy further jobs wait in the queue.
+//!
+//! ```rust
+//! use std::sync::mpsc;
+//! use coroutine::blocking;
+//!
+//! let (tx, rx) = mpsc::channel();
+//! blocking::spawn(move || tx.send(6 * 7).unwrap());
+//! assert_eq!(rx.recv().unwrap(), 42);
+//! ```
+//!
+//! The pool is bounded, so a job which never returns keeps its thread for good and a queue
+//! of such jobs never drains. A panic inside of a job is reported by the panic hook and the
+//! thread goes on with the next job.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Pool {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    max: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    jobs: VecDeque::new(),
+    threads: 0,
+    idle: 0,
+    max: 32,
+});
+static QUEUED: Condvar = Condvar::new();
+
+fn pool() -> MutexGuard<'static, Pool> {
+    POOL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run `f` on a thread of the pool
+pub fn spawn<F>(f: F)
+    where F: FnOnce() + Send + 'static
+{
+    let mut pool = pool();
+    pool.jobs.push_back(Box::new(f));
+    if pool.jobs.len() <= pool.idle {
+        QUEUED.notify_one();
+        return;
+    }
+    if pool.threads < pool.max {
+        pool.threads += 1;
+        let spawned = thread::Builder::new()
+            .name("coroutine-blocking".to_owned())
+            .spawn(work);
+        if let Err(err) = spawned {
+            pool.threads -= 1;
+            // Someone still picks the job up
+            if pool.threads == 0 {
+                panic!("failed to spawn a blocking thread: {}", err);
+            }
+        }
+    }
+}
+
+/// Start at most `max` threads, threads beyond it exit once done with their job
+pub fn set_max_threads(max: usize) {
+    assert!(max > 0, "the blocking pool needs a thread");
+    pool().max = max;
+    QUEUED.notify_all();
+}
+
+/// The most threads the pool starts
+pub fn max_threads() -> usize {
+    pool().max
+}
+
+/// Threads currently in the pool, busy or idle
+pub fn threads() -> usize {
+    pool().threads
+}
+
+fn work() {
+    let mut pool = pool();
+    loop {
+        if let Some(job) = pool.jobs.pop_front() {
+            drop(pool);
+            let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            pool = self::pool();
+            continue;
+        }
+        if pool.threads > pool.max {
+            break;
+        }
+
+        pool.idle += 1;
+        let (guard, wait) = QUEUED.wait_timeout(pool, KEEP_ALIVE)
+            .unwrap_or_else(|e| e.into_inner());
+        pool = guard;
+        pool.idle -= 1;
+        if wait.timed_out() && pool.jobs.is_empty() {
+            break;
+        }
+    }
+    pool.threads -= 1;
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn runs_every_job() {
+        let (tx, rx) = mpsc::channel();
+        for i in 0..max_threads() * 2 {
+            let tx = tx.clone();
+            spawn(move || {
+                if i == 0 {
+                    panic!("blocking job");
+                }
+                tx.send(i).unwrap();
+            });
+        }
+        drop(tx);
+
+        let mut done = rx.iter().collect::<Vec<_>>();
+        done.sort();
+        assert_eq!(done, (1..max_threads() * 2).collect::<Vec<_>>());
+        assert!(threads() <= max_threads());
+    }
+}
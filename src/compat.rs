@@ -0,0 +1,221 @@
+//! The 0.5 style API, where coroutines are plain closures yielding with `sched()`
+//!
+//! Thin adapters over `asymmetric`: the coroutine to yield is found through the current
+//! coroutine of the thread instead of an explicit `&mut Coroutine` parameter, and no data is
+//! passed on switches.
+//!
+//! ```rust
+//! use coroutine::compat::{spawn, sched};
+//!
+//! let coro = spawn(|| {
+//!     println!("Before yield");
+//!
+//!     // Yield back to its parent who resume this coroutine
+//!     sched();
+//!
+//!     println!("I am back!");
+//! });
+//!
+//! // Starts the Coroutine
+//! coro.resume().ok().expect("Failed to resume");
+//!
+//! println!("Back to main");
+//!
+//! // Resume it
+//! coro.resume().ok().expect("Failed to resume");
+//!
+//! println!("Coroutine finished");
+//! ```
+//!
+//! `Handle` is the unique handle of the old API, `Handle::into_clonable` turns it into the
+//! reference counted `ClonableHandle`. Typed coroutines live in `generator`.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
+use std::rc::Rc;
+
+use asymmetric::{self, Coroutine, State};
+use options::Options;
+
+/// Handle of a coroutine spawned with `spawn`
+pub struct Handle(RefCell<asymmetric::Handle>);
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0.try_borrow() {
+            Ok(handle) => handle.fmt(f),
+            Err(..) => f.write_str("Handle(<running>)"),
+        }
+    }
+}
+
+impl Handle {
+    /// Resume the coroutine until it calls `sched` or `block`, or finishes
+    ///
+    /// Returns the state it has then. Resuming a coroutine which is finished or which is
+    /// running further up the stack does nothing.
+    pub fn resume(&self) -> ::Result<State> {
+        let mut handle = match self.0.try_borrow_mut() {
+            Ok(handle) => handle,
+            Err(..) => return Ok(State::Running),
+        };
+        if handle.is_finished() {
+            return Ok(handle.state());
+        }
+
+        handle.resume(0)?;
+        Ok(handle.state())
+    }
+
+    /// Resume the coroutine until it exits
+    pub fn join(&self) -> ::Result<State> {
+        loop {
+            match self.resume()? {
+                State::Suspended | State::Parked => {}
+                state => return Ok(state),
+            }
+        }
+    }
+
+    /// Get the state of the coroutine, `Running` while it is resumed
+    pub fn state(&self) -> State {
+        self.0.try_borrow().map_or(State::Running, |handle| handle.state())
+    }
+
+    /// Get the name of the coroutine
+    pub fn name(&self) -> Option<String> {
+        self.0.try_borrow().ok().and_then(|handle| handle.name().cloned())
+    }
+
+    /// The underlying handle
+    pub fn into_inner(self) -> asymmetric::Handle {
+        self.0.into_inner()
+    }
+
+    /// Share the handle, the coroutine is dropped with the last clone
+    pub fn into_clonable(self) -> ClonableHandle {
+        ClonableHandle(Rc::new(self))
+    }
+}
+
+/// Reference counted `Handle`, any clone can resume the coroutine
+#[derive(Debug, Clone)]
+pub struct ClonableHandle(Rc<Handle>);
+
+impl ClonableHandle {
+    /// Check if both refer to the same coroutine
+    pub fn ptr_eq(&self, other: &ClonableHandle) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Deref for ClonableHandle {
+    type Target = Handle;
+
+    fn deref(&self) -> &Handle {
+        &self.0
+    }
+}
+
+/// Spawn a coroutine with options
+#[track_caller]
+pub fn spawn_opts<F>(f: F, opts: Options) -> Handle
+    where F: FnOnce() + 'static
+{
+    let handle = Coroutine::spawn_opts(move |_, _| {
+                                           f();
+                                           0
+                                       },
+                                       opts);
+    Handle(RefCell::new(handle))
+}
+
+/// Spawn a coroutine with default options
+#[track_caller]
+pub fn spawn<F>(f: F) -> Handle
+    where F: FnOnce() + 'static
+{
+    spawn_opts(f, Options::default())
+}
+
+/// Yield the current coroutine with `Suspended` state, does nothing outside of coroutines
+pub fn sched() {
+    asymmetric::with_current(|current| {
+        if let Some(coro) = current {
+            coro.yield_with(0);
+        }
+    })
+}
+
+/// Yield the current coroutine with `Parked` state, does nothing outside of coroutines
+pub fn block() {
+    asymmetric::with_current(|current| {
+        if let Some(coro) = current {
+            coro.park_with(0);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn nested() {
+        let steps = Rc::new(Cell::new(0));
+
+        let counter = steps.clone();
+        let outer = spawn(move || {
+            let inner_counter = counter.clone();
+            let inner = spawn(move || {
+                inner_counter.set(inner_counter.get() + 1);
+                block();
+                inner_counter.set(inner_counter.get() + 1);
+            });
+
+            assert_eq!(inner.resume().unwrap(), State::Parked);
+            // Yields the outer coroutine, not the inner one
+            sched();
+            assert_eq!(inner.join().unwrap(), State::Finished);
+            counter.set(counter.get() + 10);
+        });
+
+        assert_eq!(outer.resume().unwrap(), State::Suspended);
+        assert_eq!(steps.get(), 1);
+        assert_eq!(outer.join().unwrap(), State::Finished);
+        assert_eq!(steps.get(), 12);
+        assert_eq!(outer.resume().unwrap(), State::Finished);
+
+        // Outside of coroutines these are no-ops
+        sched();
+        block();
+    }
+
+    #[test]
+    fn clonable() {
+        let coro = spawn(|| {
+                sched();
+                sched();
+            })
+            .into_clonable();
+        let other = coro.clone();
+        assert!(coro.ptr_eq(&other));
+
+        assert_eq!(coro.resume().unwrap(), State::Suspended);
+        assert_eq!(other.resume().unwrap(), State::Suspended);
+        drop(coro);
+        assert_eq!(other.join().unwrap(), State::Finished);
+    }
+
+    #[test]
+    fn panicking() {
+        let coro = spawn(|| panic!("compat"));
+        assert!(coro.resume().is_err());
+        assert_eq!(coro.state(), State::Panicked);
+        assert_eq!(coro.join().unwrap(), State::Panicked);
+    }
+}
@@ -0,0 +1,139 @@
+//! The context switch with the ABI of boost.context's `fcontext_t`
+//!
+//! Compiled in with the `fcontext` feature. The switch underneath every coroutine is the
+//! assembly of boost.context (1.61 and later), which passes a `transfer_t` of the previous
+//! context and a data pointer on every jump. The functions here take and return that struct
+//! as `RawTransfer`, so a context made on this side can be handed to C++ code which jumps to
+//! it with `boost::context::detail::jump_fcontext`, and the other way round.
+//!
+//! ```rust
+//! use std::os::raw::c_void;
+//! use coroutine::fcontext::{self, RawTransfer, jump_fcontext};
+//!
+//! extern "C" fn add_one(mut t: RawTransfer) -> ! {
+//!     loop {
+//!         t = unsafe { jump_fcontext(t.fctx, (t.data as usize + 1) as *mut c_void) };
+//!     }
+//! }
+//!
+//! let mut stack = vec![0u8; 64 * 1024];
+//! unsafe {
+//!     let top = stack.as_mut_ptr().add(stack.len()) as *mut c_void;
+//!     let fctx = fcontext::make_fcontext(top, stack.len(), add_one);
+//!     let t = jump_fcontext(fctx, 41 as *mut c_void);
+//!     assert_eq!(t.data as usize, 42);
+//! }
+//! ```
+//!
+//! The symbols are the ones of boost.context, `jump_fcontext`, `make_fcontext` and
+//! `ontop_fcontext`. A process linking both libraries statically gets them twice; link
+//! boost.context dynamically or let the C++ side use the ones of this crate.
+
+use std::mem;
+use std::os::raw::c_void;
+
+use context::{Context, ContextFn, ResumeOntopFn, Transfer};
+use context::stack::Stack;
+
+/// A suspended context, boost.context's `fcontext_t`
+pub type FContext = *mut c_void;
+
+/// What a jump passes to the context it resumes, boost.context's `transfer_t`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct RawTransfer {
+    /// The context which has jumped, to jump back to
+    pub fctx: FContext,
+    /// The data passed to the jump
+    pub data: *mut c_void,
+}
+
+/// Entry point of a context, it must never return
+pub type RawContextFn = extern "C" fn(RawTransfer) -> !;
+
+/// Function run on top of the resumed context by `ontop_fcontext`
+pub type RawOntopFn = extern "C" fn(RawTransfer) -> RawTransfer;
+
+// `Context` is a non-null pointer and `Transfer` the same struct as `RawTransfer`
+fn into_raw(t: Transfer) -> RawTransfer {
+    unsafe { mem::transmute::<Transfer, RawTransfer>(t) }
+}
+
+unsafe fn context(fctx: FContext) -> Context {
+    assert!(!fctx.is_null(), "jump to a null fcontext");
+    mem::transmute::<FContext, Context>(fctx)
+}
+
+/// Make a context which runs `f` on the stack of `size` bytes below `sp` once jumped to
+///
+/// # Safety
+///
+/// `sp` must be the top of a writable stack of `size` bytes which outlives the context.
+pub unsafe fn make_fcontext(sp: *mut c_void, size: usize, f: RawContextFn) -> FContext {
+    let stack = Stack::new(sp, (sp as usize - size) as *mut c_void);
+    let f = mem::transmute::<RawContextFn, ContextFn>(f);
+    mem::transmute::<Context, FContext>(Context::new(&stack, f))
+}
+
+/// Suspend the current context and resume `to`, passing it `data`
+///
+/// Returns once some context jumps back, with that context and the data it passed.
+///
+/// # Safety
+///
+/// `to` must be a suspended context which has not been resumed since it was returned by
+/// `make_fcontext` or a jump. Unwinding out of the entry point of a context is undefined.
+pub unsafe fn jump_fcontext(to: FContext, data: *mut c_void) -> RawTransfer {
+    into_raw(context(to).resume(data as usize))
+}
+
+/// Like `jump_fcontext`, but `f` runs on the stack of `to` first and may replace what `to`
+/// gets
+///
+/// # Safety
+///
+/// As for `jump_fcontext`.
+pub unsafe fn ontop_fcontext(to: FContext, data: *mut c_void, f: RawOntopFn) -> RawTransfer {
+    let f = mem::transmute::<RawOntopFn, ResumeOntopFn>(f);
+    into_raw(context(to).resume_ontop(data as usize, f))
+}
+
+#[cfg(test)]
+mod test {
+    use context::stack::ProtectedFixedSizeStack;
+
+    use super::*;
+
+    fn data(n: usize) -> *mut c_void {
+        n as *mut c_void
+    }
+
+    extern "C" fn echo(mut t: RawTransfer) -> ! {
+        loop {
+            t = unsafe { jump_fcontext(t.fctx, data(t.data as usize + 1)) };
+        }
+    }
+
+    extern "C" fn double(t: RawTransfer) -> RawTransfer {
+        RawTransfer {
+            fctx: t.fctx,
+            data: data(t.data as usize * 2),
+        }
+    }
+
+    #[test]
+    fn jump_and_ontop() {
+        assert_eq!(mem::size_of::<RawTransfer>(), mem::size_of::<Transfer>());
+
+        let stack = ProtectedFixedSizeStack::new(64 * 1024).unwrap();
+        unsafe {
+            let fctx = make_fcontext(stack.top(), stack.len(), echo);
+            let t = jump_fcontext(fctx, data(1));
+            assert_eq!(t.data as usize, 2);
+
+            // Doubled on the way in, incremented on the way back
+            let t = ontop_fcontext(t.fctx, data(10), double);
+            assert_eq!(t.data as usize, 21);
+        }
+    }
+}
@@ -0,0 +1,686 @@
+//! Waiting on file descriptors without a scheduler
+//!
+//! A coroutine calls `poll_fds` to park until some file descriptors are ready. The request
+//! is handed to whoever resumes the coroutine as an `FdWait`, which a custom event loop can
+//! feed into its own readiness mechanism and answer with `set_ready`, or simply `poll` on the
+//! spot. `run` is the trivial driver doing the latter. `wait_readable` and `wait_writable`
+//! are the shorthands for a single file descriptor, e.g. an inotify or timerfd descriptor.
+//!
+//! ```rust
+//! use std::os::unix::io::AsRawFd;
+//! use std::os::unix::net::UnixStream;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::fd::{self, Fd, Interest};
+//!
+//! let (a, b) = UnixStream::pair().unwrap();
+//! let raw = a.as_raw_fd();
+//!
+//! let mut coro = Coroutine::spawn(move |coro, _| {
+//!     let ready = fd::poll_fds(coro, &[Fd::new(raw, Interest::READABLE)], None).unwrap();
+//!     ready[0].is_readable() as usize
+//! });
+//!
+//! // Parked waiting for `a` to become readable
+//! coro.resume(0).unwrap();
+//! assert_eq!(fd::pending(&mut coro).unwrap().fds()[0].fd, raw);
+//!
+//! use std::io::Write;
+//! (&b).write_all(b"x").unwrap();
+//! assert_eq!(fd::run(&mut coro).unwrap(), 1);
+//! ```
+
+use std::cell::RefCell;
+use std::io;
+use std::ops::BitOr;
+use std::panic;
+use std::rc::Rc;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use asymmetric::{self, Coroutine, Handle};
+
+/// Readiness a coroutine is interested in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest(libc::c_short);
+
+impl Interest {
+    /// Wait until reading would not block
+    pub const READABLE: Interest = Interest(libc::POLLIN);
+
+    /// Wait until writing would not block
+    pub const WRITABLE: Interest = Interest(libc::POLLOUT);
+
+    /// The `poll(2)` events for this interest
+    pub fn events(self) -> libc::c_short {
+        self.0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+/// How the waiting coroutine consumes the readiness it is woken up for
+///
+/// `poll(2)` is level triggered, so `FdWait::poll` treats both alike. Event loops built on
+/// edge triggered notification, e.g. `EPOLLET`, can register `Edge` waits as such.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    /// The coroutine may leave data behind and wait again, it must be woken up while the file
+    /// descriptor is still ready
+    Level,
+    /// The coroutine reads or writes until the operation would block before it waits again,
+    /// it only needs to be woken up on a new readiness event
+    Edge,
+}
+
+/// A file descriptor to wait on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fd {
+    /// The file descriptor
+    pub fd: RawFd,
+    /// What to wait for
+    pub interest: Interest,
+}
+
+impl Fd {
+    /// Wait on `fd` for `interest`
+    pub fn new(fd: RawFd, interest: Interest) -> Fd {
+        Fd { fd, interest }
+    }
+}
+
+/// Readiness of a file descriptor, as reported in the `revents` of `poll(2)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Ready(libc::c_short);
+
+impl Ready {
+    /// Build from `poll(2)` revents
+    pub fn from_revents(revents: libc::c_short) -> Ready {
+        Ready(revents)
+    }
+
+    /// The raw `poll(2)` revents
+    pub fn revents(self) -> libc::c_short {
+        self.0
+    }
+
+    /// Nothing happened, the wait has timed out
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Reading will not block
+    pub fn is_readable(self) -> bool {
+        self.0 & libc::POLLIN != 0
+    }
+
+    /// Writing will not block
+    pub fn is_writable(self) -> bool {
+        self.0 & libc::POLLOUT != 0
+    }
+
+    /// The peer has hung up
+    pub fn is_hangup(self) -> bool {
+        self.0 & libc::POLLHUP != 0
+    }
+
+    /// An error condition, or the file descriptor is not open
+    pub fn is_error(self) -> bool {
+        self.0 & (libc::POLLERR | libc::POLLNVAL) != 0
+    }
+}
+
+/// Request of a coroutine parked in `poll_fds`
+#[derive(Debug)]
+pub struct FdWait {
+    fds: Vec<Fd>,
+    timeout: Option<Duration>,
+    trigger: Trigger,
+    ready: Vec<Ready>,
+    error: Option<io::Error>,
+    parked: Instant,
+}
+
+impl FdWait {
+    /// The file descriptors the coroutine waits on
+    pub fn fds(&self) -> &[Fd] {
+        &self.fds
+    }
+
+    /// How long the coroutine is willing to wait, `None` for no limit
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// How the coroutine consumes the readiness, `Level` for `poll_fds`
+    pub fn trigger(&self) -> Trigger {
+        self.trigger
+    }
+
+    /// Report the readiness of `fds()[index]`, unreported ones stay empty
+    pub fn set_ready(&mut self, index: usize, ready: Ready) {
+        self.ready[index] = ready;
+    }
+
+    /// Make `poll_fds` return `err`
+    pub fn fail(&mut self, err: io::Error) {
+        self.error = Some(err);
+    }
+
+    // What is left of the timeout since parking
+    fn left(&self) -> Option<Duration> {
+        self.timeout.map(|timeout| timeout.saturating_sub(self.parked.elapsed()))
+    }
+
+    /// Block the current thread in `poll(2)` and record the result
+    pub fn poll(&mut self) {
+        let mut pollfds = self.fds
+            .iter()
+            .map(|fd| {
+                libc::pollfd {
+                    fd: fd.fd,
+                    events: fd.interest.events(),
+                    revents: 0,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let timeout = match self.timeout {
+            // Round up, waking up early would only make the coroutine wait again
+            Some(timeout) => {
+                let ms = timeout.as_nanos().div_ceil(1_000_000);
+                ms.min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+
+        loop {
+            let ret = unsafe {
+                libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout)
+            };
+            if ret >= 0 {
+                break;
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                self.fail(err);
+                return;
+            }
+        }
+
+        for (ready, pollfd) in self.ready.iter_mut().zip(&pollfds) {
+            *ready = Ready(pollfd.revents);
+        }
+    }
+}
+
+/// Park the current coroutine until one of `fds` is ready or `timeout` has passed
+///
+/// The timeout is cut short by the deadline of the coroutine, see `Coroutine::with_deadline`.
+/// The resumer is expected to answer the `FdWait` found through `pending` before resuming
+/// the coroutine again. Returns the readiness of every file descriptor in order, all of them
+/// empty on timeout.
+pub fn poll_fds(coro: &mut Coroutine, fds: &[Fd], timeout: Option<Duration>) -> io::Result<Vec<Ready>> {
+    park(coro, fds, timeout, Trigger::Level)
+}
+
+/// Park the current coroutine until `fd` is readable, or has hung up or failed
+///
+/// Unlike `poll_fds` this ignores wake-ups which report nothing, so it only returns early when
+/// the `with_deadline` section around it expires.
+pub fn wait_readable(coro: &mut Coroutine, fd: RawFd, trigger: Trigger) -> io::Result<Ready> {
+    wait(coro, Fd::new(fd, Interest::READABLE), trigger)
+}
+
+/// Park the current coroutine until `fd` is writable, or has hung up or failed
+///
+/// See `wait_readable`.
+pub fn wait_writable(coro: &mut Coroutine, fd: RawFd, trigger: Trigger) -> io::Result<Ready> {
+    wait(coro, Fd::new(fd, Interest::WRITABLE), trigger)
+}
+
+/// Park the current coroutine for `duration`
+///
+/// Waits in `poll_fds` without any file descriptor, so an event loop answering the wait
+/// sooner only costs another round.
+pub fn sleep(coro: &mut Coroutine, duration: Duration) -> io::Result<()> {
+    let until = Instant::now() + duration;
+    loop {
+        let left = until.saturating_duration_since(Instant::now());
+        if left == Duration::from_secs(0) {
+            return Ok(());
+        }
+        park(coro, &[], Some(left), Trigger::Level)?;
+    }
+}
+
+fn wait(coro: &mut Coroutine, fd: Fd, trigger: Trigger) -> io::Result<Ready> {
+    loop {
+        let ready = park(coro, &[fd], None, trigger)?[0];
+        if !ready.is_empty() {
+            return Ok(ready);
+        }
+    }
+}
+
+fn park(coro: &mut Coroutine,
+        fds: &[Fd],
+        timeout: Option<Duration>,
+        trigger: Trigger)
+        -> io::Result<Vec<Ready>> {
+    // Wake up in time to give up on an expired `with_deadline` section
+    let timeout = match coro.deadline() {
+        Some(deadline) => {
+            let left = deadline.saturating_duration_since(Instant::now());
+            Some(timeout.map_or(left, |t| t.min(left)))
+        }
+        None => timeout,
+    };
+
+    // Boxed, a coroutine on the shared stack has its frames copied away while it is parked
+    let mut wait = Box::new(FdWait {
+        fds: fds.to_vec(),
+        timeout,
+        trigger,
+        ready: vec![Ready::default(); fds.len()],
+        error: None,
+        parked: Instant::now(),
+    });
+
+    // Cleared on every way out, also when an expired deadline unwinds out of the park
+    struct Clear(*mut Coroutine);
+
+    impl Drop for Clear {
+        fn drop(&mut self) {
+            unsafe { (*self.0).set_fd_wait(0) }
+        }
+    }
+
+    coro.set_fd_wait(&mut *wait as *mut FdWait as usize);
+    let clear = Clear(coro as *mut Coroutine);
+    coro.park_with(0);
+    drop(clear);
+
+    let wait = *wait;
+    match wait.error {
+        Some(err) => Err(err),
+        None => Ok(wait.ready),
+    }
+}
+
+/// The request of a coroutine parked in `poll_fds`
+pub fn pending<K>(handle: &mut Handle<K>) -> Option<&mut FdWait> {
+    if handle.is_finished() {
+        return None;
+    }
+
+    match handle.fd_wait() {
+        0 => None,
+        // The coroutine is suspended inside of `poll_fds` which owns the request, and resuming
+        // it requires the handle which is borrowed for as long as the request
+        wait => Some(unsafe { &mut *(wait as *mut FdWait) }),
+    }
+}
+
+// Wait on behalf of the coroutine behind `handle` if it is parked in `poll_fds`: the current
+// coroutine parks on the same file descriptors, so that whatever drives it sees the wait,
+// outside of coroutines the thread blocks in `poll(2)`
+pub(crate) fn wait_for(handle: &mut Handle) -> bool {
+    match pending(handle) {
+        Some(wait) => {
+            block(wait);
+            true
+        }
+        None => false,
+    }
+}
+
+// Wait on behalf of the coroutines behind `handles` like `wait_for` does, until the first of
+// them is ready. Returns which of them to resume, those not parked are resumed right away and
+// the parked ones are only checked for readiness then, without blocking
+pub(crate) fn wait_any<'a, K: 'a, I>(handles: I) -> Vec<bool>
+    where I: IntoIterator<Item = &'a mut Handle<K>>
+{
+    let waits = handles.into_iter().map(pending).collect::<Vec<_>>();
+    let busy = waits.iter().any(|wait| wait.is_none());
+
+    let mut timeout = if busy { Some(Duration::from_secs(0)) } else { None };
+    let mut fds = Vec::new();
+    for wait in waits.iter().flatten() {
+        if let Some(left) = wait.left() {
+            timeout = Some(timeout.map_or(left, |t| t.min(left)));
+        }
+        fds.extend_from_slice(&wait.fds);
+    }
+
+    let mut all = FdWait {
+        ready: vec![Ready::default(); fds.len()],
+        fds,
+        timeout,
+        trigger: Trigger::Level,
+        error: None,
+        parked: Instant::now(),
+    };
+    if busy {
+        if all.fds.is_empty() {
+            return waits.iter().map(|wait| wait.is_none()).collect();
+        }
+        all.poll();
+    } else {
+        block(&mut all);
+    }
+
+    let (mut ready, error) = (all.ready.into_iter(), all.error);
+    waits.into_iter()
+        .map(|wait| {
+            let wait = match wait {
+                Some(wait) => wait,
+                None => return true,
+            };
+            for slot in &mut wait.ready {
+                *slot = ready.next().unwrap();
+            }
+            if let Some(ref err) = error {
+                wait.error = Some(match err.raw_os_error() {
+                    Some(code) => io::Error::from_raw_os_error(code),
+                    None => io::Error::new(err.kind(), err.to_string()),
+                });
+            }
+            wait.error.is_some() || wait.ready.iter().any(|ready| !ready.is_empty()) ||
+            wait.left() == Some(Duration::from_secs(0))
+        })
+        .collect()
+}
+
+// Answer `wait`, by parking the current coroutine on the same file descriptors or blocking
+// the thread in `poll(2)`
+fn block(wait: &mut FdWait) {
+    match asymmetric::with_current(|current| current.map(|coro| coro as *mut Coroutine)) {
+        Some(coro) => {
+            let fds = wait.fds.clone();
+            match park(unsafe { &mut *coro }, &fds, wait.timeout, wait.trigger) {
+                Ok(ready) => wait.ready = ready,
+                Err(err) => wait.error = Some(err),
+            }
+        }
+        None => wait.poll(),
+    }
+}
+
+/// Resume the coroutine, polling on its behalf for as long as it waits in `poll_fds`
+///
+/// Returns as soon as the coroutine yields for any other reason or finishes.
+pub fn run(handle: &mut Handle) -> ::Result<usize> {
+    loop {
+        if let Some(wait) = pending(handle) {
+            wait.poll();
+        }
+
+        let data = handle.resume(0)?;
+        if handle.is_finished() || pending(handle).is_none() {
+            return Ok(data);
+        }
+    }
+}
+
+/// Same as `run`, but fails with `Error::Timeout` once `timeout` has passed
+///
+/// The coroutine is left parked where it was and may be driven on. Code which never parks
+/// or yields is not interrupted.
+pub fn run_timeout(handle: &mut Handle, timeout: Duration) -> ::Result<usize> {
+    let until = Instant::now() + timeout;
+    loop {
+        if let Some(wait) = pending(handle) {
+            let left = until.saturating_duration_since(Instant::now());
+            let own = wait.timeout;
+            if own.is_some_and(|own| own <= left) {
+                wait.poll();
+            } else {
+                wait.timeout = Some(left);
+                wait.poll();
+                wait.timeout = own;
+                if wait.error.is_none() && wait.ready.iter().all(|ready| ready.is_empty()) {
+                    return Err(::Error::Timeout);
+                }
+            }
+        }
+
+        let data = handle.resume(0)?;
+        if handle.is_finished() || pending(handle).is_none() {
+            return Ok(data);
+        }
+    }
+}
+
+// Driver of `#[coroutine::test]`, resumes the coroutine until it is done
+#[doc(hidden)]
+pub fn __run_test<R: 'static>(f: fn(&mut Coroutine) -> R, timeout: Duration) -> R {
+    run_root(f, Some(timeout))
+}
+
+// Driver of `#[coroutine::main]`
+#[doc(hidden)]
+pub fn __run_main<R: 'static>(f: fn(&mut Coroutine) -> R) -> R {
+    run_root(f, None)
+}
+
+fn run_root<R: 'static>(f: fn(&mut Coroutine) -> R, timeout: Option<Duration>) -> R {
+    let result = Rc::new(RefCell::new(None));
+    let slot = result.clone();
+    let mut coro = Coroutine::spawn(move |coro, _| {
+        *slot.borrow_mut() = Some(f(coro));
+        0
+    });
+
+    let until = timeout.map(|timeout| Instant::now() + timeout);
+    while !coro.is_finished() {
+        let result = match until {
+            Some(until) => run_timeout(&mut coro, until.saturating_duration_since(Instant::now())),
+            None => run(&mut coro),
+        };
+        match result {
+            Ok(..) => {}
+            Err(::Error::Panicking(err)) => panic::resume_unwind(err),
+            Err(::Error::Timeout) => panic!("test timed out after {:?}", timeout.unwrap()),
+            Err(err) => panic!("root coroutine failed: {:?}", err),
+        }
+    }
+
+    let result = result.borrow_mut().take();
+    result.unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn timeout() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let raw = a.as_raw_fd();
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let fds = [Fd::new(raw, Interest::READABLE)];
+            let ready = poll_fds(coro, &fds, Some(Duration::from_millis(10))).unwrap();
+            ready[0].is_empty() as usize
+        });
+
+        assert_eq!(run(&mut coro).unwrap(), 1);
+    }
+
+    #[test]
+    fn deadline() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let (idle, _peer) = UnixStream::pair().unwrap();
+        let (ready, idle) = (a.as_raw_fd(), idle.as_raw_fd());
+        b.write_all(b"x").unwrap();
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let result = coro.with_deadline(Duration::from_secs(3600), |coro| {
+                poll_fds(coro, &[Fd::new(ready, Interest::READABLE)], None).unwrap()
+            });
+            assert!(result.unwrap()[0].is_readable());
+
+            // Never readable, `run` wakes the coroutine when the deadline passes
+            let result = coro.with_deadline(Duration::from_millis(10), |coro| {
+                poll_fds(coro, &[Fd::new(idle, Interest::READABLE)], None).unwrap();
+            });
+            result.is_err() as usize
+        });
+
+        assert_eq!(run(&mut coro).unwrap(), 1);
+    }
+
+    #[test]
+    fn deadline_clears_wait() {
+        let (idle, _peer) = UnixStream::pair().unwrap();
+        let idle = idle.as_raw_fd();
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let result = coro.with_deadline(Duration::from_millis(5), |coro| {
+                poll_fds(coro, &[Fd::new(idle, Interest::READABLE)], None).unwrap();
+            });
+            assert!(result.is_err());
+            coro.yield_with(7)
+        });
+
+        coro.resume(0).unwrap();
+        assert!(pending(&mut coro).is_some());
+        ::std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(coro.resume(0).unwrap(), 7);
+        assert!(pending(&mut coro).is_none());
+        assert_eq!(coro.resume(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn custom_loop() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let raw = a.as_raw_fd();
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let fds = [Fd::new(raw, Interest::READABLE | Interest::WRITABLE)];
+            let ready = poll_fds(coro, &fds, None).unwrap();
+            assert!(ready[0].is_writable());
+            coro.yield_with(1);
+            poll_fds(coro, &fds, None).unwrap_err();
+            2
+        });
+
+        coro.resume(0).unwrap();
+        {
+            let wait = pending(&mut coro).unwrap();
+            assert_eq!(wait.timeout(), None);
+            assert_eq!(wait.trigger(), Trigger::Level);
+            wait.set_ready(0, Ready::from_revents(libc::POLLOUT));
+        }
+        assert_eq!(coro.resume(0).unwrap(), 1);
+        assert!(pending(&mut coro).is_none());
+
+        coro.resume(0).unwrap();
+        pending(&mut coro).unwrap().fail(io::Error::other("loop closed"));
+        b.write_all(b"unused").unwrap();
+        assert_eq!(coro.resume(0).unwrap(), 2);
+    }
+
+    #[test]
+    fn run_timeout_expires() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let raw = a.as_raw_fd();
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let ready = poll_fds(coro, &[Fd::new(raw, Interest::READABLE)], None).unwrap();
+            ready[0].is_readable() as usize
+        });
+
+        // Still parked after giving up, the readiness has not been consumed
+        match run_timeout(&mut coro, Duration::from_millis(10)) {
+            Err(::Error::Timeout) => {}
+            other => panic!("did not time out: {:?}", other),
+        }
+        assert!(pending(&mut coro).is_some());
+        b.write_all(b"x").unwrap();
+        assert_eq!(run_timeout(&mut coro, Duration::from_secs(60)).unwrap(), 1);
+    }
+
+    #[test]
+    fn sleep_for() {
+        let mut coro = Coroutine::spawn(|coro, _| {
+            let start = Instant::now();
+            sleep(coro, Duration::from_millis(10)).unwrap();
+            (start.elapsed() >= Duration::from_millis(10)) as usize
+        });
+
+        // An impatient loop does not cut it short
+        coro.resume(0).unwrap();
+        coro.resume(0).unwrap();
+        assert_eq!(run(&mut coro).unwrap(), 1);
+    }
+
+    #[test]
+    fn wait_ready() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let raw = a.as_raw_fd();
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            assert!(wait_writable(coro, raw, Trigger::Level).unwrap().is_writable());
+            wait_readable(coro, raw, Trigger::Edge).unwrap().is_readable() as usize
+        });
+
+        assert_eq!(coro.resume(0).unwrap(), 0);
+        pending(&mut coro).unwrap().poll();
+        coro.resume(0).unwrap();
+
+        // An empty answer does not wake it up
+        assert_eq!(pending(&mut coro).unwrap().trigger(), Trigger::Edge);
+        coro.resume(0).unwrap();
+        assert!(pending(&mut coro).is_some());
+
+        b.write_all(b"x").unwrap();
+        assert_eq!(run(&mut coro).unwrap(), 1);
+    }
+
+    #[test]
+    fn shared_stack() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let raw = a.as_raw_fd();
+        let opts = || {
+            ::Options {
+                shared_stack: true,
+                ..::Options::default()
+            }
+        };
+
+        let mut waiter = Coroutine::spawn_opts(move |coro, _| {
+            let fds = [Fd::new(raw, Interest::READABLE); 3];
+            poll_fds(coro, &fds, None).unwrap()[2].is_readable() as usize
+        }, opts());
+        assert_eq!(waiter.resume(0).unwrap(), 0);
+
+        // Takes over the shared stack and scribbles over where the frames of `waiter` were
+        let mut other = Coroutine::spawn_opts(|coro, _| {
+            let junk = [0xaau8; 16 * 1024];
+            coro.yield_with(::std::hint::black_box(&junk)[0] as usize)
+        }, opts());
+        assert_eq!(other.resume(0).unwrap(), 0xaa);
+
+        let wait = pending(&mut waiter).unwrap();
+        assert_eq!(wait.fds(), &[Fd::new(raw, Interest::READABLE); 3]);
+        b.write_all(b"x").unwrap();
+        wait.poll();
+        assert_eq!(waiter.resume(0).unwrap(), 1);
+        assert_eq!(other.resume(0).unwrap(), 0);
+    }
+}
@@ -0,0 +1,298 @@
+//! Watching files from a coroutine
+//!
+//! `watch` sets up an inotify instance on Linux. `Watcher::next` parks the coroutine in
+//! `fd::wait_readable` until the kernel reports a change, so a coroutine reloading its
+//! configuration does not need a thread of its own. `Watcher::events` iterates over the changes
+//! the same way.
+//!
+//! ```rust
+//! use std::env;
+//! use std::fs::File;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::fd;
+//!
+//! let dir = env::temp_dir().join(format!("coroutine-fs-doc-{}", std::process::id()));
+//! std::fs::create_dir_all(&dir).unwrap();
+//!
+//! let mut watcher = coroutine::fs::watch(&dir).unwrap();
+//! let file = dir.join("config");
+//! File::create(&file).unwrap();
+//!
+//! let mut coro = Coroutine::spawn(move |coro, _| {
+//!     let event = watcher.next(coro).unwrap();
+//!     assert_eq!(event.path(), &file);
+//!     event.is_created() as usize
+//! });
+//! assert_eq!(fd::run(&mut coro).unwrap(), 1);
+//! std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+use libc;
+
+use asymmetric::Coroutine;
+use fd::{self, Trigger};
+
+const MASK: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MODIFY | libc::IN_CLOSE_WRITE |
+                  libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_DELETE_SELF |
+                  libc::IN_MOVE_SELF | libc::IN_ATTRIB;
+
+/// A change of a watched file, or of a file in a watched directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    path: PathBuf,
+    mask: u32,
+}
+
+impl Event {
+    /// The file which has changed
+    ///
+    /// Empty for `is_overflow` events, which do not belong to any file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The raw inotify event mask
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// The file has been created, or moved in
+    pub fn is_created(&self) -> bool {
+        self.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+    }
+
+    /// The content or the metadata of the file has changed
+    pub fn is_modified(&self) -> bool {
+        self.mask & (libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_ATTRIB) != 0
+    }
+
+    /// The file has been deleted, or moved away
+    pub fn is_removed(&self) -> bool {
+        self.mask &
+        (libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0
+    }
+
+    /// Events have been lost because they were not read fast enough
+    pub fn is_overflow(&self) -> bool {
+        self.mask & libc::IN_Q_OVERFLOW != 0
+    }
+}
+
+/// Watches files and directories for changes
+#[derive(Debug)]
+pub struct Watcher {
+    fd: RawFd,
+    // Path of every watch descriptor
+    watches: HashMap<libc::c_int, PathBuf>,
+    pending: VecDeque<Event>,
+}
+
+/// Start watching `path`, which may be a file or a directory
+pub fn watch<P: AsRef<Path>>(path: P) -> io::Result<Watcher> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut watcher = Watcher {
+        fd,
+        watches: HashMap::new(),
+        pending: VecDeque::new(),
+    };
+    watcher.add(path)?;
+    Ok(watcher)
+}
+
+impl Watcher {
+    /// Watch `path` as well
+    ///
+    /// The files directly inside of a directory are watched, not those in its subdirectories.
+    pub fn add<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let name = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let wd = unsafe { libc::inotify_add_watch(self.fd, name.as_ptr(), MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.watches.insert(wd, path.to_owned());
+        Ok(())
+    }
+
+    /// Stop watching `path`, returns whether it was watched
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        let path = path.as_ref();
+        let wd = match self.watches.iter().find(|w| w.1 == path) {
+            Some((&wd, _)) => wd,
+            None => return false,
+        };
+
+        self.watches.remove(&wd);
+        unsafe {
+            libc::inotify_rm_watch(self.fd, wd);
+        }
+        true
+    }
+
+    /// Park `coro` until the next change
+    pub fn next(&mut self, coro: &mut Coroutine) -> io::Result<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+            self.read(coro)?;
+        }
+    }
+
+    /// Iterate over the changes, parking `coro` while there are none
+    ///
+    /// Never ends on its own, only a failed read is returned as the last item.
+    pub fn events<'a>(&'a mut self, coro: &'a mut Coroutine) -> Events<'a> {
+        Events {
+            watcher: self,
+            coro,
+            failed: false,
+        }
+    }
+
+    fn read(&mut self, coro: &mut Coroutine) -> io::Result<()> {
+        // Aligned for the event headers, large enough for any single event
+        let mut buf = [0u64; 512];
+        let len = loop {
+            let n = unsafe {
+                libc::read(self.fd, buf.as_mut_ptr() as *mut _, mem::size_of_val(&buf))
+            };
+            if n >= 0 {
+                break n as usize;
+            }
+
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock => {
+                    fd::wait_readable(coro, self.fd, Trigger::Edge)?;
+                }
+                io::ErrorKind::Interrupted => {}
+                _ => return Err(err),
+            }
+        };
+
+        let bytes = unsafe { ::std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+        let header = mem::size_of::<libc::inotify_event>();
+        let mut offset = 0;
+        while offset + header <= len {
+            let event = unsafe {
+                (bytes.as_ptr().add(offset) as *const libc::inotify_event).read_unaligned()
+            };
+            let name = &bytes[offset + header..offset + header + event.len as usize];
+            offset += header + event.len as usize;
+
+            if event.mask & libc::IN_IGNORED != 0 {
+                // The watch is gone, e.g. because the file has been deleted
+                self.watches.remove(&event.wd);
+                continue;
+            }
+
+            let mut path = self.watches.get(&event.wd).cloned().unwrap_or_default();
+            // The name is padded with NULs
+            let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            if end > 0 {
+                path.push(OsStr::from_bytes(&name[..end]));
+            }
+            self.pending.push_back(Event {
+                path,
+                mask: event.mask,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Iterator over the changes seen by a `Watcher`, see `Watcher::events`
+pub struct Events<'a> {
+    watcher: &'a mut Watcher,
+    coro: &'a mut Coroutine,
+    failed: bool,
+}
+
+impl<'a> Iterator for Events<'a> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        if self.failed {
+            return None;
+        }
+
+        let event = self.watcher.next(self.coro);
+        self.failed = event.is_err();
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn events() {
+        let dir = env::temp_dir().join(format!("coroutine-fs-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("watched");
+        File::create(&file).unwrap();
+
+        let mut watcher = watch(&file).unwrap();
+        watcher.add(&dir).unwrap();
+        assert!(watcher.remove(&dir));
+        assert!(!watcher.remove(&dir));
+
+        let watched = file.clone();
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let mut modified = false;
+            for event in watcher.events(coro) {
+                let event = event.unwrap();
+                assert_eq!(event.path(), &watched);
+                modified |= event.is_modified();
+                if event.is_removed() {
+                    break;
+                }
+            }
+            modified as usize
+        });
+
+        // Parked until something happens
+        coro.resume(0).unwrap();
+        assert!(fd::pending(&mut coro).is_some());
+
+        File::create(&file).unwrap().write_all(b"changed").unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(fd::run(&mut coro).unwrap(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,545 @@
+//! Generators with the signature of the nightly `std::ops::Generator`
+//!
+//! Code written against the nightly trait can run on stable by implementing its body as a
+//! closure which yields through a `Yielder`.
+//!
+//! ```rust
+//! use std::pin::Pin;
+//! use coroutine::generator::{Gen, Generator, GeneratorState};
+//!
+//! let mut gen = Gen::new(|y, first: i32| {
+//!     let second = y.yield_(first + 1);
+//!     second * 2
+//! });
+//!
+//! match Pin::new(&mut gen).resume(1) {
+//!     GeneratorState::Yielded(v) => assert_eq!(v, 2),
+//!     GeneratorState::Complete(..) => unreachable!(),
+//! }
+//! match Pin::new(&mut gen).resume(5) {
+//!     GeneratorState::Complete(v) => assert_eq!(v, 10),
+//!     GeneratorState::Yielded(..) => unreachable!(),
+//! }
+//! ```
+
+use std::any::Any;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver};
+use std::task::{Context, Poll};
+
+use asymmetric::{Coroutine, Handle};
+use blocking;
+#[cfg(unix)]
+use fd;
+use options::Options;
+#[cfg(unix)]
+use sync::mpsc as external;
+
+/// The result of a generator resumption, mirrors `std::ops::GeneratorState`
+#[derive(Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Debug, Hash)]
+pub enum GeneratorState<Y, R> {
+    /// The generator suspended with a value
+    Yielded(Y),
+
+    /// The generator completed with a return value
+    Complete(R),
+}
+
+/// Crate-local copy of the nightly `std::ops::Generator` trait
+pub trait Generator<R = ()> {
+    /// The type of value this generator yields
+    type Yield;
+
+    /// The type of value this generator returns
+    type Return;
+
+    /// Resumes the execution of this generator
+    fn resume(self: Pin<&mut Self>, arg: R) -> GeneratorState<Self::Yield, Self::Return>;
+}
+
+// Passed by pointer on every switch. Both sides are always suspended in a frame which owns the
+// message while the other one looks at it, so no allocation is needed.
+struct Message<Y, R, Ret> {
+    arg: Option<R>,
+    out: *mut Option<GeneratorState<Y, Ret>>,
+}
+
+/// Yields values from inside of a `Gen`
+pub struct Yielder<Y, R, Ret> {
+    coro: *mut Coroutine,
+    msg: *mut Message<Y, R, Ret>,
+}
+
+impl<Y, R, Ret> Message<Y, R, Ret> {
+    fn take_out(&mut self) -> Option<GeneratorState<Y, Ret>> {
+        unsafe { (*self.out).take() }
+    }
+}
+
+impl<Y, R, Ret> Yielder<Y, R, Ret> {
+    fn take_arg(&mut self) -> R {
+        unsafe { (*self.msg).arg.take().expect("generator argument has already been taken") }
+    }
+
+    fn put(&mut self, state: GeneratorState<Y, Ret>) {
+        unsafe {
+            *(*self.msg).out = Some(state);
+        }
+    }
+
+    /// Announce how many more values the generator is going to yield, see
+    /// `Coroutine::hint_len`
+    pub fn hint_len(&mut self, n: usize) {
+        unsafe { (*self.coro).hint_len(n) }
+    }
+
+    /// Suspend the generator with `value`, returns the argument of the next `resume`
+    pub fn yield_(&mut self, value: Y) -> R {
+        self.put(GeneratorState::Yielded(value));
+        let coro = unsafe { &mut *self.coro };
+        self.msg = coro.yield_with(0) as *mut Message<Y, R, Ret>;
+        self.take_arg()
+    }
+}
+
+/// A generator running on its own coroutine
+pub struct Gen<Y, R = (), Ret = ()> {
+    handle: Handle,
+    _marker: PhantomData<fn(R) -> (Y, Ret)>,
+}
+
+impl<Y, R, Ret> Gen<Y, R, Ret>
+    where Y: 'static,
+          R: 'static,
+          Ret: 'static
+{
+    /// Create a generator with default options, nothing runs until the first `resume`
+    #[track_caller]
+    pub fn new<F>(f: F) -> Gen<Y, R, Ret>
+        where F: FnOnce(&mut Yielder<Y, R, Ret>, R) -> Ret + 'static
+    {
+        Gen::with_options(f, Options::default())
+    }
+
+    /// Create a generator with `Options`
+    #[track_caller]
+    pub fn with_options<F>(f: F, opts: Options) -> Gen<Y, R, Ret>
+        where F: FnOnce(&mut Yielder<Y, R, Ret>, R) -> Ret + 'static
+    {
+        let handle = Coroutine::spawn_opts(move |coro, data| {
+            let mut yielder = Yielder {
+                coro,
+                msg: data as *mut Message<Y, R, Ret>,
+            };
+            let arg = yielder.take_arg();
+            let ret = f(&mut yielder, arg);
+            yielder.put(GeneratorState::Complete(ret));
+            0
+        }, opts);
+
+        Gen {
+            handle,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<Y, R, Ret> Gen<Y, R, Ret> {
+    /// Check if the generator has completed
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// The coroutine backing this generator
+    #[inline]
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+}
+
+impl<Y, R, Ret> Generator<R> for Gen<Y, R, Ret> {
+    type Yield = Y;
+    type Return = Ret;
+
+    /// Panics inside the generator are propagated to the caller
+    ///
+    /// A generator waiting in `fd::poll_fds` is waited for in turn: the calling coroutine
+    /// parks on the same file descriptors, a plain thread blocks until they are ready.
+    fn resume(self: Pin<&mut Self>, arg: R) -> GeneratorState<Y, Ret> {
+        let this = self.get_mut();
+
+        let mut out = None;
+        let mut msg = Message {
+            arg: Some(arg),
+            out: &mut out,
+        };
+
+        loop {
+            match this.handle.resume(&mut msg as *mut Message<Y, R, Ret> as usize) {
+                Ok(..) => {
+                    if let Some(state) = msg.take_out() {
+                        return state;
+                    }
+                    if !wait_for(&mut this.handle) {
+                        panic!("generator switched back without a value");
+                    }
+                }
+                Err(::Error::Panicking(err)) => panic::resume_unwind(err),
+                Err(err) => panic!("generator failed to resume: {:?}", err),
+            }
+        }
+    }
+}
+
+impl<Y, Ret> Gen<Y, (), Ret> {
+    /// Expose the generator as an asynchronous stream of its yielded values
+    ///
+    /// The return value is discarded.
+    pub fn into_async_stream(self) -> AsyncStream<Y, Ret> {
+        AsyncStream { gen: self }
+    }
+}
+
+/// Generators without a resume argument are iterators over their yielded values
+///
+/// All the `Iterator` adapters (`map`, `filter`, `take_while`, `zip`, ...) therefore work on
+/// them lazily, driving the generator from the consuming side without spawning any further
+/// coroutine. The return value is discarded.
+impl<Y, Ret> Iterator for Gen<Y, (), Ret> {
+    type Item = Y;
+
+    fn next(&mut self) -> Option<Y> {
+        if self.is_complete() {
+            return None;
+        }
+
+        match Pin::new(self).resume(()) {
+            GeneratorState::Yielded(value) => Some(value),
+            GeneratorState::Complete(..) => None,
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.is_complete() {
+            return (0, Some(0));
+        }
+        (self.handle.len_hint().unwrap_or(0), None)
+    }
+}
+
+/// Asynchronous stream over the values of a `Gen`
+///
+/// `poll_next` has the signature of `futures::Stream::poll_next`. The generator is driven
+/// inline: every poll resumes it once on the polling thread, so it is always ready, and a
+/// generator blocking between yields blocks the task polling it.
+pub struct AsyncStream<Y, Ret = ()> {
+    gen: Gen<Y, (), Ret>,
+}
+
+impl<Y, Ret> AsyncStream<Y, Ret> {
+    /// Attempt to pull out the next value, `None` once the generator has completed
+    pub fn poll_next(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Option<Y>> {
+        Poll::Ready(self.get_mut().gen.next())
+    }
+
+    /// Future resolving to the next value, for `while let Some(v) = stream.next_item().await`
+    pub fn next_item(&mut self) -> Next<'_, Y, Ret> {
+        Next { stream: self }
+    }
+}
+
+/// Future returned by `AsyncStream::next_item`
+pub struct Next<'a, Y: 'a, Ret: 'a> {
+    stream: &'a mut AsyncStream<Y, Ret>,
+}
+
+impl<'a, Y, Ret> Future for Next<'a, Y, Ret> {
+    type Output = Option<Y>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Y>> {
+        Pin::new(&mut *self.get_mut().stream).poll_next(cx)
+    }
+}
+
+#[cfg(unix)]
+fn wait_for(handle: &mut Handle) -> bool {
+    fd::wait_for(handle)
+}
+
+#[cfg(not(unix))]
+fn wait_for(_: &mut Handle) -> bool {
+    false
+}
+
+// Items a bridge thread may run ahead of its consumer
+const BRIDGE_BUFFER: usize = 64;
+
+// What the blocking side of a bridge sends, a panic ends it
+type Bridged<T> = Result<T, Box<dyn Any + Send>>;
+
+/// Turn a blocking iterator into a generator
+///
+/// The iterator is driven by a thread of the `blocking` pool which runs up to 64 items ahead.
+/// When the consumer is faster, the generator waits in `fd::poll_fds` for the next item, so a
+/// coroutine consuming it parks instead of blocking its thread, see `Gen::resume`. Once the
+/// generator is dropped the thread stops before the next item, or returns to the pool as soon
+/// as the iterator returns from the one it is blocked on. An iterator which blocks for good
+/// keeps its thread of the bounded pool for good as well.
+#[cfg(unix)]
+pub fn iter_to_coroutine<I>(iter: I) -> Gen<I::Item>
+    where I: Iterator + Send + 'static,
+          I::Item: Send + 'static
+{
+    let (tx, rx) = external::channel_with_external_senders::<Bridged<I::Item>>()
+        .expect("failed to create the wake-up file descriptor");
+    // One credit for every item the iterator may run ahead
+    let (credit_tx, credit_rx) = mpsc::sync_channel(BRIDGE_BUFFER);
+    for _ in 0..BRIDGE_BUFFER {
+        credit_tx.send(()).unwrap();
+    }
+
+    blocking::spawn(move || {
+        let mut iter = iter;
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            while credit_rx.recv().is_ok() {
+                match iter.next() {
+                    Some(item) => {
+                        if tx.send(Ok(item)).is_err() {
+                            return;
+                        }
+                    }
+                    None => return,
+                }
+            }
+        }));
+        // Hand a panic of the iterator over to the consumer
+        if let Err(err) = result {
+            let _ = tx.send(Err(err));
+        }
+    });
+
+    Gen::new(move |y, ()| {
+        while let Ok(item) = rx.recv(unsafe { &mut *y.coro }) {
+            match item {
+                Ok(item) => {
+                    let _ = credit_tx.try_send(());
+                    y.yield_(item);
+                }
+                Err(err) => panic::resume_unwind(err),
+            }
+        }
+    })
+}
+
+/// Run a generator on a thread of its own and consume its values from a plain thread
+///
+/// `f` is the body of a `Gen`, run by a thread of the `blocking` pool up to 64 values ahead of
+/// the consumer. A panic inside of it is propagated by the iterator after the values yielded
+/// before it.
+pub fn coroutine_to_iter<T, F>(f: F) -> BlockingIter<T>
+    where F: FnOnce(&mut Yielder<T, (), ()>) + Send + 'static,
+          T: Send + 'static
+{
+    let (tx, rx) = mpsc::sync_channel(BRIDGE_BUFFER);
+    blocking::spawn(move || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            for value in Gen::new(move |y, ()| f(y)) {
+                if tx.send(Ok(value)).is_err() {
+                    break;
+                }
+            }
+        }));
+        if let Err(err) = result {
+            let _ = tx.send(Err(err));
+        }
+    });
+
+    BlockingIter { rx }
+}
+
+/// Blocking iterator over the values of a generator, see `coroutine_to_iter`
+///
+/// Dropping it stops the generator the next time it yields.
+#[derive(Debug)]
+pub struct BlockingIter<T> {
+    rx: Receiver<Bridged<T>>,
+}
+
+impl<T> Iterator for BlockingIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        match self.rx.recv() {
+            Ok(Ok(value)) => Some(value),
+            Ok(Err(err)) => panic::resume_unwind(err),
+            Err(..) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::pin::Pin;
+
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn iter_bridge_parks() {
+        let gen = iter_to_coroutine((0..200).map(|i| i * 2));
+        assert_eq!(gen.sum::<i32>(), 200 * 199);
+
+        let (tx, rx) = mpsc::channel();
+        let mut consumer = Coroutine::spawn(move |_, _| iter_to_coroutine(rx.into_iter()).sum());
+
+        // Nothing to take yet, the consumer parks rather than blocking the thread
+        consumer.resume(0).unwrap();
+        assert!(fd::pending(&mut consumer).is_some());
+        for i in 1..=10 {
+            tx.send(i).unwrap();
+        }
+        drop(tx);
+        assert_eq!(fd::run(&mut consumer).unwrap(), 55);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[should_panic(expected = "in iterator")]
+    fn iter_bridge_iterator_panics() {
+        let gen = iter_to_coroutine((0..10).inspect(|&i| assert!(i < 5, "in iterator")));
+        gen.for_each(drop);
+    }
+
+    #[test]
+    fn iter_bridges() {
+        let values = coroutine_to_iter(|y| {
+                for i in 0..200 {
+                    y.yield_(i);
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, (0..200).collect::<Vec<_>>());
+
+        // Stops early when the consumer goes away
+        let mut endless = coroutine_to_iter(|y| loop {
+            y.yield_(1);
+        });
+        assert_eq!(endless.next(), Some(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "in generator")]
+    fn iter_bridge_panics() {
+        let mut iter = coroutine_to_iter(|y| {
+            y.yield_(1);
+            panic!("in generator");
+        });
+        assert_eq!(iter.next(), Some(1));
+        iter.next();
+    }
+
+    #[test]
+    fn yield_and_complete() {
+        let mut gen = Gen::new(|y, ()| {
+            for i in 0..3 {
+                y.yield_(i);
+            }
+            "done"
+        });
+
+        for i in 0..3 {
+            assert_eq!(Pin::new(&mut gen).resume(()), GeneratorState::Yielded(i));
+        }
+        assert_eq!(Pin::new(&mut gen).resume(()), GeneratorState::Complete("done"));
+        assert!(gen.is_complete());
+    }
+
+    #[test]
+    fn resume_arguments() {
+        let mut gen = Gen::new(|y, first: String| {
+            let second = y.yield_(first.len());
+            first + &second
+        });
+
+        assert_eq!(Pin::new(&mut gen).resume("ab".to_owned()),
+                   GeneratorState::Yielded(2));
+        assert_eq!(Pin::new(&mut gen).resume("cd".to_owned()),
+                   GeneratorState::Complete("abcd".to_owned()));
+    }
+
+    #[test]
+    fn combinators() {
+        let squares = Gen::new(|y, ()| {
+            for i in 0.. {
+                y.yield_(i * i);
+            }
+        });
+        let letters = Gen::new(|y, ()| {
+            for c in "abcdef".chars() {
+                y.yield_(c);
+            }
+        });
+
+        let pairs = squares.filter(|n| n % 2 == 0)
+            .map(|n| n + 1)
+            .take_while(|&n| n < 50)
+            .zip(letters)
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, [(1, 'a'), (5, 'b'), (17, 'c'), (37, 'd')]);
+    }
+
+    #[test]
+    fn size_hint() {
+        let gen = Gen::new(|y, ()| {
+            y.hint_len(1000);
+            for i in 0..1000 {
+                y.yield_(i);
+            }
+        });
+
+        // The hint is only known once the generator has started
+        let mut gen = gen.peekable();
+        gen.peek();
+        assert_eq!(gen.size_hint().0, 1000);
+
+        let values = gen.collect::<Vec<_>>();
+        assert_eq!(values.len(), 1000);
+        assert!(values.capacity() < 2000);
+    }
+
+    #[test]
+    fn async_stream() {
+        use std::future::Future;
+        use std::task::{Context, Poll, Waker};
+
+        let gen = Gen::new(|y, ()| {
+            y.yield_(1);
+            y.yield_(2);
+        });
+        let mut stream = gen.into_async_stream();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut values = Vec::new();
+        loop {
+            let mut next = stream.next_item();
+            match Pin::new(&mut next).poll(&mut cx) {
+                Poll::Ready(Some(v)) => values.push(v),
+                Poll::Ready(None) => break,
+                Poll::Pending => unreachable!(),
+            }
+        }
+
+        assert_eq!(values, [1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "inside generator")]
+    fn propagate_panic() {
+        let mut gen: Gen<(), ()> = Gen::new(|_, ()| panic!("inside generator"));
+        Pin::new(&mut gen).resume(());
+    }
+}
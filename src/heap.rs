@@ -0,0 +1,241 @@
+//! Attributing heap memory to the coroutines which allocated it
+//!
+//! `TrackingAllocator` wraps a global allocator and tags every allocation with the id of the
+//! coroutine running on the thread at the time, `Handle::id`. The bytes still allocated are
+//! summed up per coroutine, also when the memory is freed elsewhere, so `by_coroutine` points
+//! at the coroutines a leak comes from, even after they have finished.
+//!
+//! ```rust
+//! use std::mem;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::heap::{self, TrackingAllocator};
+//!
+//! #[global_allocator]
+//! static ALLOC: TrackingAllocator = TrackingAllocator::new();
+//!
+//! let mut coro = Coroutine::spawn(|_, _| {
+//!     mem::forget(vec![0u8; 4096]);
+//!     0
+//! });
+//! let id = coro.id();
+//! coro.resume(0).unwrap();
+//! assert_eq!(heap::live(id), 4096);
+//! ```
+//!
+//! Allocations made outside of coroutines are not counted, neither are those the crate makes
+//! for its own bookkeeping while a coroutine runs. Every allocation carries a header
+//! of at least 16 bytes and takes a lock on one of 64 stripes of the table, which is fine for
+//! profiling but not for production builds. At most 4096 coroutines are told apart at a time,
+//! stripes permitting, the bytes of those beyond show up in `untracked`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, UnsafeCell};
+use std::cmp;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use asymmetric;
+
+const STRIPES: usize = 64;
+const SLOTS: usize = 64;
+const HEADER: usize = 16;
+
+static TABLE: [Stripe; STRIPES] = [const { Stripe::new() }; STRIPES];
+static UNTRACKED: AtomicUsize = AtomicUsize::new(0);
+
+// Set while the crate does bookkeeping of its own on behalf of the running coroutine
+thread_local!(static INTERNAL: Cell<bool> = const { Cell::new(false) });
+
+// Live bytes of the coroutines whose ids fall into it, a slot is free while its id is 0
+struct Stripe {
+    lock: AtomicBool,
+    slots: UnsafeCell<[(usize, usize); SLOTS]>,
+}
+
+// The slots are only touched with the lock held
+unsafe impl Sync for Stripe {}
+
+impl Stripe {
+    const fn new() -> Stripe {
+        Stripe {
+            lock: AtomicBool::new(false),
+            slots: UnsafeCell::new([(0, 0); SLOTS]),
+        }
+    }
+
+    // Must not allocate, the allocator is inside of it
+    fn with<R, F>(&self, f: F) -> R
+        where F: FnOnce(&mut [(usize, usize); SLOTS]) -> R
+    {
+        while self.lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err() {
+            ::std::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.slots.get() });
+        self.lock.store(false, Ordering::Release);
+        result
+    }
+}
+
+fn stripe(id: usize) -> &'static Stripe {
+    &TABLE[id % STRIPES]
+}
+
+// Whether the bytes have been added to the slot of `id` rather than to `UNTRACKED`
+fn add(id: usize, bytes: usize) -> bool {
+    let tracked = stripe(id).with(|slots| {
+        if let Some(slot) = slots.iter_mut().find(|slot| slot.0 == id) {
+            slot.1 += bytes;
+            return true;
+        }
+        match slots.iter_mut().find(|slot| slot.0 == 0) {
+            Some(slot) => {
+                *slot = (id, bytes);
+                true
+            }
+            None => false,
+        }
+    });
+    if !tracked {
+        UNTRACKED.fetch_add(bytes, Ordering::Relaxed);
+    }
+    tracked
+}
+
+fn sub(id: usize, bytes: usize, tracked: bool) {
+    if !tracked {
+        UNTRACKED.fetch_sub(bytes, Ordering::Relaxed);
+        return;
+    }
+    stripe(id).with(|slots| {
+        if let Some(slot) = slots.iter_mut().find(|slot| slot.0 == id) {
+            slot.1 -= bytes;
+            if slot.1 == 0 {
+                *slot = (0, 0);
+            }
+        }
+    });
+}
+
+// Run `f` without charging what it allocates to the running coroutine
+#[cfg(feature = "latency-histogram")]
+pub(crate) fn internal<R, F>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            INTERNAL.with(|internal| internal.set(self.0));
+        }
+    }
+
+    let _restore = Restore(INTERNAL.with(|internal| internal.replace(true)));
+    f()
+}
+
+/// Bytes allocated by the coroutine with this id and not freed yet
+pub fn live(id: usize) -> usize {
+    stripe(id).with(|slots| slots.iter().find(|slot| slot.0 == id).map_or(0, |slot| slot.1))
+}
+
+/// Ids of the coroutines with allocations left and their live bytes, largest first
+pub fn by_coroutine() -> Vec<(usize, usize)> {
+    // Reserved up front, the stripes are locked while it is filled
+    let mut all = Vec::with_capacity(STRIPES * SLOTS);
+    for stripe in &TABLE {
+        stripe.with(|slots| all.extend(slots.iter().filter(|slot| slot.0 != 0).cloned()));
+    }
+    all.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    all
+}
+
+/// Bytes allocated by coroutines which found the table full
+pub fn untracked() -> usize {
+    UNTRACKED.load(Ordering::Relaxed)
+}
+
+/// A global allocator which accounts allocations to the running coroutine, see the module
+/// documentation
+#[derive(Debug, Default)]
+pub struct TrackingAllocator<A = System> {
+    inner: A,
+}
+
+impl TrackingAllocator<System> {
+    /// Track allocations of the system allocator
+    pub const fn new() -> TrackingAllocator<System> {
+        TrackingAllocator { inner: System }
+    }
+}
+
+impl<A> TrackingAllocator<A> {
+    /// Track allocations of `inner`
+    pub const fn wrap(inner: A) -> TrackingAllocator<A> {
+        TrackingAllocator { inner }
+    }
+}
+
+// The allocation with room for the header in front, which holds the id and whether the
+// bytes are in the table
+fn outer(layout: Layout) -> (Layout, usize) {
+    let header = cmp::max(layout.align(), HEADER);
+    let size = layout.size() + header;
+    (unsafe { Layout::from_size_align_unchecked(size, layout.align()) }, header)
+}
+
+unsafe fn tag(ptr: *mut u8) -> (*mut usize, *mut usize) {
+    ((ptr as *mut usize).sub(2), (ptr as *mut usize).sub(1))
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (outer, header) = outer(layout);
+        let base = self.inner.alloc(outer);
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = base.add(header);
+        let id = if INTERNAL.with(|internal| internal.get()) {
+            0
+        } else {
+            asymmetric::with_current(|current| current.map_or(0, |coro| coro.id()))
+        };
+        let tracked = id != 0 && add(id, layout.size());
+        let (id_tag, tracked_tag) = tag(ptr);
+        ptr::write(id_tag, id);
+        ptr::write(tracked_tag, tracked as usize);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, header) = outer(layout);
+        let (id_tag, tracked_tag) = tag(ptr);
+        let id = ptr::read(id_tag);
+        if id != 0 {
+            sub(id, layout.size(), ptr::read(tracked_tag) != 0);
+        }
+        self.inner.dealloc(ptr.sub(header), outer)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let (outer, header) = outer(layout);
+        let base = self.inner.realloc(ptr.sub(header), outer, new_size + header);
+        if base.is_null() {
+            return base;
+        }
+
+        // Still accounted to the coroutine which made the first allocation
+        let ptr = base.add(header);
+        let (id_tag, tracked_tag) = tag(ptr);
+        let id = ptr::read(id_tag);
+        if id != 0 {
+            let tracked = ptr::read(tracked_tag) != 0;
+            sub(id, layout.size(), tracked);
+            ptr::write(tracked_tag, add(id, new_size) as usize);
+        }
+        ptr
+    }
+}
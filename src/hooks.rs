@@ -0,0 +1,202 @@
+//! Callbacks invoked at lifecycle transitions of every coroutine
+//!
+//! Hooks are process-wide and called on the thread where the transition happens: `on_spawn`
+//! by the spawning thread, `on_resume` and `on_yield` by the resumer right before switching in
+//! and right after getting control back, and `on_exit` when the handle is dropped, with the
+//! state the coroutine had at that point (anything but `Finished` or `Panicked` means it has
+//! been cancelled). Nothing is paid while no hook is installed.
+//!
+//! ```rust
+//! use std::sync::Arc;
+//! use std::sync::atomic::{AtomicUsize, Ordering};
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::hooks;
+//!
+//! let resumes = Arc::new(AtomicUsize::new(0));
+//! let counter = resumes.clone();
+//! hooks::on_resume(move |_| {
+//!     counter.fetch_add(1, Ordering::Relaxed);
+//! });
+//!
+//! let mut coro = Coroutine::spawn(|coro, _| coro.yield_with(0));
+//! coro.resume(0).unwrap();
+//! coro.resume(0).unwrap();
+//! assert_eq!(resumes.load(Ordering::Relaxed), 2);
+//! ```
+
+use std::panic::Location;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use asymmetric::{Coroutine, State};
+
+/// A lifecycle transition of a coroutine
+#[derive(Debug)]
+pub struct Event<'a> {
+    /// Id of the coroutine
+    pub id: usize,
+    /// Name of the coroutine
+    pub name: Option<&'a str>,
+    /// Where the coroutine has been spawned
+    pub spawn_location: &'static Location<'static>,
+    /// State right after the transition, or before it is dropped for `on_exit`
+    pub state: State,
+}
+
+type Hook = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Clone, Copy)]
+pub(crate) enum Kind {
+    Spawn,
+    Resume,
+    Yield,
+    Exit,
+}
+
+struct Hooks {
+    spawn: Vec<Hook>,
+    resume: Vec<Hook>,
+    yield_: Vec<Hook>,
+    exit: Vec<Hook>,
+}
+
+impl Hooks {
+    fn get(&self, kind: Kind) -> &Vec<Hook> {
+        match kind {
+            Kind::Spawn => &self.spawn,
+            Kind::Resume => &self.resume,
+            Kind::Yield => &self.yield_,
+            Kind::Exit => &self.exit,
+        }
+    }
+
+    fn get_mut(&mut self, kind: Kind) -> &mut Vec<Hook> {
+        match kind {
+            Kind::Spawn => &mut self.spawn,
+            Kind::Resume => &mut self.resume,
+            Kind::Yield => &mut self.yield_,
+            Kind::Exit => &mut self.exit,
+        }
+    }
+}
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static HOOKS: RwLock<Hooks> = RwLock::new(Hooks {
+    spawn: Vec::new(),
+    resume: Vec::new(),
+    yield_: Vec::new(),
+    exit: Vec::new(),
+});
+
+fn install<F>(kind: Kind, f: F)
+    where F: Fn(&Event) + Send + Sync + 'static
+{
+    HOOKS.write().unwrap().get_mut(kind).push(Arc::new(f));
+    INSTALLED.store(true, Ordering::Release);
+}
+
+/// Call `f` whenever a coroutine is spawned
+pub fn on_spawn<F>(f: F)
+    where F: Fn(&Event) + Send + Sync + 'static
+{
+    install(Kind::Spawn, f)
+}
+
+/// Call `f` whenever a coroutine is about to be resumed
+pub fn on_resume<F>(f: F)
+    where F: Fn(&Event) + Send + Sync + 'static
+{
+    install(Kind::Resume, f)
+}
+
+/// Call `f` whenever a coroutine has switched back to its resumer, by yielding or finishing
+pub fn on_yield<F>(f: F)
+    where F: Fn(&Event) + Send + Sync + 'static
+{
+    install(Kind::Yield, f)
+}
+
+/// Call `f` whenever the handle of a coroutine is dropped
+pub fn on_exit<F>(f: F)
+    where F: Fn(&Event) + Send + Sync + 'static
+{
+    install(Kind::Exit, f)
+}
+
+/// Remove all hooks
+pub fn clear() {
+    let mut hooks = HOOKS.write().unwrap();
+    INSTALLED.store(false, Ordering::Release);
+    for kind in [Kind::Spawn, Kind::Resume, Kind::Yield, Kind::Exit] {
+        hooks.get_mut(kind).clear();
+    }
+}
+
+#[inline]
+pub(crate) fn fire(kind: Kind, coro: &Coroutine) {
+    if INSTALLED.load(Ordering::Acquire) {
+        fire_slow(kind, coro);
+    }
+}
+
+#[inline(never)]
+fn fire_slow(kind: Kind, coro: &Coroutine) {
+    // Not called under the lock, hooks may spawn coroutines or install hooks themselves
+    let hooks = HOOKS.read().unwrap().get(kind).clone();
+    if hooks.is_empty() {
+        return;
+    }
+
+    let event = Event {
+        id: coro.id(),
+        name: coro.name().map(|n| &n[..]),
+        spawn_location: coro.spawn_location(),
+        state: coro.state(),
+    };
+    for hook in hooks {
+        hook(&event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn lifecycle() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        // Hooks are global, only look at the coroutine spawned here
+        let name = "hooks::lifecycle";
+        let record = |label: &'static str| {
+            let events = events.clone();
+            move |event: &Event| {
+                if event.name == Some(name) {
+                    events.lock().unwrap().push((label, event.state));
+                }
+            }
+        };
+
+        on_spawn(record("spawn"));
+        on_resume(record("resume"));
+        on_yield(record("yield"));
+        on_exit(record("exit"));
+
+        {
+            let opts = ::Options {
+                name: Some(name.to_owned()),
+                ..::Options::default()
+            };
+            let mut coro = Coroutine::spawn_opts(|coro, _| coro.yield_with(0), opts);
+            coro.resume(0).unwrap();
+        }
+
+        assert_eq!(*events.lock().unwrap(),
+                   [("spawn", State::Suspended),
+                    ("resume", State::Running),
+                    ("yield", State::Suspended),
+                    ("exit", State::Suspended)]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Fan-out/fan-in combinators over groups of coroutines
+//!
+//! The combinators drive the coroutines themselves, resuming them (with `0`) in round-robin
+//! order on the calling thread or coroutine until the combinator's condition is met.
+//! Once all of those still running wait in `fd::poll_fds` the combinator waits for them, see
+//! `fd::run`, and resumes only the ones which are ready.
+//! Coroutines which are no longer needed are cancelled by dropping their handles, which unwinds
+//! them.
+
+use asymmetric::Handle;
+
+/// Resume every coroutine until all of them have finished
+///
+/// Returns the result of the final resume of each coroutine, in the order of `handles`.
+pub fn join_all<K>(handles: Vec<Handle<K>>) -> Vec<::Result<usize>> {
+    let mut results = (0..handles.len()).map(|_| None).collect::<Vec<_>>();
+    let mut pending = handles.into_iter().enumerate().collect::<Vec<_>>();
+
+    while !pending.is_empty() {
+        let mut ready = ready(pending.iter_mut().map(|entry| &mut entry.1)).into_iter();
+        pending.retain_mut(|&mut (i, ref mut handle)| {
+            if !ready.next().unwrap() {
+                return true;
+            }
+            let result = handle.resume(0);
+            if handle.is_finished() {
+                results[i] = Some(result);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+/// Resume the coroutines until the first of them finishes, then cancel the others
+///
+/// Returns the index of the winner in `handles` along with its result.
+pub fn race<K>(mut handles: Vec<Handle<K>>) -> (usize, ::Result<usize>) {
+    assert!(!handles.is_empty(), "racing no coroutines");
+
+    loop {
+        let ready = ready(handles.iter_mut());
+        for (i, handle) in handles.iter_mut().enumerate() {
+            if !ready[i] {
+                continue;
+            }
+            let result = handle.resume(0);
+            if handle.is_finished() {
+                return (i, result);
+            }
+        }
+    }
+}
+
+/// Like `join_all`, but stop at the first coroutine that panics and cancel the others
+///
+/// Returns the index of the failed coroutine in `handles` along with its error.
+pub fn try_join<K>(handles: Vec<Handle<K>>) -> Result<Vec<usize>, (usize, ::Error)> {
+    let mut results = (0..handles.len()).map(|_| None).collect::<Vec<_>>();
+    let mut pending = handles.into_iter().enumerate().collect::<Vec<_>>();
+
+    while !pending.is_empty() {
+        let mut ready = ready(pending.iter_mut().map(|entry| &mut entry.1)).into_iter();
+        let mut cursor = 0;
+        while cursor < pending.len() {
+            let i = pending[cursor].0;
+            if !ready.next().unwrap() {
+                cursor += 1;
+                continue;
+            }
+            match pending[cursor].1.resume(0) {
+                Err(err) => return Err((i, err)),
+                Ok(data) => {
+                    if pending[cursor].1.is_finished() {
+                        results[i] = Some(data);
+                        pending.remove(cursor);
+                    } else {
+                        cursor += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(results.into_iter().map(|r| r.unwrap()).collect())
+}
+
+// Which of the coroutines to resume in the next round
+#[cfg(unix)]
+fn ready<'a, K: 'a, I>(handles: I) -> Vec<bool>
+    where I: IntoIterator<Item = &'a mut Handle<K>>
+{
+    ::fd::wait_any(handles)
+}
+
+#[cfg(not(unix))]
+fn ready<'a, K: 'a, I>(handles: I) -> Vec<bool>
+    where I: IntoIterator<Item = &'a mut Handle<K>>
+{
+    handles.into_iter().map(|_| true).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use asymmetric::Coroutine;
+    use super::*;
+
+    fn counter(steps: usize, ret: usize) -> Handle {
+        Coroutine::spawn(move |coro, _| {
+            for _ in 0..steps {
+                coro.yield_with(0);
+            }
+            ret
+        })
+    }
+
+    #[test]
+    fn join_all_in_order() {
+        let results = join_all(vec![counter(5, 0), counter(0, 1), counter(2, 2)]);
+        let results = results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(results, [0, 1, 2]);
+    }
+
+    #[test]
+    fn race_cancels() {
+        let cancelled = Rc::new(Cell::new(false));
+
+        struct Guard(Rc<Cell<bool>>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let guard = Guard(cancelled.clone());
+        let slow = Coroutine::spawn(move |coro, _| {
+            let _guard = guard;
+            loop {
+                coro.yield_with(0);
+            }
+        });
+
+        let (winner, result) = race(vec![slow, counter(3, 7)]);
+        assert_eq!(winner, 1);
+        assert_eq!(result.unwrap(), 7);
+        assert!(cancelled.get());
+    }
+
+    #[test]
+    fn try_join_error() {
+        let failing = Coroutine::spawn(|coro, _| {
+            coro.yield_with(0);
+            panic!("failed");
+        });
+
+        match try_join(vec![counter(10, 0), failing]) {
+            Err((1, ::Error::Panicking(..))) => {}
+            other => panic!("unexpected {:?}", other.map_err(|(i, _)| i)),
+        }
+
+        assert_eq!(try_join(vec![counter(1, 3), counter(2, 4)]).unwrap(), [3, 4]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn join_all_sleeping() {
+        use std::time::{Duration, Instant};
+        use fd;
+
+        fn cpu_time() -> Duration {
+            let mut ts = ::libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            unsafe { ::libc::clock_gettime(::libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+            Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+        }
+
+        let sleeper = |ms| {
+            Coroutine::spawn(move |coro, _| {
+                fd::sleep(coro, Duration::from_millis(ms)).unwrap();
+                ms as usize
+            })
+        };
+
+        let (start, cpu) = (Instant::now(), cpu_time());
+        let results = join_all(vec![sleeper(100), sleeper(150)]);
+        let results = results.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(results, [100, 150]);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+        // Spinning over the sleepers would burn all of it
+        assert!(cpu_time() - cpu < Duration::from_millis(30));
+    }
+}
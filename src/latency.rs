@@ -0,0 +1,336 @@
+//! Histograms of the context switch latency
+//!
+//! Compiled in with the `latency-histogram` feature. Every thread records how long switching
+//! into a coroutine takes (`Direction::Resume`, from the resumer calling `resume` until the
+//! coroutine runs) and switching back out (`Direction::Yield`, from `yield_with` until the
+//! resumer has control again) into log-linear buckets with a relative error below 12.5%, so a
+//! regression in the switch path shows up without any external profiler.
+//!
+//! ```rust
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::latency::{self, Direction};
+//!
+//! let mut coro = Coroutine::spawn(|coro, _| loop {
+//!     coro.yield_with(0);
+//! });
+//! for _ in 0..100 {
+//!     coro.resume(0).unwrap();
+//! }
+//!
+//! let resume = latency::total(Direction::Resume);
+//! assert!(resume.count() >= 100);
+//! println!("p99 of resume: {:?}", resume.quantile(0.99));
+//! ```
+//!
+//! A thread pool worker is a thread like any other, `per_thread` lists the histograms by
+//! thread name. `dump_at_exit` prints all of them to stderr when the process exits.
+
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use heap;
+
+// Values below are exact, above they share 2^SUB_BITS buckets per power of two
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Which way a switch goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the resumer into the coroutine
+    Resume,
+    /// From the coroutine back to its resumer
+    Yield,
+}
+
+struct Recorder {
+    thread: Option<String>,
+    buckets: [Vec<AtomicU64>; 2],
+}
+
+impl Recorder {
+    fn new() -> Recorder {
+        let buckets = || (0..BUCKETS).map(|_| AtomicU64::new(0)).collect();
+        Recorder {
+            thread: thread::current().name().map(|n| n.to_owned()),
+            buckets: [buckets(), buckets()],
+        }
+    }
+
+    fn snapshot(&self, direction: Direction) -> Histogram {
+        Histogram {
+            counts: self.buckets[direction as usize]
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+// Histograms of threads which have exited are kept, they still count for the totals
+static RECORDERS: Mutex<Vec<Arc<Recorder>>> = Mutex::new(Vec::new());
+
+thread_local!(static RECORDER: Arc<Recorder> = {
+    let recorder = Arc::new(Recorder::new());
+    recorders().push(recorder.clone());
+    recorder
+});
+
+// Switch in flight on this thread
+thread_local!(static PENDING: Cell<Option<(Instant, Direction)>> = const { Cell::new(None) });
+
+// Also used at exit, where a panic would abort
+fn recorders() -> MutexGuard<'static, Vec<Arc<Recorder>>> {
+    RECORDERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+
+    let msb = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (msb - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    (msb - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+// Smallest value falling into `index`, and the width of the bucket
+fn bucket_range(index: usize) -> (u64, u64) {
+    if index < SUB_BUCKETS {
+        return (index as u64, 1);
+    }
+
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub = (index % SUB_BUCKETS) as u64;
+    ((SUB_BUCKETS as u64 + sub) << shift, 1 << shift)
+}
+
+#[inline]
+pub(crate) fn start(direction: Direction) {
+    PENDING.with(|pending| pending.set(Some((Instant::now(), direction))));
+}
+
+/// Record the switch started last on this thread, if any
+#[inline]
+pub(crate) fn finish() {
+    if let Some((started, direction)) = PENDING.with(|pending| pending.take()) {
+        let nanos = started.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+        // The recorder of the thread is set up on its first switch, inside of the coroutine
+        heap::internal(|| {
+            RECORDER.with(|recorder| {
+                recorder.buckets[direction as usize][bucket(nanos)]
+                    .fetch_add(1, Ordering::Relaxed);
+            })
+        });
+    }
+}
+
+/// Snapshot of a latency histogram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+}
+
+impl Histogram {
+    /// Number of recorded switches
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Check if nothing has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// Lower bound of the fastest switch
+    pub fn min(&self) -> Duration {
+        match self.counts.iter().position(|&c| c > 0) {
+            Some(index) => Duration::from_nanos(bucket_range(index).0),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Upper bound of the slowest switch
+    pub fn max(&self) -> Duration {
+        match self.counts.iter().rposition(|&c| c > 0) {
+            Some(index) => {
+                let (low, width) = bucket_range(index);
+                Duration::from_nanos(low.saturating_add(width - 1))
+            }
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Approximate mean, taking the middle of every bucket
+    pub fn mean(&self) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let total = self.counts
+            .iter()
+            .enumerate()
+            .map(|(index, &c)| {
+                let (low, width) = bucket_range(index);
+                c as u128 * (low as u128 + width as u128 / 2)
+            })
+            .sum::<u128>();
+        Duration::from_nanos((total / count as u128) as u64)
+    }
+
+    /// Upper bound of the latency at quantile `q` in `[0, 1]`, e.g. `0.99` for the 99th
+    /// percentile
+    pub fn quantile(&self, q: f64) -> Duration {
+        assert!((0.0..=1.0).contains(&q), "quantile out of range");
+
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_secs(0);
+        }
+
+        let rank = ((q * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &c) in self.counts.iter().enumerate() {
+            seen += c;
+            if seen >= rank {
+                let (low, width) = bucket_range(index);
+                return Duration::from_nanos(low.saturating_add(width - 1));
+            }
+        }
+        self.max()
+    }
+
+    /// Add the switches recorded in `other`
+    pub fn merge(&mut self, other: &Histogram) {
+        for (c, o) in self.counts.iter_mut().zip(&other.counts) {
+            *c += o;
+        }
+    }
+
+    fn empty() -> Histogram {
+        Histogram { counts: vec![0; BUCKETS] }
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "count={} min={:?} mean={:?} p50={:?} p99={:?} p999={:?} max={:?}",
+               self.count(),
+               self.min(),
+               self.mean(),
+               self.quantile(0.5),
+               self.quantile(0.99),
+               self.quantile(0.999),
+               self.max())
+    }
+}
+
+/// Histograms of every thread which has switched coroutines, with the thread's name
+pub fn per_thread(direction: Direction) -> Vec<(Option<String>, Histogram)> {
+    recorders()
+        .iter()
+        .map(|r| (r.thread.clone(), r.snapshot(direction)))
+        .collect()
+}
+
+/// Histogram of all threads together
+pub fn total(direction: Direction) -> Histogram {
+    let mut total = Histogram::empty();
+    for recorder in recorders().iter() {
+        total.merge(&recorder.snapshot(direction));
+    }
+    total
+}
+
+/// Forget everything recorded so far
+pub fn reset() {
+    for recorder in recorders().iter() {
+        for c in recorder.buckets.iter().flatten() {
+            c.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Write a summary line per thread and direction to `w`
+pub fn dump<W: Write>(w: &mut W) -> io::Result<()> {
+    for recorder in recorders().iter() {
+        let thread = recorder.thread.as_ref().map_or("<unnamed>", |n| &n[..]);
+        for &direction in &[Direction::Resume, Direction::Yield] {
+            let histogram = recorder.snapshot(direction);
+            if !histogram.is_empty() {
+                writeln!(w, "{} {:?}: {}", thread, direction, histogram)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Print the histograms to stderr when the process exits normally
+pub fn dump_at_exit() {
+    static REGISTER: Once = Once::new();
+
+    extern "C" fn at_exit() {
+        let _ = dump(&mut io::stderr());
+    }
+
+    REGISTER.call_once(|| unsafe {
+        libc::atexit(at_exit);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asymmetric::Coroutine;
+
+    #[test]
+    fn buckets() {
+        for nanos in (0..100_000).chain([u64::MAX / 3, u64::MAX]) {
+            let (low, width) = bucket_range(bucket(nanos));
+            assert!(low <= nanos && nanos - low < width, "{}", nanos);
+        }
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn records_switches() {
+        let thread = thread::Builder::new()
+            .name("latency::records_switches".to_owned())
+            .spawn(|| {
+                let mut coro = Coroutine::spawn(|coro, _| {
+                    for _ in 0..10 {
+                        coro.yield_with(0);
+                    }
+                    0
+                });
+                while !coro.is_finished() {
+                    coro.resume(0).unwrap();
+                }
+            })
+            .unwrap();
+        thread.join().unwrap();
+
+        let name = Some("latency::records_switches".to_owned());
+        let find = |direction| {
+            per_thread(direction).into_iter().find(|r| r.0 == name).unwrap().1
+        };
+
+        let resume = find(Direction::Resume);
+        assert_eq!(resume.count(), 11);
+        assert!(resume.min() <= resume.quantile(0.5));
+        assert!(resume.quantile(0.5) <= resume.max());
+        // The final switch out of a finished coroutine is not a yield
+        assert_eq!(find(Direction::Yield).count(), 10);
+        assert!(total(Direction::Yield).count() >= 10);
+    }
+}
@@ -25,17 +25,65 @@
 extern crate log;
 extern crate libc;
 extern crate context;
+#[cfg(feature = "macros")]
+extern crate coroutine_macros;
 
 use std::any::Any;
 use std::error;
 use std::fmt::{self, Display};
+use std::io;
 use std::panic;
 use std::thread;
 
-pub use options::Options;
+pub use options::{Options, SandboxOptions, StackSizeHeuristic, set_stack_size_heuristic};
+pub use options::{AUTO_STACK_CEILING, AUTO_STACK_FLOOR, MIN_STACK_SIZE, page_size};
+pub use compat::{sched, spawn};
+#[cfg(all(feature = "macros", unix))]
+pub use coroutine_macros::{main, test};
 
+pub mod actor;
 pub mod asymmetric;
+pub mod blocking;
+#[cfg(all(feature = "frame-pointers", target_arch = "x86_64", unix))]
+pub mod backtrace;
+pub mod compat;
+#[cfg(unix)]
+pub mod fd;
+#[cfg(feature = "fcontext")]
+pub mod fcontext;
+#[cfg(target_os = "linux")]
+pub mod fs;
+pub mod generator;
+pub mod heap;
+pub mod hooks;
+pub mod join;
+#[cfg(feature = "latency-histogram")]
+pub mod latency;
+pub mod logging;
+pub mod monitor;
+#[cfg(target_os = "linux")]
+pub mod overflow;
+#[cfg(target_os = "linux")]
+pub mod os;
+pub mod pipeline;
+pub mod rand;
+pub mod raw;
+pub mod recursion;
+pub mod registry;
+pub mod replay;
+#[cfg(unix)]
+pub mod retry;
+pub mod sim;
+pub mod stack;
+pub mod supervisor;
+pub mod sync;
+#[cfg(feature = "thread-pool")]
+pub mod thread_pool;
+#[cfg(test)]
+mod abi_tests;
+mod arena;
 mod options;
+mod watchdog;
 
 /// Return type of resuming. Ok if resume successfully with the current state,
 /// Err if resume failed with `Error`.
@@ -47,14 +95,26 @@ pub enum Error {
     Panicked,
 
     /// Coroutine is panicking, carry with the parameter of `panic!()`
-    Panicking(Box<Any + Send>),
+    Panicking(Box<dyn Any + Send>),
+
+    /// Coroutine did not yield before the deadline of `resume_timeout` and was preempted
+    Timeout,
+
+    /// Coroutine has been resumed as many times as its sandbox allows
+    SwitchLimit,
+
+    /// Coroutine has been cancelled together with the coroutine which spawned it
+    Cancelled,
+
+    /// Coroutine has overflowed its stack, see `overflow::run_with_overflow_recovery`
+    StackOverflow,
 }
 
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Error::Panicked => write!(f, "Panicked"),
-            &Error::Panicking(ref err) => {
+        match *self {
+            Error::Panicked => write!(f, "Panicked"),
+            Error::Panicking(ref err) => {
                 let msg = match err.downcast_ref::<&'static str>() {
                     Some(s) => *s,
                     None => {
@@ -66,25 +126,133 @@ impl fmt::Debug for Error {
                 };
                 write!(f, "Panicking({})", msg)
             }
+            Error::Timeout => write!(f, "Timeout"),
+            Error::SwitchLimit => write!(f, "SwitchLimit"),
+            Error::Cancelled => write!(f, "Cancelled"),
+            Error::StackOverflow => write!(f, "StackOverflow"),
         }
     }
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", error::Error::description(self))
+        match *self {
+            Error::Panicked => write!(f, "Panicked"),
+            Error::Panicking(..) => write!(f, "Panicking(..)"),
+            Error::Timeout => write!(f, "Timeout"),
+            Error::SwitchLimit => write!(f, "SwitchLimit"),
+            Error::Cancelled => write!(f, "Cancelled"),
+            Error::StackOverflow => write!(f, "StackOverflow"),
+        }
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        match self {
-            &Error::Panicked => "Panicked",
-            &Error::Panicking(..) => "Panicking(..)",
+        match *self {
+            Error::Panicked => "Panicked",
+            Error::Panicking(..) => "Panicking(..)",
+            Error::Timeout => "Timeout",
+            Error::SwitchLimit => "SwitchLimit",
+            Error::Cancelled => "Cancelled",
+            Error::StackOverflow => "StackOverflow",
         }
     }
 }
 
+/// Reason why a coroutine could not be spawned
+#[derive(Debug)]
+pub enum SpawnError {
+    /// The requested stack cannot hold the coroutine, `min` is the smallest usable size
+    StackTooSmall {
+        /// Requested stack size
+        size: usize,
+        /// Minimum stack size for this coroutine
+        min: usize,
+    },
+
+    /// The requested stack is larger than the system allows
+    StackTooLarge {
+        /// Requested stack size
+        size: usize,
+        /// Maximum stack size
+        max: usize,
+    },
+
+    /// Spawning would exceed the limit set by `stack::set_limit`
+    StackLimit {
+        /// Requested stack size
+        size: usize,
+        /// Bytes left below the limit
+        available: usize,
+    },
+
+    /// Spawning would exceed the limit set by `asymmetric::set_max_live`
+    CoroutineLimit {
+        /// Maximum number of live coroutines
+        limit: usize,
+    },
+
+    /// The stack could not be mapped
+    Io(io::Error),
+
+    /// The sandbox of the current coroutine denies spawning
+    Denied,
+}
+
+impl Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpawnError::StackTooSmall { size, min } => {
+                write!(f, "stack of {} bytes is too small, at least {} are needed", size, min)
+            }
+            SpawnError::StackTooLarge { size, max } => {
+                write!(f, "stack of {} bytes exceeds the maximum of {}", size, max)
+            }
+            SpawnError::StackLimit { size, available } => {
+                write!(f,
+                       "stack of {} bytes exceeds the stack limit, {} bytes are available",
+                       size,
+                       available)
+            }
+            SpawnError::CoroutineLimit { limit } => {
+                write!(f, "the limit of {} live coroutines has been reached", limit)
+            }
+            SpawnError::Io(ref err) => write!(f, "failed to acquire stack: {}", err),
+            SpawnError::Denied => write!(f, "spawning coroutines is denied in this sandbox"),
+        }
+    }
+}
+
+impl error::Error for SpawnError {
+    fn description(&self) -> &str {
+        match *self {
+            SpawnError::StackTooSmall { .. } => "StackTooSmall",
+            SpawnError::StackTooLarge { .. } => "StackTooLarge",
+            SpawnError::StackLimit { .. } => "StackLimit",
+            SpawnError::CoroutineLimit { .. } => "CoroutineLimit",
+            SpawnError::Io(..) => "Io",
+            SpawnError::Denied => "Denied",
+        }
+    }
+}
+
+/// A `with_deadline` section did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl error::Error for Elapsed {
+    fn description(&self) -> &str {
+        "Elapsed"
+    }
+}
+
 unsafe fn try<R, F: FnOnce() -> R>(f: F) -> thread::Result<R> {
     let mut f = Some(f);
     let f = &mut f as *mut Option<F> as usize;
@@ -0,0 +1,241 @@
+//! Log records tagged with the coroutine they are emitted from
+//!
+//! The `coro_error!`, `coro_warn!`, `coro_info!`, `coro_debug!` and `coro_trace!` macros take
+//! the same arguments as their `log` counterparts and prefix the message with the name and id
+//! of the coroutine running on the current thread, `[name#id]`, or `[#id]` for unnamed
+//! coroutines. Outside of coroutines the message is logged unchanged.
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate coroutine;
+//!
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::logging;
+//!
+//! fn main() {
+//!     let mut coro = Coroutine::spawn(|coro, _| {
+//!         coro_info!("handling request {}", 42);  // "[#<id>] handling request 42"
+//!         logging::context().unwrap().id
+//!     });
+//!     let id = coro.id();
+//!     assert_eq!(coro.resume(0).unwrap(), id);
+//!     assert!(logging::context().is_none());
+//! }
+//! ```
+//!
+//! Panics inside of coroutines are reported on stderr by the panic hook like any other, unless
+//! `set_panic_logging` routes them to `log` instead, as errors with the target
+//! `coroutine::panic`.
+
+use std::fmt;
+use std::panic::{self, PanicHookInfo};
+use std::sync::Once;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log;
+
+#[doc(hidden)]
+pub use log::{LogLevel, LogLocation};
+
+use asymmetric;
+
+/// Identifies the coroutine a record has been emitted from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogContext {
+    /// Id of the coroutine
+    pub id: usize,
+    /// Name of the coroutine
+    pub name: Option<String>,
+}
+
+impl fmt::Display for LogContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{}#{}", name, self.id),
+            None => write!(f, "#{}", self.id),
+        }
+    }
+}
+
+/// Context of the coroutine running on the current thread, `None` outside of coroutines
+pub fn context() -> Option<LogContext> {
+    asymmetric::with_current(|current| {
+        current.map(|coro| {
+            LogContext {
+                id: coro.id(),
+                name: coro.name().cloned(),
+            }
+        })
+    })
+}
+
+static LOG_PANICS: AtomicBool = AtomicBool::new(false);
+static INSTALL_HOOK: Once = Once::new();
+
+/// Report panics inside of coroutines through `log` rather than on stderr
+///
+/// The first call installs a panic hook which takes over from the one installed before.
+/// Panics outside of coroutines, and all panics while this is off, still go to that one.
+///
+/// ```rust
+/// extern crate coroutine;
+/// extern crate log;
+///
+/// use std::sync::{Arc, Mutex};
+/// use log::{Log, LogLevelFilter, LogMetadata, LogRecord};
+/// use coroutine::asymmetric::Coroutine;
+/// use coroutine::logging;
+///
+/// struct Capture(Arc<Mutex<Vec<String>>>);
+///
+/// impl Log for Capture {
+///     fn enabled(&self, _: &LogMetadata) -> bool {
+///         true
+///     }
+///
+///     fn log(&self, record: &LogRecord) {
+///         if record.target() == "coroutine::panic" {
+///             self.0.lock().unwrap().push(record.args().to_string());
+///         }
+///     }
+/// }
+///
+/// fn main() {
+///     let records = Arc::new(Mutex::new(Vec::new()));
+///     let capture = Capture(records.clone());
+///     log::set_logger(move |max| {
+///         max.set(LogLevelFilter::Error);
+///         Box::new(capture)
+///     }).unwrap();
+///
+///     logging::set_panic_logging(true);
+///     let mut coro = Coroutine::spawn(|_, _| panic!("no stderr"));
+///     assert!(coro.resume(0).is_err());
+///     assert!(records.lock().unwrap()[0].ends_with("no stderr"));
+/// }
+/// ```
+pub fn set_panic_logging(enabled: bool) {
+    LOG_PANICS.store(enabled, Ordering::Relaxed);
+    if enabled {
+        INSTALL_HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                if !LOG_PANICS.load(Ordering::Relaxed) || !log_panic(info) {
+                    previous(info);
+                }
+            }));
+        });
+    }
+}
+
+/// Whether panics inside of coroutines are logged, see `set_panic_logging`
+pub fn panic_logging() -> bool {
+    LOG_PANICS.load(Ordering::Relaxed)
+}
+
+// Returns whether the panic has been logged, which it is only inside of coroutines
+fn log_panic(info: &PanicHookInfo) -> bool {
+    let ctx = match context() {
+        Some(ctx) => ctx,
+        None => return false,
+    };
+
+    let payload = info.payload();
+    let msg = match payload.downcast_ref::<&'static str>() {
+        Some(s) => *s,
+        None => {
+            match payload.downcast_ref::<String>() {
+                Some(s) => &s[..],
+                None => "Box<Any>",
+            }
+        }
+    };
+    match info.location() {
+        Some(location) => {
+            error!(target: "coroutine::panic", "[{}] panicked at {}: {}", ctx, location, msg)
+        }
+        None => error!(target: "coroutine::panic", "[{}] panicked: {}", ctx, msg),
+    }
+    true
+}
+
+#[doc(hidden)]
+pub fn __log(level: LogLevel, target: &str, loc: &LogLocation, args: fmt::Arguments) {
+    if level > log::max_log_level() {
+        return;
+    }
+
+    match context() {
+        Some(ctx) => log::__log(level, target, loc, format_args!("[{}] {}", ctx, args)),
+        None => log::__log(level, target, loc, args),
+    }
+}
+
+/// Log through `log` with the context of the current coroutine
+#[macro_export]
+macro_rules! coro_log {
+    (target: $target:expr, $lvl:expr, $($arg:tt)+) => ({
+        static _LOC: $crate::logging::LogLocation = $crate::logging::LogLocation {
+            __line: line!(),
+            __file: file!(),
+            __module_path: module_path!(),
+        };
+        $crate::logging::__log($lvl, $target, &_LOC, format_args!($($arg)+))
+    });
+    ($lvl:expr, $($arg:tt)+) => ($crate::coro_log!(target: module_path!(), $lvl, $($arg)+))
+}
+
+/// `error!` with the context of the current coroutine
+#[macro_export]
+macro_rules! coro_error {
+    ($($arg:tt)+) => ($crate::coro_log!($crate::logging::LogLevel::Error, $($arg)+))
+}
+
+/// `warn!` with the context of the current coroutine
+#[macro_export]
+macro_rules! coro_warn {
+    ($($arg:tt)+) => ($crate::coro_log!($crate::logging::LogLevel::Warn, $($arg)+))
+}
+
+/// `info!` with the context of the current coroutine
+#[macro_export]
+macro_rules! coro_info {
+    ($($arg:tt)+) => ($crate::coro_log!($crate::logging::LogLevel::Info, $($arg)+))
+}
+
+/// `debug!` with the context of the current coroutine
+#[macro_export]
+macro_rules! coro_debug {
+    ($($arg:tt)+) => ($crate::coro_log!($crate::logging::LogLevel::Debug, $($arg)+))
+}
+
+/// `trace!` with the context of the current coroutine
+#[macro_export]
+macro_rules! coro_trace {
+    ($($arg:tt)+) => ($crate::coro_log!($crate::logging::LogLevel::Trace, $($arg)+))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asymmetric::Coroutine;
+    use options::Options;
+
+    #[test]
+    fn context_inside() {
+        let opts = Options {
+            name: Some("worker".to_owned()),
+            ..Options::default()
+        };
+        let mut coro = Coroutine::spawn_opts(|coro, _| {
+            coro_debug!("inside {}", "coroutine");
+            let ctx = context().unwrap();
+            assert_eq!(ctx.id, coro.id());
+            assert_eq!(ctx.to_string(), format!("worker#{}", coro.id()));
+            0
+        }, opts);
+
+        coro.resume(0).unwrap();
+        assert_eq!(context(), None);
+    }
+}
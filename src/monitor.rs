@@ -0,0 +1,200 @@
+//! Detection of coroutines which stay parked or suspended for too long
+//!
+//! Tracking is opt-in: after `enable`, newly spawned coroutines record when they last yielded
+//! and why, and `stalled` lists the ones which have not been resumed for longer than a
+//! threshold. `watch` runs that sweep periodically on a background thread, which is a cheap way
+//! to surface leaked coroutines and lost wakeups in long-running servers.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::monitor;
+//!
+//! monitor::enable();
+//!
+//! let mut coro = Coroutine::spawn(|coro, _| coro.park_with_reason(0, "waiting for input"));
+//! coro.resume(0).unwrap();
+//!
+//! let stalled = monitor::stalled(Duration::from_secs(0));
+//! assert!(stalled.iter().any(|s| s.reason == Some("waiting for input")));
+//! ```
+
+use std::panic::Location;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use asymmetric::State;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static RECORDS: Mutex<Vec<Weak<Mutex<Record>>>> = Mutex::new(Vec::new());
+
+/// What a tracked coroutine was last seen doing
+#[derive(Debug)]
+pub struct Record {
+    name: Option<String>,
+    location: &'static Location<'static>,
+    state: State,
+    reason: Option<&'static str>,
+    since: Instant,
+}
+
+impl Record {
+    pub(crate) fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_owned());
+    }
+
+    pub(crate) fn set_reason(&mut self, reason: &'static str) {
+        self.reason = Some(reason);
+    }
+
+    pub(crate) fn update(&mut self, state: State) {
+        if state == State::Running {
+            self.reason = None;
+        }
+        self.state = state;
+        self.since = Instant::now();
+    }
+}
+
+/// Start tracking the coroutines spawned from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Stop tracking newly spawned coroutines, those already tracked stay tracked
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+pub(crate) fn track(name: Option<&String>,
+                    location: &'static Location<'static>)
+                    -> Option<Arc<Mutex<Record>>> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let record = Arc::new(Mutex::new(Record {
+        name: name.cloned(),
+        location,
+        state: State::Suspended,
+        reason: None,
+        since: Instant::now(),
+    }));
+
+    let mut records = RECORDS.lock().unwrap();
+    records.retain(|r| r.strong_count() > 0);
+    records.push(Arc::downgrade(&record));
+    Some(record)
+}
+
+/// A coroutine which has not been resumed for longer than the threshold
+#[derive(Debug, Clone)]
+pub struct Stalled {
+    /// Name of the coroutine
+    pub name: Option<String>,
+    /// Where it has been spawned
+    pub spawn_location: &'static Location<'static>,
+    /// `Suspended` or `Parked`
+    pub state: State,
+    /// Reason given to `park_with_reason`
+    pub reason: Option<&'static str>,
+    /// How long it has been waiting
+    pub duration: Duration,
+}
+
+/// Tracked coroutines which have been suspended or parked for at least `threshold`
+pub fn stalled(threshold: Duration) -> Vec<Stalled> {
+    let now = Instant::now();
+    let records = RECORDS.lock().unwrap();
+
+    records.iter()
+        .filter_map(|r| r.upgrade())
+        .filter_map(|record| {
+            let record = record.lock().unwrap();
+            let duration = now.saturating_duration_since(record.since);
+            let waiting = matches!(record.state, State::Suspended | State::Parked);
+
+            if waiting && duration >= threshold {
+                Some(Stalled {
+                    name: record.name.clone(),
+                    spawn_location: record.location,
+                    state: record.state,
+                    reason: record.reason,
+                    duration,
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Sweep every `interval` on a background thread and pass the coroutines stalled for longer
+/// than `threshold` to `report`, tracking is enabled as well
+pub fn watch<F>(interval: Duration, threshold: Duration, mut report: F)
+    where F: FnMut(&[Stalled]) + Send + 'static
+{
+    enable();
+    thread::Builder::new()
+        .name("coroutine-monitor".to_owned())
+        .spawn(move || {
+            loop {
+                thread::sleep(interval);
+                let stalled = stalled(threshold);
+                if !stalled.is_empty() {
+                    report(&stalled);
+                }
+            }
+        })
+        .expect("failed to spawn the monitor thread");
+}
+
+/// `watch` reporting through the `log` facade
+pub fn watch_and_log(interval: Duration, threshold: Duration) {
+    watch(interval, threshold, |stalled| {
+        for s in stalled {
+            warn!("Coroutine `{}` (spawned at {}): {:?} for {:?}, reason: {}",
+                  s.name.as_ref().map_or("<unnamed>", |n| &n[..]),
+                  s.spawn_location,
+                  s.state,
+                  s.duration,
+                  s.reason.unwrap_or("unknown"));
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asymmetric::Coroutine;
+
+    #[test]
+    fn reports_parked() {
+        enable();
+
+        let mut coro = Coroutine::spawn(|coro, _| {
+            coro.park_with_reason(0, "monitor test");
+            0
+        });
+        coro.set_name("parked".to_owned());
+        coro.resume(0).unwrap();
+
+        let found = stalled(Duration::from_secs(0))
+            .into_iter()
+            .find(|s| s.reason == Some("monitor test"))
+            .unwrap();
+        assert_eq!(found.name, Some("parked".to_owned()));
+        assert_eq!(found.state, State::Parked);
+
+        assert!(stalled(Duration::from_secs(3600))
+            .iter()
+            .all(|s| s.reason != Some("monitor test")));
+
+        coro.resume(0).unwrap();
+        assert!(stalled(Duration::from_secs(0))
+            .iter()
+            .all(|s| s.reason != Some("monitor test")));
+    }
+}
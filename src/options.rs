@@ -1,15 +1,107 @@
 //! Coroutine options
 
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+#[cfg(unix)]
+use libc;
+
 const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024; // 2M
 
+/// Size of the stack shared by the coroutines spawned with `Options::shared_stack`
+pub(crate) const SHARED_STACK_SIZE: usize = DEFAULT_STACK_SIZE;
+
+/// Smallest stack a coroutine can be spawned with, not counting the size of its closure
+///
+/// Requested sizes are rounded up to a multiple of `page_size`, and a guard page is mapped
+/// below every stack on top of that.
+pub const MIN_STACK_SIZE: usize = 8 * 1024; // 8K
+
+/// Smallest stack `Options::stack_size_auto` picks
+pub const AUTO_STACK_FLOOR: usize = 16 * 1024; // 16K
+
+/// Largest stack `Options::stack_size_auto` picks
+pub const AUTO_STACK_CEILING: usize = 8 * 1024 * 1024; // 8M
+
+// Unoptimized code needs much bigger frames
+#[cfg(debug_assertions)]
+const AUTO_STACK_BASE: usize = 256 * 1024; // 256K
+#[cfg(not(debug_assertions))]
+const AUTO_STACK_BASE: usize = 64 * 1024; // 64K
+
+/// Maps the size of a coroutine closure to a stack size
+pub type StackSizeHeuristic = fn(usize) -> usize;
+
+static HEURISTIC: Mutex<Option<StackSizeHeuristic>> = Mutex::new(None);
+
+/// Replace the heuristic behind `Options::stack_size_auto`
+///
+/// The callback receives the size of the coroutine closure in bytes and returns a stack size,
+/// which is still clamped to `AUTO_STACK_FLOOR..=AUTO_STACK_CEILING`. `None` restores the
+/// default heuristic.
+pub fn set_stack_size_heuristic(heuristic: Option<StackSizeHeuristic>) {
+    *HEURISTIC.lock().unwrap() = heuristic;
+}
+
+/// Size of a memory page, stack sizes are always a multiple of it
+pub fn page_size() -> usize {
+    static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+    match PAGE_SIZE.load(Ordering::Relaxed) {
+        0 => {
+            let size = sys_page_size().unwrap_or(4096);
+            PAGE_SIZE.store(size, Ordering::Relaxed);
+            size
+        }
+        size => size,
+    }
+}
+
+#[cfg(unix)]
+fn sys_page_size() -> Option<usize> {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => Some(size as usize),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn sys_page_size() -> Option<usize> {
+    None
+}
+
+/// Round `size` up to a multiple of the page size
+pub(crate) fn round_to_page(size: usize) -> usize {
+    let page = page_size();
+    size.div_ceil(page) * page
+}
+
+/// Stack size for a coroutine with a closure of `closure_size` bytes
+pub(crate) fn auto_stack_size(closure_size: usize) -> usize {
+    let size = match *HEURISTIC.lock().unwrap() {
+        Some(heuristic) => heuristic(closure_size),
+        // The closure is stored on the stack
+        None => AUTO_STACK_BASE + closure_size,
+    };
+    size.clamp(AUTO_STACK_FLOOR, AUTO_STACK_CEILING)
+}
+
 /// Coroutine spawn options
 #[derive(Debug)]
 pub struct Options {
-    /// The size of the stack
+    /// The size of the stack, `0` lets the size be picked at spawn, see `stack_size_auto`
     pub stack_size: usize,
 
     /// The name of the Coroutine
     pub name: Option<String>,
+
+    /// Resource caps for running untrusted code, unrestricted if `None`
+    pub sandbox: Option<SandboxOptions>,
+
+    /// Run on a stack shared with the other such coroutines of the thread, see
+    /// `asymmetric::Coroutine::spawn_opts`. `stack_size` is ignored then.
+    pub shared_stack: bool,
 }
 
 impl Default for Options {
@@ -17,6 +109,71 @@ impl Default for Options {
         Options {
             stack_size: DEFAULT_STACK_SIZE,
             name: None,
+            sandbox: None,
+            shared_stack: false,
+        }
+    }
+}
+
+impl Options {
+    /// Options with a stack size picked at spawn
+    ///
+    /// The default heuristic takes 256K in debug builds and 64K in release builds, plus the
+    /// size of the closure, within `AUTO_STACK_FLOOR` and `AUTO_STACK_CEILING`. It can be
+    /// replaced with `set_stack_size_heuristic`.
+    pub fn stack_size_auto() -> Options {
+        Options {
+            stack_size: 0,
+            ..Options::default()
         }
     }
 }
+
+/// Restrictions enforced on a sandboxed coroutine
+#[derive(Debug, Clone)]
+pub struct SandboxOptions {
+    /// Maximum number of times the coroutine may be resumed, further resumes
+    /// fail with `Error::SwitchLimit`
+    pub max_switches: Option<usize>,
+
+    /// Deadline applied to every resume, see `Handle::resume_timeout`
+    pub max_slice: Option<Duration>,
+
+    /// Upper bound of the stack size, larger `Options::stack_size` are clamped to it
+    pub max_stack_size: usize,
+
+    /// Fail spawning from inside of the coroutine with `SpawnError::Denied`, which makes
+    /// `Coroutine::spawn` panic
+    pub deny_spawn: bool,
+}
+
+impl Default for SandboxOptions {
+    fn default() -> SandboxOptions {
+        SandboxOptions {
+            max_switches: None,
+            max_slice: None,
+            max_stack_size: DEFAULT_STACK_SIZE,
+            deny_spawn: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn page_rounding() {
+        let page = page_size();
+        assert!(page.is_power_of_two());
+        assert_eq!(round_to_page(1), page);
+        assert_eq!(round_to_page(page), page);
+        assert_eq!(round_to_page(page + 1), 2 * page);
+    }
+
+    #[test]
+    fn auto_stack_size_bounds() {
+        assert_eq!(auto_stack_size(0), AUTO_STACK_BASE);
+        assert_eq!(auto_stack_size(usize::MAX / 2), AUTO_STACK_CEILING);
+    }
+}
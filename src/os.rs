@@ -0,0 +1,239 @@
+//! Linux timer and notification file descriptors whose `read` parks the coroutine
+//!
+//! `TimerFd` wraps `timerfd_create(2)` and `EventFd` wraps `eventfd(2)`. Both are nonblocking,
+//! reading waits in `fd::wait_readable`, so the coroutine is woken up by whatever drives it
+//! with `fd::run` or its own event loop.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::fd;
+//! use coroutine::os::TimerFd;
+//!
+//! let mut coro = Coroutine::spawn(|coro, _| {
+//!     let timer = TimerFd::new().unwrap();
+//!     timer.set(Duration::from_millis(1), None).unwrap();
+//!     timer.read(coro).unwrap() as usize
+//! });
+//! assert_eq!(fd::run(&mut coro).unwrap(), 1);
+//! ```
+
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+use libc;
+
+use asymmetric::Coroutine;
+use fd::{self, Trigger};
+
+// Both hand out a native endian u64 counter per read
+fn read_counter(fd: RawFd, coro: &mut Coroutine) -> io::Result<u64> {
+    loop {
+        let mut value = 0u64;
+        let n = unsafe { libc::read(fd, &mut value as *mut u64 as *mut _, 8) };
+        if n == 8 {
+            return Ok(value);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => {
+                fd::wait_readable(coro, fd, Trigger::Edge)?;
+            }
+            io::ErrorKind::Interrupted => {}
+            _ => return Err(err),
+        }
+    }
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
+    }
+}
+
+/// Timer on the monotonic clock
+#[derive(Debug)]
+pub struct TimerFd {
+    fd: RawFd,
+}
+
+impl TimerFd {
+    /// Create a disarmed timer
+    pub fn new() -> io::Result<TimerFd> {
+        let fd = unsafe {
+            libc::timerfd_create(libc::CLOCK_MONOTONIC,
+                                 libc::TFD_CLOEXEC | libc::TFD_NONBLOCK)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(TimerFd { fd })
+    }
+
+    /// Fire once after `after`, then every `interval` if there is one
+    ///
+    /// Replaces the previous setting, expirations which have not been read yet are dropped.
+    pub fn set(&self, after: Duration, interval: Option<Duration>) -> io::Result<()> {
+        // Zero would disarm the timer
+        let after = after.max(Duration::from_nanos(1));
+        self.settime(timespec(after), timespec(interval.unwrap_or_default()))
+    }
+
+    /// Stop the timer
+    pub fn disarm(&self) -> io::Result<()> {
+        self.settime(timespec(Duration::from_secs(0)), timespec(Duration::from_secs(0)))
+    }
+
+    fn settime(&self, value: libc::timespec, interval: libc::timespec) -> io::Result<()> {
+        let spec = libc::itimerspec {
+            it_interval: interval,
+            it_value: value,
+        };
+        if unsafe { libc::timerfd_settime(self.fd, 0, &spec, ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Time left until the timer fires next, `None` if it is disarmed
+    pub fn remaining(&self) -> io::Result<Option<Duration>> {
+        let mut spec: libc::itimerspec = unsafe { mem::zeroed() };
+        if unsafe { libc::timerfd_gettime(self.fd, &mut spec) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let left = Duration::new(spec.it_value.tv_sec as u64, spec.it_value.tv_nsec as u32);
+        Ok(if left == Duration::from_secs(0) {
+            None
+        } else {
+            Some(left)
+        })
+    }
+
+    /// Park `coro` until the timer has fired, returns how many times it did since the last read
+    pub fn read(&self, coro: &mut Coroutine) -> io::Result<u64> {
+        read_counter(self.fd, coro)
+    }
+}
+
+impl AsRawFd for TimerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for TimerFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Counter which wakes up the coroutine reading it, may be written from any thread
+#[derive(Debug)]
+pub struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    /// Create a counter starting at `initial`
+    pub fn new(initial: u32) -> io::Result<EventFd> {
+        let fd = unsafe { libc::eventfd(initial, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd { fd })
+    }
+
+    /// Add `n` to the counter
+    ///
+    /// Never blocks, fails with `WouldBlock` if the counter would overflow.
+    pub fn write(&self, n: u64) -> io::Result<()> {
+        loop {
+            if unsafe { libc::write(self.fd, &n as *const u64 as *const _, 8) } == 8 {
+                return Ok(());
+            }
+
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Park `coro` until the counter is not zero, then return it and reset it to zero
+    pub fn read(&self, coro: &mut Coroutine) -> io::Result<u64> {
+        read_counter(self.fd, coro)
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn timer() {
+        let timer = TimerFd::new().unwrap();
+        assert_eq!(timer.remaining().unwrap(), None);
+        timer.set(Duration::from_secs(3600), None).unwrap();
+        assert!(timer.remaining().unwrap().unwrap() > Duration::from_secs(3500));
+        timer.disarm().unwrap();
+        assert_eq!(timer.remaining().unwrap(), None);
+
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            timer.set(Duration::from_millis(1), Some(Duration::from_millis(1))).unwrap();
+            let mut fired = 0;
+            while fired < 5 {
+                fired += timer.read(coro).unwrap();
+            }
+            1
+        });
+        assert_eq!(fd::run(&mut coro).unwrap(), 1);
+    }
+
+    #[test]
+    fn event() {
+        let event = Arc::new(EventFd::new(1).unwrap());
+
+        let writer = event.clone();
+        let mut coro = Coroutine::spawn(move |coro, _| {
+            let mut total = event.read(coro).unwrap();
+            while total < 101 {
+                total += event.read(coro).unwrap();
+            }
+            total as usize
+        });
+
+        thread::spawn(move || {
+                for _ in 0..100 {
+                    writer.write(1).unwrap();
+                }
+            })
+            .join()
+            .unwrap();
+        assert_eq!(fd::run(&mut coro).unwrap(), 101);
+    }
+}
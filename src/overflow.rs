@@ -0,0 +1,223 @@
+//! Recovering from stack overflows inside of coroutines
+//!
+//! A coroutine which runs past the end of its stack hits the guard page below it, which
+//! normally kills the process. Coroutines resumed inside of `run_with_overflow_recovery` are
+//! switched back to their resumer instead, whose `resume` fails with `Error::StackOverflow`.
+//! The frames of such a coroutine are abandoned, not unwound: whatever they own is leaked and
+//! the stack is unmapped when the handle is dropped.
+//!
+//! ```rust
+//! use std::hint;
+//! use coroutine::{Error, Options};
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::overflow;
+//!
+//! fn deeper(n: usize) -> usize {
+//!     let frame = hint::black_box([n as u8; 512]);
+//!     if n == usize::MAX { 0 } else { deeper(n + 1) + frame[0] as usize }
+//! }
+//!
+//! overflow::run_with_overflow_recovery(|| {
+//!     let opts = Options { stack_size: 64 * 1024, ..Options::default() };
+//!     let mut coro = Coroutine::spawn_opts(|_, _| deeper(0), opts);
+//!     match coro.resume(0) {
+//!         Err(Error::StackOverflow) => {}
+//!         _ => panic!("did not overflow"),
+//!     }
+//!     assert!(coro.is_finished());
+//! });
+//! ```
+//!
+//! Faults are caught by a `SIGSEGV` handler running on an alternate signal stack, the thread
+//! gets one unless it already has one. Faults outside of the guard page of a coroutine, and
+//! those outside of `run_with_overflow_recovery`, go to the handler which was installed
+//! before. Coroutines on shared stacks are not covered.
+
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::ptr;
+use std::sync::OnceLock;
+
+use libc;
+
+use asymmetric;
+
+const ALT_STACK_SIZE: usize = 64 * 1024;
+
+// The handlers which were installed for SIGSEGV and SIGBUS before ours
+static PREVIOUS: OnceLock<[libc::sigaction; 2]> = OnceLock::new();
+
+thread_local!(static RECOVERING: Cell<usize> = const { Cell::new(0) });
+thread_local!(static ALT_STACK: RefCell<Option<AltStack>> = const { RefCell::new(None) });
+
+// Mapped for a thread which had no alternate signal stack of its own
+struct AltStack {
+    base: *mut libc::c_void,
+}
+
+impl Drop for AltStack {
+    fn drop(&mut self) {
+        unsafe {
+            let disable = libc::stack_t {
+                ss_sp: ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: ALT_STACK_SIZE,
+            };
+            libc::sigaltstack(&disable, ptr::null_mut());
+            libc::munmap(self.base, ALT_STACK_SIZE);
+        }
+    }
+}
+
+/// Run `f`, turning stack overflows of the coroutines it resumes on this thread into
+/// `Err(Error::StackOverflow)` of their `resume`
+///
+/// Sections nest. Workers of a thread pool have to enter one of their own.
+pub fn run_with_overflow_recovery<R, F>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    struct Leave;
+
+    impl Drop for Leave {
+        fn drop(&mut self) {
+            RECOVERING.with(|depth| depth.set(depth.get() - 1));
+        }
+    }
+
+    install();
+    ensure_alt_stack();
+
+    RECOVERING.with(|depth| depth.set(depth.get() + 1));
+    let _leave = Leave;
+    f()
+}
+
+fn install() {
+    PREVIOUS.get_or_init(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handler as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: [libc::sigaction; 2] = mem::zeroed();
+        libc::sigaction(libc::SIGSEGV, &action, &mut previous[0]);
+        libc::sigaction(libc::SIGBUS, &action, &mut previous[1]);
+        previous
+    });
+}
+
+fn ensure_alt_stack() {
+    unsafe {
+        let mut current: libc::stack_t = mem::zeroed();
+        libc::sigaltstack(ptr::null(), &mut current);
+        if current.ss_flags & libc::SS_DISABLE == 0 {
+            return;
+        }
+
+        let base = libc::mmap(ptr::null_mut(),
+                              ALT_STACK_SIZE,
+                              libc::PROT_READ | libc::PROT_WRITE,
+                              libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                              -1,
+                              0);
+        if base == libc::MAP_FAILED {
+            warn!("Failed to map an alternate signal stack, stack overflows stay fatal");
+            return;
+        }
+
+        let stack = libc::stack_t {
+            ss_sp: base,
+            ss_flags: 0,
+            ss_size: ALT_STACK_SIZE,
+        };
+        libc::sigaltstack(&stack, ptr::null_mut());
+        ALT_STACK.with(|alt| *alt.borrow_mut() = Some(AltStack { base }));
+    }
+}
+
+extern "C" fn handler(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    unsafe {
+        if RECOVERING.with(|depth| depth.get()) > 0 {
+            // Does not return if the coroutine has overflowed
+            asymmetric::recover_overflow((*info).si_addr() as usize);
+        }
+        chain(signal, info, ctx);
+    }
+}
+
+unsafe fn chain(signal: libc::c_int, info: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    let previous = match PREVIOUS.get() {
+        Some(previous) if signal == libc::SIGSEGV => previous[0],
+        Some(previous) => previous[1],
+        None => return,
+    };
+
+    match previous.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // Fault again with the default action once the handler returns
+            let mut default: libc::sigaction = mem::zeroed();
+            default.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(signal, &default, ptr::null_mut());
+        }
+        f if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let f = mem::transmute::<libc::sighandler_t,
+                                     extern "C" fn(libc::c_int,
+                                                   *mut libc::siginfo_t,
+                                                   *mut libc::c_void)>(f);
+            f(signal, info, ctx)
+        }
+        f => {
+            let f = mem::transmute::<libc::sighandler_t, extern "C" fn(libc::c_int)>(f);
+            f(signal)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hint;
+    use std::thread;
+
+    use asymmetric::Coroutine;
+    use options::Options;
+    use super::*;
+
+    fn deeper(n: usize) -> usize {
+        let frame = hint::black_box([n as u8; 512]);
+        if n == usize::MAX {
+            0
+        } else {
+            deeper(n + 1) + frame[0] as usize
+        }
+    }
+
+    #[test]
+    fn recovers() {
+        // On a thread of its own, which gets an alternate signal stack of ours
+        thread::spawn(|| {
+                run_with_overflow_recovery(|| {
+                    let opts = || {
+                        Options {
+                            stack_size: 64 * 1024,
+                            ..Options::default()
+                        }
+                    };
+                    for _ in 0..3 {
+                        let mut coro = Coroutine::spawn_opts(|_, _| deeper(0), opts());
+                        match coro.resume(0) {
+                            Err(::Error::StackOverflow) => {}
+                            other => panic!("did not overflow: {:?}", other),
+                        }
+                        assert!(coro.is_finished());
+                    }
+
+                    // Coroutines which do not overflow are not affected
+                    let mut coro = Coroutine::spawn_opts(|coro, _| coro.yield_with(1), opts());
+                    assert_eq!(coro.resume(0).unwrap(), 1);
+                    assert_eq!(coro.resume(2).unwrap(), 2);
+                });
+            })
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+//! Pipelines of coroutines connected as processing stages
+//!
+//! Every stage runs in its own coroutine and pulls its input from the stage before it, so a
+//! stage only runs when the stage after it asks for an item: a fast source can never run ahead
+//! of a slow sink. A panic inside any stage propagates down to whoever drives the pipeline.
+//!
+//! ```rust
+//! use coroutine::pipeline;
+//!
+//! let total: u32 = pipeline::source(|y| {
+//!         for i in 0..10 {
+//!             y.yield_(i);
+//!         }
+//!     })
+//!     .stage(|input, y| {
+//!         for i in input.filter(|i| i % 2 == 0) {
+//!             y.yield_(i * 10);
+//!         }
+//!     })
+//!     .sink(|input| input.sum());
+//!
+//! assert_eq!(total, 200);
+//! ```
+
+use generator::{Gen, Yielder};
+
+/// Yields the output items of a stage
+pub type Output<T> = Yielder<T, (), ()>;
+
+/// A pipeline stage producing items of type `T`
+///
+/// Iterating over it pulls items through all the stages before it.
+pub struct Stage<T> {
+    gen: Gen<T, (), ()>,
+}
+
+/// Start a pipeline with a stage which has no input
+#[track_caller]
+pub fn source<T, F>(f: F) -> Stage<T>
+    where T: 'static,
+          F: FnOnce(&mut Output<T>) + 'static
+{
+    Stage { gen: Gen::new(move |y, ()| f(y)) }
+}
+
+impl<T: 'static> Stage<T> {
+    /// Append a stage consuming the items of this one
+    #[track_caller]
+    pub fn stage<U, F>(self, f: F) -> Stage<U>
+        where U: 'static,
+              F: FnOnce(&mut Stage<T>, &mut Output<U>) + 'static
+    {
+        let mut input = self;
+        Stage { gen: Gen::new(move |y, ()| f(&mut input, y)) }
+    }
+
+    /// Finish the pipeline by consuming its items on the current thread
+    pub fn sink<R, F>(self, f: F) -> R
+        where F: FnOnce(Stage<T>) -> R
+    {
+        f(self)
+    }
+}
+
+impl<T> Iterator for Stage<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.gen.next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn backpressure() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let source_log = log.clone();
+        let sink_log = log.clone();
+        let stage = source(move |y| {
+            for i in 0..3 {
+                source_log.borrow_mut().push(format!("produce {}", i));
+                y.yield_(i);
+            }
+        });
+
+        stage.sink(move |input| {
+            for i in input {
+                sink_log.borrow_mut().push(format!("consume {}", i));
+            }
+        });
+
+        assert_eq!(*log.borrow(),
+                   ["produce 0", "consume 0", "produce 1", "consume 1", "produce 2", "consume 2"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "bad item")]
+    fn propagate_panic() {
+        source(|y| {
+                y.yield_(1);
+                panic!("bad item");
+            })
+            .stage(|input, y| {
+                for i in input {
+                    y.yield_(i);
+                }
+            })
+            .sink(|input| input.count());
+    }
+}
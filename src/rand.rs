@@ -0,0 +1,224 @@
+//! Deterministic random numbers per coroutine
+//!
+//! Every coroutine owns a generator seeded from a process-wide master seed and its id. As ids
+//! are handed out in spawn order, a simulation which spawns its coroutines in the same order
+//! draws exactly the same numbers on every run, whatever the interleaving of other threads
+//! looks like as long as they do not spawn in between.
+//!
+//! ```rust
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::rand;
+//!
+//! rand::set_seed(42);
+//!
+//! let mut coro = Coroutine::spawn(|_, _| rand::local_rng().gen_range(0, 6) as usize);
+//! let roll = coro.resume(0).unwrap();
+//! assert!(roll < 6);
+//! ```
+//!
+//! This is not a cryptographic generator.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use asymmetric;
+
+static MASTER_SEED: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+// Used outside of coroutines, seeded as if it was the coroutine with id 0
+thread_local!(static THREAD_RNG: RefCell<Option<Rng>> = const { RefCell::new(None) });
+
+/// Set the master seed, coroutines which have not drawn a number yet will derive their seed
+/// from it
+pub fn set_seed(seed: u64) {
+    MASTER_SEED.store(seed, Ordering::Relaxed);
+}
+
+/// The current master seed
+pub fn seed() -> u64 {
+    MASTER_SEED.load(Ordering::Relaxed)
+}
+
+/// Small and fast generator based on SplitMix64
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator from a seed, equal seeds yield equal sequences
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    fn for_coroutine(id: usize) -> Rng {
+        let mut mix = Rng::new(seed() ^ (id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        Rng::new(mix.next_u64())
+    }
+
+    /// Next 64 random bits
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Next 32 random bits
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// Uniformly distributed number in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+    }
+
+    /// Uniformly distributed number in `[low, high)`
+    pub fn gen_range(&mut self, low: u64, high: u64) -> u64 {
+        assert!(low < high, "empty range");
+        let span = high - low;
+        // Reject the top of the 64 bit range which does not divide evenly by `span`
+        let zone = u64::MAX - (u64::MAX - span + 1) % span;
+        loop {
+            let v = self.next_u64();
+            if v <= zone {
+                return low + v % span;
+            }
+        }
+    }
+
+    /// `true` with probability `p`
+    pub fn gen_bool(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+
+    /// Fill `dest` with random bytes
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    /// Shuffle `slice` in place
+    pub fn shuffle<T>(&mut self, slice: &mut [T]) {
+        for i in (1..slice.len()).rev() {
+            let j = self.gen_range(0, i as u64 + 1) as usize;
+            slice.swap(i, j);
+        }
+    }
+}
+
+/// Run `f` with the generator of the current coroutine, or of the current thread outside of
+/// coroutines
+pub fn with_local_rng<R, F>(f: F) -> R
+    where F: FnOnce(&mut Rng) -> R
+{
+    asymmetric::with_current(|current| {
+        match current {
+            Some(coro) => {
+                let id = coro.id();
+                f(coro.rng_mut().get_or_insert_with(|| Rng::for_coroutine(id)))
+            }
+            None => {
+                THREAD_RNG.with(|rng| {
+                    f(rng.borrow_mut().get_or_insert_with(|| Rng::for_coroutine(0)))
+                })
+            }
+        }
+    })
+}
+
+/// Accessor for the generator of whichever coroutine is currently running
+pub fn local_rng() -> LocalRng {
+    LocalRng { _not_send: ::std::marker::PhantomData }
+}
+
+/// Returned by `local_rng`, every call draws from the generator of the coroutine running at
+/// that moment
+#[derive(Debug, Clone, Copy)]
+pub struct LocalRng {
+    _not_send: ::std::marker::PhantomData<*mut ()>,
+}
+
+impl LocalRng {
+    /// Next 64 random bits
+    pub fn next_u64(&self) -> u64 {
+        with_local_rng(|rng| rng.next_u64())
+    }
+
+    /// Next 32 random bits
+    pub fn next_u32(&self) -> u32 {
+        with_local_rng(|rng| rng.next_u32())
+    }
+
+    /// Uniformly distributed number in `[0, 1)`
+    pub fn next_f64(&self) -> f64 {
+        with_local_rng(|rng| rng.next_f64())
+    }
+
+    /// Uniformly distributed number in `[low, high)`
+    pub fn gen_range(&self, low: u64, high: u64) -> u64 {
+        with_local_rng(|rng| rng.gen_range(low, high))
+    }
+
+    /// `true` with probability `p`
+    pub fn gen_bool(&self, p: f64) -> bool {
+        with_local_rng(|rng| rng.gen_bool(p))
+    }
+
+    /// Fill `dest` with random bytes
+    pub fn fill_bytes(&self, dest: &mut [u8]) {
+        with_local_rng(|rng| rng.fill_bytes(dest))
+    }
+
+    /// Shuffle `slice` in place
+    pub fn shuffle<T>(&self, slice: &mut [T]) {
+        with_local_rng(|rng| rng.shuffle(slice))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use asymmetric::Coroutine;
+
+    #[test]
+    fn reproducible() {
+        let mut a = Rng::new(7);
+        let mut b = Rng::new(7);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+
+        for _ in 0..100 {
+            assert!(a.gen_range(3, 10) >= 3);
+            assert!(a.next_f64() < 1.0);
+        }
+    }
+
+    #[test]
+    fn per_coroutine() {
+        let draw = |coro: &mut Coroutine, _| {
+            let first = local_rng().next_u64();
+            coro.yield_with(first as usize);
+            local_rng().next_u64() as usize
+        };
+
+        let mut a = Coroutine::spawn(draw);
+        let mut b = Coroutine::spawn(draw);
+
+        let a1 = a.resume(0).unwrap();
+        let b1 = b.resume(0).unwrap();
+        let b2 = b.resume(0).unwrap();
+        let a2 = a.resume(0).unwrap();
+
+        // The sequence of a coroutine only depends on its id, not on the interleaving
+        let mut expected = Rng::for_coroutine(a.id());
+        assert_eq!(a1, expected.next_u64() as usize);
+        assert_eq!(a2, expected.next_u64() as usize);
+        assert!(a1 != b1 && b1 != b2);
+    }
+}
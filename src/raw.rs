@@ -0,0 +1,210 @@
+//! A stack and a context, the building block for a runtime of your own
+//!
+//! `raw::Coroutine` owns a stack and the context suspended on it, and `yield_to` switches
+//! from the running one to any suspended one, handing it a `usize`. There is no resuming and
+//! yielding back, no panic propagation and no bookkeeping: whoever holds the coroutines decides
+//! where to switch next. `asymmetric` is the one to use unless the scheduling is the point.
+//!
+//! ```rust
+//! use std::ptr;
+//! use coroutine::raw::{self, Coroutine, ProtectedFixedSizeStack};
+//!
+//! struct Pair {
+//!     main: Coroutine,
+//!     worker: Coroutine,
+//! }
+//!
+//! // Doubles whatever it is handed, forever
+//! fn worker(data: usize) -> ! {
+//!     let pair = data as *mut Pair;
+//!     let mut data = 0;
+//!     loop {
+//!         data = unsafe {
+//!             raw::yield_to(ptr::addr_of_mut!((*pair).worker),
+//!                           ptr::addr_of_mut!((*pair).main),
+//!                           data * 2)
+//!         };
+//!     }
+//! }
+//!
+//! let stack = ProtectedFixedSizeStack::new(64 * 1024).unwrap();
+//! let pair = Box::into_raw(Box::new(Pair {
+//!     main: Coroutine::current(),
+//!     worker: Coroutine::new(stack, worker),
+//! }));
+//! unsafe {
+//!     let (main, worker) = (ptr::addr_of_mut!((*pair).main), ptr::addr_of_mut!((*pair).worker));
+//!     assert_eq!(raw::yield_to(main, worker, pair as usize), 0);
+//!     assert_eq!(raw::yield_to(main, worker, 21), 42);
+//!     drop(Box::from_raw(pair));
+//! }
+//! ```
+//!
+//! # Safety
+//!
+//! A coroutine is either running or suspended. `Coroutine::current` stands for the code which
+//! runs without a stack of its own, e.g. the thread, and is running until it switches away.
+//!
+//! - `yield_to` switches from the running `from` to the suspended `to`. The context of `from`
+//!   is stored into it when the next switch back arrives, so it must stay where it is until
+//!   then. Both are only accessed through the pointers, no references to them may be alive
+//!   across the switch.
+//! - The entry function never returns, it ends by switching away for good. A panic escaping
+//!   it aborts the process.
+//! - Dropping a suspended coroutine frees its stack without running the destructors of the
+//!   frames on it. Dropping the running one frees the stack under its feet.
+//! - Coroutines stay on the thread they have been created on.
+
+use std::ptr;
+
+use context::{Context, Transfer};
+pub use context::stack::ProtectedFixedSizeStack;
+
+/// Function started on the stack of a coroutine, with the data of the first switch to it
+pub type Entry = fn(usize) -> !;
+
+/// A stack and the context suspended on it
+#[derive(Debug)]
+pub struct Coroutine {
+    // `Some` while suspended
+    context: Option<Context>,
+    // `Some` until started
+    entry: Option<Entry>,
+    stack: Option<ProtectedFixedSizeStack>,
+}
+
+// What `yield_to` hands over, read on arrival while the frame of the switching side is intact
+struct Switch {
+    from: *mut Coroutine,
+    data: usize,
+    entry: Option<Entry>,
+}
+
+impl Coroutine {
+    /// The code running right now, which has no stack of its own
+    pub fn current() -> Coroutine {
+        Coroutine {
+            context: None,
+            entry: None,
+            stack: None,
+        }
+    }
+
+    /// Run `entry` on `stack` once it is switched to
+    pub fn new(stack: ProtectedFixedSizeStack, entry: Entry) -> Coroutine {
+        Coroutine {
+            context: Some(Context::new(&stack, start)),
+            entry: Some(entry),
+            stack: Some(stack),
+        }
+    }
+
+    /// Whether it is suspended and may be switched to
+    pub fn is_suspended(&self) -> bool {
+        self.context.is_some()
+    }
+
+    /// Whether it has been switched to at least once
+    pub fn is_started(&self) -> bool {
+        self.entry.is_none()
+    }
+
+    /// The stack it runs on, `None` for `current`
+    pub fn stack(&self) -> Option<&ProtectedFixedSizeStack> {
+        self.stack.as_ref()
+    }
+
+    /// Give the stack back, e.g. to start another coroutine on it
+    ///
+    /// The frames of a started coroutine are abandoned, see the safety notes of the module.
+    pub fn into_stack(self) -> Option<ProtectedFixedSizeStack> {
+        self.stack
+    }
+}
+
+// Stores the context of the side which has switched here, returns the data and the entry
+unsafe fn arrive(t: Transfer) -> (usize, Option<Entry>) {
+    let switch = ptr::read(t.data as *const Switch);
+    debug_assert!((*switch.from).context.is_none());
+    (*switch.from).context = Some(t.context);
+    (switch.data, switch.entry)
+}
+
+extern "C" fn start(t: Transfer) -> ! {
+    let (data, entry) = unsafe { arrive(t) };
+    entry.unwrap()(data)
+}
+
+/// Switch from the running coroutine `from` to the suspended `to`, handing it `data`
+///
+/// Returns the data of the switch which eventually comes back to `from`, from any coroutine.
+///
+/// # Panics
+///
+/// If `to` is not suspended.
+///
+/// # Safety
+///
+/// `from` must be the running coroutine and stay valid until it is switched back to, see the
+/// module documentation.
+pub unsafe fn yield_to(from: *mut Coroutine, to: *mut Coroutine, data: usize) -> usize {
+    let context = (*to).context.take().expect("switching to a coroutine which is not suspended");
+    let switch = Switch {
+        from,
+        data,
+        entry: (*to).entry.take(),
+    };
+    let t = context.resume(&switch as *const Switch as usize);
+    arrive(t).0
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    thread_local!(static RING: Cell<*mut [Coroutine; 3]> = const { Cell::new(ptr::null_mut()) });
+
+    fn ring(index: usize) -> *mut Coroutine {
+        RING.with(|ring| unsafe { ptr::addr_of_mut!((*ring.get())[index]) })
+    }
+
+    // Passes the data on to the next one around the ring, adding its own index
+    fn member(mut data: usize) -> ! {
+        let index = data;
+        loop {
+            data = unsafe { yield_to(ring(index), ring((index + 1) % 3), data + index) };
+        }
+    }
+
+    #[test]
+    fn symmetric() {
+        let stack = || ProtectedFixedSizeStack::new(64 * 1024).unwrap();
+        let ring_ptr = Box::into_raw(Box::new([Coroutine::current(),
+                                               Coroutine::new(stack(), member),
+                                               Coroutine::new(stack(), member)]));
+        RING.with(|ring| ring.set(ring_ptr));
+
+        unsafe {
+            assert!(!(*ring(1)).is_started());
+            // Start both, 1 hands 2 its index which starts 2 with the right one
+            assert_eq!(yield_to(ring(0), ring(1), 1), 4);
+            assert!((*ring(1)).is_started() && (*ring(2)).is_started());
+            assert!((*ring(1)).is_suspended() && !(*ring(0)).is_suspended());
+            assert_eq!(yield_to(ring(0), ring(1), 10), 13);
+
+            drop(Box::from_raw(ring_ptr));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not suspended")]
+    fn running() {
+        let mut main = Coroutine::current();
+        let mut other = Coroutine::current();
+        unsafe {
+            yield_to(&mut main, &mut other, 0);
+        }
+    }
+}
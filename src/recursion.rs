@@ -0,0 +1,164 @@
+//! Running deep recursion on coroutine stacks
+//!
+//! `recurse` runs a closure on a fresh coroutine stack of a given size and returns its result,
+//! so a recursive parser or tree walk can go deeper than the stack of the thread allows without
+//! being rewritten around an explicit stack. `maybe_grow` only does so once the current stack
+//! is running low. Called at every level of the recursion it chains as many stacks as needed,
+//! each one taken from the `StackPool` and given back once its part of the recursion returns.
+//!
+//! ```rust
+//! use coroutine::recursion;
+//!
+//! fn depth(n: usize) -> usize {
+//!     recursion::maybe_grow(64 * 1024, 1024 * 1024, || {
+//!         if n == 0 { 0 } else { depth(n - 1) + 1 }
+//!     })
+//! }
+//!
+//! assert_eq!(depth(200_000), 200_000);
+//! ```
+//!
+//! The closure must not yield the coroutine it runs in, neither through `compat::sched` nor by
+//! waiting in `fd`: the stack is given back as soon as the closure is done and `recurse` panics
+//! if it has not.
+
+use std::mem;
+use std::panic;
+
+use asymmetric::{self, Coroutine};
+use options::Options;
+
+/// Bytes of stack left below the caller, `None` if the bounds of the stack are unknown
+///
+/// Known inside of coroutines, and for threads on Linux.
+pub fn remaining_stack() -> Option<usize> {
+    let marker = 0u8;
+    let sp = &marker as *const u8 as usize;
+    let bottom = asymmetric::with_current(|current| current.map(|coro| coro.stack_bottom()))
+        .or_else(thread_stack_bottom)?;
+    Some(sp.saturating_sub(bottom))
+}
+
+#[cfg(target_os = "linux")]
+fn thread_stack_bottom() -> Option<usize> {
+    use std::cell::Cell;
+
+    use libc;
+
+    thread_local!(static BOTTOM: Cell<usize> = const { Cell::new(0) });
+
+    let cached = BOTTOM.with(|bottom| bottom.get());
+    if cached != 0 {
+        return Some(cached);
+    }
+
+    let bottom = unsafe {
+        let mut attr: libc::pthread_attr_t = mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = ::std::ptr::null_mut();
+        let mut size = 0;
+        let ret = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        if ret != 0 {
+            return None;
+        }
+        addr as usize
+    };
+    BOTTOM.with(|cell| cell.set(bottom));
+    Some(bottom)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_stack_bottom() -> Option<usize> {
+    None
+}
+
+/// Run `f` on a coroutine stack of `stack_size` bytes and return its result
+///
+/// A panic inside of `f` is resumed on the caller.
+pub fn recurse<R, F>(stack_size: usize, f: F) -> R
+    where F: FnOnce() -> R
+{
+    let mut f = Some(f);
+    let mut result = None;
+    {
+        let mut run = || result = Some((f.take().unwrap())());
+        let run: &mut dyn FnMut() = &mut run;
+        // The coroutine is gone before this block is left, with it the last use of `run`
+        let run = unsafe { mem::transmute::<&mut dyn FnMut(), &'static mut dyn FnMut()>(run) };
+
+        let opts = Options {
+            stack_size,
+            ..Options::default()
+        };
+        let mut coro = Coroutine::spawn_opts(move |_, _| {
+                                                 run();
+                                                 0
+                                             },
+                                             opts);
+        match coro.resume(0) {
+            Ok(..) => assert!(coro.is_finished(), "yielded from a recursion stack"),
+            Err(::Error::Panicking(err)) => panic::resume_unwind(err),
+            Err(err) => panic!("recursion stack failed: {:?}", err),
+        }
+    }
+    result.unwrap()
+}
+
+/// Run `f` on a new coroutine stack of `stack_size` bytes if less than `red_zone` bytes are
+/// left on the current one, else right away
+///
+/// Stacks whose bounds are unknown are assumed to be large enough.
+pub fn maybe_grow<R, F>(red_zone: usize, stack_size: usize, f: F) -> R
+    where F: FnOnce() -> R
+{
+    match remaining_stack() {
+        Some(left) if left < red_zone => recurse(stack_size, f),
+        _ => f(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn remaining() {
+        let mut coro = Coroutine::spawn_opts(|_, _| remaining_stack().unwrap(),
+                                             Options {
+                                                 stack_size: 256 * 1024,
+                                                 ..Options::default()
+                                             });
+        let left = coro.resume(0).unwrap();
+        assert!(left > 128 * 1024 && left < 256 * 1024);
+    }
+
+    #[test]
+    fn chained() {
+        fn walk(n: usize, deepest: &Cell<usize>) -> usize {
+            maybe_grow(32 * 1024, 128 * 1024, || {
+                deepest.set(deepest.get().min(remaining_stack().unwrap()));
+                if n == 0 { 0 } else { walk(n - 1, deepest) + 1 }
+            })
+        }
+
+        // Far more than one stack of 128 KiB can hold, none of them ran out
+        let deepest = Cell::new(usize::MAX);
+        let mut coro = Coroutine::spawn(move |_, _| {
+            assert_eq!(walk(20_000, &deepest), 20_000);
+            deepest.get()
+        });
+        assert!(coro.resume(0).unwrap() > 0);
+    }
+
+    #[test]
+    fn panic_resumed() {
+        let result = panic::catch_unwind(|| recurse(96 * 1024, || -> usize { panic!("deep") }));
+        assert_eq!(*result.unwrap_err().downcast::<&str>().unwrap(), "deep");
+        assert_eq!(recurse(96 * 1024, || 7), 7);
+    }
+}
@@ -0,0 +1,134 @@
+//! Name based registry for discovering coroutines and actors
+//!
+//! Independently written modules can publish an address under a well-known name instead of
+//! passing it through every constructor. The process-wide registry accepts any `Send` value,
+//! e.g. the sender of a channel; the thread-local one also accepts values which are bound to a
+//! thread, such as `actor::Addr`.
+//!
+//! ```rust
+//! use coroutine::{actor, registry};
+//!
+//! let addr = actor::spawn(4, |mailbox: &mut actor::Mailbox<u32>| while mailbox.recv().is_some() {});
+//! registry::register_local("counter", addr).ok().unwrap();
+//!
+//! let found = registry::lookup_local::<actor::Addr<u32>>("counter").unwrap();
+//! found.send(1).unwrap();
+//! ```
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static GLOBAL: Mutex<BTreeMap<String, Box<dyn Any + Send>>> = Mutex::new(BTreeMap::new());
+
+thread_local!(static LOCAL: RefCell<BTreeMap<String, Box<dyn Any>>> = const { RefCell::new(BTreeMap::new()) });
+
+/// Publish `value` process-wide under `name`
+///
+/// Fails and hands the value back if the name is already taken.
+pub fn register<T: Any + Send>(name: &str, value: T) -> Result<(), T> {
+    let mut global = GLOBAL.lock().unwrap();
+    if global.contains_key(name) {
+        return Err(value);
+    }
+    global.insert(name.to_owned(), Box::new(value));
+    Ok(())
+}
+
+/// Clone of the process-wide value registered under `name`, if it has type `T`
+pub fn lookup<T: Any + Send + Clone>(name: &str) -> Option<T> {
+    let global = GLOBAL.lock().unwrap();
+    global.get(name).and_then(|v| v.downcast_ref::<T>()).cloned()
+}
+
+/// Remove `name` from the process-wide registry, returns whether it was registered
+pub fn unregister(name: &str) -> bool {
+    // Drop the value outside of the lock, it may unregister others
+    let value = GLOBAL.lock().unwrap().remove(name);
+    value.is_some()
+}
+
+/// Publish `value` under `name` for the current thread only
+///
+/// Fails and hands the value back if the name is already taken.
+pub fn register_local<T: Any>(name: &str, value: T) -> Result<(), T> {
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        if local.contains_key(name) {
+            return Err(value);
+        }
+        local.insert(name.to_owned(), Box::new(value));
+        Ok(())
+    })
+}
+
+/// Clone of the value registered under `name` on the current thread, if it has type `T`
+pub fn lookup_local<T: Any + Clone>(name: &str) -> Option<T> {
+    LOCAL.with(|local| local.borrow().get(name).and_then(|v| v.downcast_ref::<T>()).cloned())
+}
+
+/// Remove `name` from the registry of the current thread, returns whether it was registered
+pub fn unregister_local(name: &str) -> bool {
+    // Drop the value outside of the borrow, it may unregister others
+    let value = LOCAL.with(|local| local.borrow_mut().remove(name));
+    value.is_some()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn global() {
+        let (tx, rx) = mpsc::channel::<u32>();
+        register("registry::test::global", tx).unwrap();
+        assert!(register("registry::test::global", 0u32).is_err());
+
+        thread::spawn(|| {
+                let tx = lookup::<mpsc::Sender<u32>>("registry::test::global").unwrap();
+                tx.send(7).unwrap();
+            })
+            .join()
+            .unwrap();
+        assert_eq!(rx.recv().unwrap(), 7);
+
+        assert!(lookup::<u32>("registry::test::global").is_none());
+        assert!(unregister("registry::test::global"));
+        assert!(!unregister("registry::test::global"));
+    }
+
+    #[test]
+    fn unregister_from_drop() {
+        #[derive(Debug)]
+        struct Chained(&'static str);
+
+        impl Drop for Chained {
+            fn drop(&mut self) {
+                unregister(self.0);
+            }
+        }
+
+        register("registry::test::first", Chained("registry::test::second")).unwrap();
+        register("registry::test::second", 0u32).unwrap();
+        assert!(unregister("registry::test::first"));
+        assert!(lookup::<u32>("registry::test::second").is_none());
+    }
+
+    #[test]
+    fn local() {
+        use std::rc::Rc;
+
+        register_local("local", Rc::new(5)).unwrap();
+        assert_eq!(*lookup_local::<Rc<i32>>("local").unwrap(), 5);
+
+        thread::spawn(|| assert!(lookup_local::<Rc<i32>>("local").is_none()))
+            .join()
+            .unwrap();
+
+        assert!(unregister_local("local"));
+    }
+}
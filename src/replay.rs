@@ -0,0 +1,348 @@
+//! Recording the order in which coroutines are resumed, and replaying it
+//!
+//! `record` runs a closure and logs every resume issued from outside of any coroutine on the
+//! current thread: which coroutine, the state it was woken from, the data passed in and what
+//! came back. Coroutines are identified by the order in which they have been spawned from
+//! outside of coroutines during the recording, so the log stays meaningful in a later run.
+//! A log can be saved with `Log::write_to` and loaded with `Log::read_from`.
+//!
+//! `Log::replay` re-executes the recorded run single-threaded: given the coroutines spawned
+//! the same way again, it resumes them in the recorded order with the recorded data and
+//! stops at the first step which does not produce the recorded outcome, which narrows a bug
+//! that only shows under some interleaving down to a reproducible sequence.
+//!
+//! ```rust
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::replay;
+//!
+//! let spawn = || {
+//!     (0..2)
+//!         .map(|i| Coroutine::spawn(move |coro, data| coro.yield_with(data + i) + i))
+//!         .collect::<Vec<_>>()
+//! };
+//!
+//! let (_, log) = replay::record(|| {
+//!     let mut coros = spawn();
+//!     coros[1].resume(10).unwrap();
+//!     coros[0].resume(20).unwrap();
+//!     coros[1].resume(30).unwrap();
+//! });
+//! assert_eq!(log.steps().len(), 3);
+//!
+//! let mut coros = spawn();
+//! log.replay(&mut coros).unwrap();
+//! assert!(coros[1].is_finished() && !coros[0].is_finished());
+//! ```
+//!
+//! Data passed by pointer, like everything going through `generator`, cannot be replayed.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use asymmetric::{Coroutine, Handle, State};
+
+// Threads currently recording, a single load on the resume path while zero
+static RECORDING: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) });
+
+struct Recorder {
+    // Spawn order of the coroutines by id
+    coroutines: HashMap<usize, usize>,
+    steps: Vec<Step>,
+}
+
+/// One resume of a recorded run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    /// Index of the coroutine in spawn order
+    pub coroutine: usize,
+    /// State the coroutine was woken from, `Suspended` or `Parked`
+    pub woken_from: State,
+    /// Data passed to `resume`
+    pub data: usize,
+    /// Data returned by `resume`, `None` if it failed
+    pub result: Option<usize>,
+}
+
+/// Resumes of a recorded run in order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Log {
+    steps: Vec<Step>,
+}
+
+/// A replayed step which did not go as recorded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the step in the log
+    pub step: usize,
+    /// The step as recorded
+    pub expected: Step,
+    /// The step as replayed, `None` if the coroutine could not be resumed at all
+    pub found: Option<Step>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.found {
+            Some(ref found) => {
+                write!(f,
+                       "replay diverged at step {}: expected {:?}, found {:?}",
+                       self.step,
+                       self.expected,
+                       found)
+            }
+            None => {
+                write!(f,
+                       "replay diverged at step {}: coroutine {} cannot be resumed",
+                       self.step,
+                       self.expected.coroutine)
+            }
+        }
+    }
+}
+
+impl error::Error for Divergence {
+    fn description(&self) -> &str {
+        "replay diverged"
+    }
+}
+
+/// Run `f` and record the resumes it issues on this thread
+///
+/// Recordings do not nest, a resume is recorded by the innermost one.
+pub fn record<R, F>(f: F) -> (R, Log)
+    where F: FnOnce() -> R
+{
+    // Puts the outer recording back, also when `f` panics
+    struct Restore {
+        outer: Option<Recorder>,
+        done: bool,
+    }
+
+    impl Restore {
+        fn finish(&mut self) -> Option<Recorder> {
+            self.done = true;
+            RECORDING.fetch_sub(1, Ordering::Relaxed);
+            RECORDER.with(|r| r.replace(self.outer.take()))
+        }
+    }
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if !self.done {
+                self.finish();
+            }
+        }
+    }
+
+    let outer = RECORDER.with(|r| {
+        r.replace(Some(Recorder {
+            coroutines: HashMap::new(),
+            steps: Vec::new(),
+        }))
+    });
+    RECORDING.fetch_add(1, Ordering::Relaxed);
+
+    let mut restore = Restore { outer, done: false };
+    let ret = f();
+    let recorder = restore.finish().expect("recording has been replaced");
+
+    (ret, Log { steps: recorder.steps })
+}
+
+#[inline]
+pub(crate) fn is_recording() -> bool {
+    RECORDING.load(Ordering::Relaxed) != 0
+}
+
+/// A coroutine has been spawned from outside of coroutines
+pub(crate) fn spawned(coro: &Coroutine) {
+    RECORDER.with(|r| {
+        if let Some(ref mut recorder) = *r.borrow_mut() {
+            let index = recorder.coroutines.len();
+            recorder.coroutines.insert(coro.id(), index);
+        }
+    })
+}
+
+/// A coroutine spawned during the recording has been resumed from outside of coroutines
+pub(crate) fn resumed(coro: &Coroutine, woken_from: State, data: usize, result: &::Result<usize>) {
+    RECORDER.with(|r| {
+        if let Some(ref mut recorder) = *r.borrow_mut() {
+            if let Some(&coroutine) = recorder.coroutines.get(&coro.id()) {
+                recorder.steps.push(Step {
+                    coroutine,
+                    woken_from,
+                    data,
+                    result: result.as_ref().ok().cloned(),
+                });
+            }
+        }
+    })
+}
+
+fn state_name(state: State) -> &'static str {
+    match state {
+        State::Suspended => "suspended",
+        State::Running => "running",
+        State::Parked => "parked",
+        State::Finished => "finished",
+        State::Panicked => "panicked",
+    }
+}
+
+fn invalid(line: usize, what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("line {} of the replay log: {}", line + 1, what))
+}
+
+impl Log {
+    /// The recorded resumes in order
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Save the log as text, one step per line
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for step in &self.steps {
+            let result = match step.result {
+                Some(data) => data.to_string(),
+                None => "err".to_owned(),
+            };
+            writeln!(w,
+                     "{} {} {} {}",
+                     step.coroutine,
+                     state_name(step.woken_from),
+                     step.data,
+                     result)?;
+        }
+        Ok(())
+    }
+
+    /// Load a log saved by `write_to`
+    pub fn read_from<R: BufRead>(r: R) -> io::Result<Log> {
+        let mut steps = Vec::new();
+        for (n, line) in r.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() != 4 {
+                return Err(invalid(n, "expected 4 fields"));
+            }
+
+            let woken_from = match fields[1] {
+                "suspended" => State::Suspended,
+                "parked" => State::Parked,
+                _ => return Err(invalid(n, "unknown state")),
+            };
+            let number = |field: &str| field.parse().map_err(|_| invalid(n, "invalid number"));
+            steps.push(Step {
+                coroutine: number(fields[0])?,
+                woken_from,
+                data: number(fields[2])?,
+                result: match fields[3] {
+                    "err" => None,
+                    data => Some(number(data)?),
+                },
+            });
+        }
+        Ok(Log { steps })
+    }
+
+    /// Resume `coroutines`, given in the order they were spawned in the recording, exactly as
+    /// recorded
+    ///
+    /// Stops at the first step which does not turn out as recorded.
+    pub fn replay<K>(&self, coroutines: &mut [Handle<K>]) -> Result<(), Divergence> {
+        for (n, expected) in self.steps.iter().enumerate() {
+            let diverged = |found| {
+                Divergence {
+                    step: n,
+                    expected: expected.clone(),
+                    found,
+                }
+            };
+
+            let handle = match coroutines.get_mut(expected.coroutine) {
+                Some(handle) if !handle.is_finished() => handle,
+                _ => return Err(diverged(None)),
+            };
+
+            let woken_from = handle.state();
+            let result = handle.resume(expected.data).ok();
+            let found = Step {
+                coroutine: expected.coroutine,
+                woken_from,
+                data: expected.data,
+                result,
+            };
+            if found != *expected {
+                return Err(diverged(Some(found)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn spawn() -> Vec<Handle> {
+        let parker = Coroutine::spawn(|coro, data| {
+            let inner = coro.park_with(data * 2);
+            // Resumes of nested coroutines are not part of the log
+            let mut child = Coroutine::spawn(|coro, data| coro.yield_with(data + 1));
+            child.resume(inner).unwrap()
+        });
+        let counter = Coroutine::spawn(|coro, mut data| {
+            loop {
+                data = coro.yield_with(data + 100);
+            }
+        });
+        vec![parker, counter]
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_, log) = record(|| {
+            let mut coros = spawn();
+            coros[0].resume(1).unwrap();
+            coros[1].resume(2).unwrap();
+            coros[0].resume(3).unwrap();
+            coros[1].resume(4).unwrap();
+        });
+        assert_eq!(log.steps().len(), 4);
+        assert_eq!(log.steps()[2],
+                   Step {
+                       coroutine: 0,
+                       woken_from: State::Parked,
+                       data: 3,
+                       result: Some(4),
+                   });
+
+        let mut text = Vec::new();
+        log.write_to(&mut text).unwrap();
+        let loaded = Log::read_from(&text[..]).unwrap();
+        assert_eq!(loaded, log);
+
+        loaded.replay(&mut spawn()).unwrap();
+
+        // Swapping the coroutines makes the first step diverge
+        let mut swapped = spawn();
+        swapped.reverse();
+        let err = loaded.replay(&mut swapped).unwrap_err();
+        assert_eq!(err.step, 0);
+        assert_eq!(err.found.unwrap().result, Some(101));
+
+        assert!(Log::read_from(&b"0 running 1 2\n"[..]).is_err());
+    }
+}
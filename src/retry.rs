@@ -0,0 +1,166 @@
+//! Retrying fallible operations with exponential backoff
+//!
+//! `retry` runs an operation until it succeeds or the `Backoff` runs out of attempts. Between
+//! two attempts the coroutine sleeps in `fd::sleep`, for a delay which grows by `factor` after
+//! every failure up to `max_delay`. With jitter, the default, the actual delay is drawn
+//! uniformly up to that bound from the generator of the coroutine (see `rand`), which keeps
+//! many clients failing at once from retrying in lockstep.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::fd;
+//! use coroutine::retry::{self, Backoff};
+//!
+//! let mut coro = Coroutine::spawn(|coro, _| {
+//!     let backoff = Backoff::new(Duration::from_millis(1)).max_attempts(5);
+//!     let mut attempts = 0;
+//!     let result = retry::retry(coro, &backoff, |_| {
+//!         attempts += 1;
+//!         if attempts < 3 { Err("unavailable") } else { Ok(attempts) }
+//!     });
+//!     result.unwrap()
+//! });
+//! assert_eq!(fd::run(&mut coro).unwrap(), 3);
+//! ```
+
+use std::time::Duration;
+
+use asymmetric::Coroutine;
+use fd;
+use rand;
+
+/// How often and how long to wait between attempts
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max_delay: Duration,
+    factor: u32,
+    max_attempts: usize,
+    jitter: bool,
+}
+
+impl Backoff {
+    /// Wait `initial` after the first failure, doubling up to a minute, for at most 5 attempts
+    pub fn new(initial: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max_delay: Duration::from_secs(60),
+            factor: 2,
+            max_attempts: 5,
+            jitter: true,
+        }
+    }
+
+    /// Never wait longer than `max_delay` between attempts
+    pub fn max_delay(mut self, max_delay: Duration) -> Backoff {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Multiply the delay by `factor` after every failure, `1` for a constant delay
+    pub fn factor(mut self, factor: u32) -> Backoff {
+        self.factor = factor;
+        self
+    }
+
+    /// Give up after `max_attempts`, counting the first one
+    pub fn max_attempts(mut self, max_attempts: usize) -> Backoff {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Whether to randomize the delays
+    pub fn jitter(mut self, jitter: bool) -> Backoff {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Upper bound of the delay after `failures` failed attempts, before jitter
+    pub fn delay(&self, failures: usize) -> Duration {
+        let exponent = failures.saturating_sub(1).min(u32::MAX as usize) as u32;
+        let factor = self.factor.checked_pow(exponent).unwrap_or(u32::MAX);
+        self.initial.checked_mul(factor).unwrap_or(self.max_delay).min(self.max_delay)
+    }
+
+    fn sleep_time(&self, failures: usize) -> Duration {
+        let delay = self.delay(failures);
+        if !self.jitter || delay == Duration::from_secs(0) {
+            return delay;
+        }
+
+        let nanos = delay.as_nanos().min(u64::MAX as u128 - 1) as u64;
+        Duration::from_nanos(rand::with_local_rng(|rng| rng.gen_range(0, nanos + 1)))
+    }
+}
+
+/// Run `op` until it succeeds, sleeping between the attempts as `backoff` says
+///
+/// Returns the error of the last attempt once `max_attempts` have failed, or as soon as the
+/// event loop fails a sleep. A `Coroutine::with_deadline` section around `retry` ends the
+/// retries when it expires, sleeping included.
+pub fn retry<T, E, F>(coro: &mut Coroutine, backoff: &Backoff, mut op: F) -> Result<T, E>
+    where F: FnMut(&mut Coroutine) -> Result<T, E>
+{
+    let mut failures = 0;
+    loop {
+        let err = match op(coro) {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        failures += 1;
+        if failures >= backoff.max_attempts {
+            return Err(err);
+        }
+
+        let delay = backoff.sleep_time(failures);
+        trace!("Coroutine `{}`: attempt {} failed, retrying in {:?}",
+               coro.debug_name(),
+               failures,
+               delay);
+        if fd::sleep(coro, delay).is_err() {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn delays() {
+        let backoff = Backoff::new(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .jitter(false);
+        let delays = (1..6).map(|n| backoff.delay(n).as_millis()).collect::<Vec<_>>();
+        assert_eq!(delays, [100, 200, 400, 800, 1000]);
+        assert_eq!(backoff.delay(1000), Duration::from_secs(1));
+        assert_eq!(backoff.clone().factor(1).delay(10), Duration::from_millis(100));
+
+        let jittered = Backoff::new(Duration::from_millis(100));
+        for n in 1..10 {
+            assert!(jittered.sleep_time(n) <= jittered.delay(n));
+        }
+    }
+
+    #[test]
+    fn gives_up() {
+        let mut coro = Coroutine::spawn(|coro, _| {
+            let backoff = Backoff::new(Duration::from_millis(5)).max_attempts(3).jitter(false);
+            let start = Instant::now();
+            let mut attempts = 0;
+            let result: Result<(), usize> = retry(coro, &backoff, |_| {
+                attempts += 1;
+                Err(attempts)
+            });
+            assert_eq!(result, Err(3));
+            // Slept 5 and 10 ms in between
+            (start.elapsed() >= Duration::from_millis(15)) as usize
+        });
+        assert_eq!(fd::run(&mut coro).unwrap(), 1);
+    }
+}
@@ -0,0 +1,280 @@
+//! Discrete-event simulation on top of coroutines
+//!
+//! A `Simulation` keeps a virtual clock. Processes are coroutines which `sleep` in virtual
+//! time: sleeping schedules a wake-up event and suspends the process, and the simulation
+//! always resumes the process with the earliest event next, moving the clock straight to its
+//! timestamp. Nothing ever waits in real time, and events with the same timestamp run in
+//! the order they were scheduled, so a run is fully deterministic.
+//!
+//! ```rust
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//! use std::time::Duration;
+//! use coroutine::sim::Simulation;
+//!
+//! let log = Rc::new(RefCell::new(Vec::new()));
+//! let mut sim = Simulation::new();
+//!
+//! for (name, period) in [("fast", 2), ("slow", 3)] {
+//!     let log = log.clone();
+//!     sim.spawn(move |p| {
+//!         for _ in 0..2 {
+//!             p.sleep(Duration::from_secs(period));
+//!             log.borrow_mut().push((p.now().as_secs(), name));
+//!         }
+//!     });
+//! }
+//!
+//! sim.run();
+//! assert_eq!(*log.borrow(), [(2, "fast"), (3, "slow"), (4, "fast"), (6, "slow")]);
+//! assert_eq!(sim.now(), Duration::from_secs(6));
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::panic;
+use std::rc::Rc;
+use std::time::Duration;
+
+use asymmetric::{Coroutine, Handle};
+
+type Spawn = Box<dyn FnOnce(&mut Process)>;
+
+// State shared between the simulation and its processes
+struct Shared {
+    now: Cell<Duration>,
+    // Wake-up time requested by the process which has just yielded
+    wake: Cell<Option<Duration>>,
+    // Processes spawned by other processes, started at the current time
+    spawned: RefCell<Vec<Spawn>>,
+}
+
+/// Driver of a discrete-event simulation
+pub struct Simulation {
+    shared: Rc<Shared>,
+    processes: Vec<Option<Handle>>,
+    // Ordered by time, then by the order in which the events have been scheduled
+    queue: BinaryHeap<Reverse<(Duration, u64, usize)>>,
+    seq: u64,
+}
+
+/// A simulated process, passed to the closure running the process
+pub struct Process {
+    coro: *mut Coroutine,
+    shared: Rc<Shared>,
+}
+
+impl Process {
+    /// The current virtual time
+    pub fn now(&self) -> Duration {
+        self.shared.now.get()
+    }
+
+    /// Suspend the process for `duration` of virtual time
+    pub fn sleep(&mut self, duration: Duration) {
+        self.sleep_until(self.now() + duration);
+    }
+
+    /// Suspend the process until the virtual clock reaches `time`, returns immediately if it
+    /// already has
+    pub fn sleep_until(&mut self, time: Duration) {
+        if time < self.now() {
+            return;
+        }
+
+        self.shared.wake.set(Some(time));
+        unsafe {
+            (*self.coro).yield_with(0);
+        }
+    }
+
+    /// Let the other processes scheduled at the current time run first
+    pub fn yield_now(&mut self) {
+        self.sleep(Duration::from_secs(0));
+    }
+
+    /// Start another process at the current time, after the processes already scheduled for it
+    pub fn spawn<F>(&mut self, f: F)
+        where F: FnOnce(&mut Process) + 'static
+    {
+        self.shared.spawned.borrow_mut().push(Box::new(f));
+    }
+}
+
+impl Simulation {
+    /// Create a simulation with the clock at zero
+    pub fn new() -> Simulation {
+        Simulation {
+            shared: Rc::new(Shared {
+                now: Cell::new(Duration::from_secs(0)),
+                wake: Cell::new(None),
+                spawned: RefCell::new(Vec::new()),
+            }),
+            processes: Vec::new(),
+            queue: BinaryHeap::new(),
+            seq: 0,
+        }
+    }
+
+    /// The current virtual time
+    pub fn now(&self) -> Duration {
+        self.shared.now.get()
+    }
+
+    /// Check if no event is left
+    pub fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Time of the next event
+    pub fn next_event(&self) -> Option<Duration> {
+        self.queue.peek().map(|&Reverse((time, _, _))| time)
+    }
+
+    fn schedule(&mut self, time: Duration, pid: usize) {
+        self.queue.push(Reverse((time, self.seq, pid)));
+        self.seq += 1;
+    }
+
+    /// Start a process at the current time
+    #[track_caller]
+    pub fn spawn<F>(&mut self, f: F)
+        where F: FnOnce(&mut Process) + 'static
+    {
+        let shared = self.shared.clone();
+        let handle = Coroutine::spawn(move |coro, _| {
+            let mut process = Process { coro, shared };
+            f(&mut process);
+            0
+        });
+
+        let pid = self.processes.len();
+        self.processes.push(Some(handle));
+        let now = self.now();
+        self.schedule(now, pid);
+    }
+
+    /// Process the next event, returns `false` if there was none
+    ///
+    /// A panic inside the process is propagated to the caller.
+    pub fn step(&mut self) -> bool {
+        let Reverse((time, _, pid)) = match self.queue.pop() {
+            Some(event) => event,
+            None => return false,
+        };
+
+        self.shared.now.set(time);
+        self.shared.wake.set(None);
+
+        let result = self.processes[pid]
+            .as_mut()
+            .expect("event scheduled for a finished process")
+            .resume(0);
+        if let Err(err) = result {
+            self.processes[pid] = None;
+            match err {
+                ::Error::Panicking(err) => panic::resume_unwind(err),
+                err => panic!("simulated process failed: {:?}", err),
+            }
+        }
+
+        if self.processes[pid].as_ref().is_some_and(|h| h.is_finished()) {
+            self.processes[pid] = None;
+        } else {
+            // A process which yielded on its own is treated like `yield_now`
+            let wake = self.shared.wake.get().unwrap_or(time);
+            self.schedule(wake, pid);
+        }
+
+        let spawned = self.shared.spawned.replace(Vec::new());
+        for f in spawned {
+            self.spawn(f);
+        }
+        true
+    }
+
+    /// Run until no event is left
+    pub fn run(&mut self) {
+        while self.step() {}
+    }
+
+    /// Run the events up to and including `time`, then move the clock to `time`
+    pub fn run_until(&mut self, time: Duration) {
+        while self.next_event().is_some_and(|next| next <= time) {
+            self.step();
+        }
+        if self.now() < time {
+            self.shared.now.set(time);
+        }
+    }
+}
+
+impl Default for Simulation {
+    fn default() -> Simulation {
+        Simulation::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn same_time_in_schedule_order() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut sim = Simulation::new();
+
+        for i in 0..3 {
+            let log = log.clone();
+            sim.spawn(move |p| {
+                p.sleep(Duration::from_secs(1));
+                log.borrow_mut().push(i);
+                p.yield_now();
+                log.borrow_mut().push(i + 10);
+            });
+        }
+
+        sim.run();
+        assert_eq!(*log.borrow(), [0, 1, 2, 10, 11, 12]);
+        assert_eq!(sim.now(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn spawn_from_process() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut sim = Simulation::new();
+
+        let inner = log.clone();
+        sim.spawn(move |p| {
+            p.sleep(Duration::from_millis(5));
+            p.spawn(move |p| {
+                p.sleep(Duration::from_millis(5));
+                inner.borrow_mut().push(p.now());
+            });
+        });
+
+        sim.run_until(Duration::from_millis(7));
+        assert!(log.borrow().is_empty());
+        assert_eq!(sim.now(), Duration::from_millis(7));
+
+        sim.run();
+        assert_eq!(*log.borrow(), [Duration::from_millis(10)]);
+        assert!(sim.is_idle());
+    }
+
+    #[test]
+    #[should_panic(expected = "in simulation")]
+    fn propagate_panic() {
+        let mut sim = Simulation::new();
+        sim.spawn(|p| {
+            p.sleep(Duration::from_secs(1));
+            panic!("in simulation");
+        });
+        sim.run();
+    }
+}
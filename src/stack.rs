@@ -0,0 +1,226 @@
+//! Accounting of the memory reserved for coroutine stacks
+//!
+//! Every live coroutine counts with the size of its stack, as rounded up at spawn, until its
+//! handle is dropped. With a limit set, spawning beyond it fails with `SpawnError::StackLimit`
+//! from `try_spawn` (and panics from `spawn`), which gives servers spawning a coroutine per
+//! connection a way to shed load instead of exhausting the memory of the host.
+//!
+//! ```rust
+//! use coroutine::{stack, Options, SpawnError};
+//! use coroutine::asymmetric::Coroutine;
+//!
+//! stack::set_limit(Some(stack::allocated() + 256 * 1024));
+//!
+//! let opts = || Options { stack_size: 256 * 1024, ..Options::default() };
+//! let first = Coroutine::try_spawn_opts(|_, _| 0, opts()).unwrap();
+//! match Coroutine::try_spawn_opts(|_, _| 0, opts()) {
+//!     Err(SpawnError::StackLimit { .. }) => {}
+//!     _ => panic!("limit not enforced"),
+//! }
+//!
+//! drop(first);
+//! assert!(Coroutine::try_spawn_opts(|_, _| 0, opts()).is_ok());
+//! stack::set_limit(None);
+//! ```
+//!
+//! Stacks of finished coroutines are kept in the `StackPool` and handed to the next coroutine
+//! spawned with the same stack size. Cached stacks do not count towards the limit, and the pool
+//! is emptied automatically once the live stacks get close to it.
+
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use context::stack::ProtectedFixedSizeStack;
+#[cfg(unix)]
+use libc;
+
+use SpawnError;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Bytes currently reserved for the stacks of live coroutines
+pub fn allocated() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+/// Cap the bytes reserved for stacks, `None` removes the cap
+///
+/// Lowering the limit below `allocated()` does not affect existing coroutines, it only makes
+/// further spawns fail until enough of them are gone.
+pub fn set_limit(limit: Option<usize>) {
+    LIMIT.store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+}
+
+/// The current cap, `None` if unlimited
+pub fn limit() -> Option<usize> {
+    match LIMIT.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        limit => Some(limit),
+    }
+}
+
+/// Account for a new stack of `size` bytes
+pub(crate) fn reserve(size: usize) -> Result<(), SpawnError> {
+    let limit = LIMIT.load(Ordering::Relaxed);
+    if limit != usize::MAX && allocated().saturating_add(size) > limit / 8 * 7 {
+        StackPool::trim(Trim::Release);
+    }
+    ALLOCATED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |allocated| {
+            allocated.checked_add(size).filter(|&total| total <= limit)
+        })
+        .map(|_| ())
+        .map_err(|allocated| {
+            SpawnError::StackLimit {
+                size,
+                available: limit.saturating_sub(allocated),
+            }
+        })
+}
+
+/// Give back a stack accounted by `reserve`
+pub(crate) fn release(size: usize) {
+    ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+}
+
+// Stacks are plain memory mappings, nothing ties them to a thread
+struct Cached {
+    stack: ProtectedFixedSizeStack,
+    advised: bool,
+}
+
+unsafe impl Send for Cached {}
+
+struct Pool {
+    stacks: Vec<Cached>,
+    capacity: usize,
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    stacks: Vec::new(),
+    capacity: 64,
+});
+
+// Also used while switching stacks, where a panic would abort, so poisoning is ignored
+fn pool() -> MutexGuard<'static, Pool> {
+    POOL.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// How much `StackPool::trim` gives back to the operating system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trim {
+    /// Keep the cached stacks mapped but let the system reclaim their pages, reusing such a
+    /// stack costs page faults instead of a new mapping
+    Advise,
+    /// Unmap all cached stacks
+    Release,
+}
+
+/// Cache of the stacks of finished coroutines
+pub struct StackPool;
+
+impl StackPool {
+    /// Number of cached stacks
+    pub fn len() -> usize {
+        pool().stacks.len()
+    }
+
+    /// Check if no stack is cached
+    pub fn is_empty() -> bool {
+        pool().stacks.is_empty()
+    }
+
+    /// Bytes of the cached stacks
+    pub fn bytes() -> usize {
+        pool().stacks.iter().map(|c| c.stack.len()).sum()
+    }
+
+    /// Cache at most `capacity` stacks, `0` disables caching, 64 by default
+    pub fn set_capacity(capacity: usize) {
+        let mut pool = pool();
+        pool.capacity = capacity;
+        let excess = pool.stacks.len().saturating_sub(capacity);
+        pool.stacks.drain(..excess);
+    }
+
+    /// Give the memory of the cached stacks back to the operating system
+    pub fn trim(level: Trim) {
+        match level {
+            Trim::Release => {
+                // Unmap outside of the lock
+                let stacks = ::std::mem::take(&mut pool().stacks);
+                drop(stacks);
+            }
+            Trim::Advise => {
+                for cached in pool().stacks.iter_mut().filter(|c| !c.advised) {
+                    let bottom = cached.stack.bottom() as usize;
+                    discard(bottom, cached.stack.top() as usize - bottom);
+                    cached.advised = true;
+                }
+            }
+        }
+    }
+}
+
+/// A cached stack of exactly `size` bytes
+pub(crate) fn take(size: usize) -> Option<ProtectedFixedSizeStack> {
+    let mut pool = pool();
+    let pos = pool.stacks.iter().rposition(|c| c.stack.len() == size)?;
+    Some(pool.stacks.swap_remove(pos).stack)
+}
+
+/// Put the stack of a finished coroutine back into the pool
+pub(crate) fn recycle(stack: ProtectedFixedSizeStack) {
+    let mut pool = pool();
+    if pool.stacks.len() < pool.capacity {
+        pool.stacks.push(Cached {
+            stack,
+            advised: false,
+        });
+    }
+}
+
+/// Let the system reclaim the pages of `[addr, addr + len)`, which read back as zeroes
+#[cfg(unix)]
+pub(crate) fn discard(addr: usize, len: usize) {
+    unsafe {
+        libc::madvise(addr as *mut libc::c_void, len, libc::MADV_DONTNEED);
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn discard(_addr: usize, _len: usize) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuse_and_trim() {
+        let size = 16 * ::options::page_size();
+        let stack = ProtectedFixedSizeStack::new(size).unwrap();
+        assert_eq!(stack.len(), size);
+        let bottom = stack.bottom();
+
+        recycle(stack);
+        StackPool::trim(Trim::Advise);
+
+        // Other tests may recycle stacks concurrently, take ours back by size and address
+        let mut taken = Vec::new();
+        while let Some(stack) = take(size) {
+            let ours = stack.bottom() == bottom;
+            taken.push(stack);
+            if ours {
+                break;
+            }
+        }
+        let ours = taken.iter().find(|s| s.bottom() == bottom).unwrap();
+
+        // Advised pages read back as zeroes and stay writable
+        let word = ours.bottom() as *mut usize;
+        unsafe {
+            assert_eq!(*word, 0);
+            *word = 1;
+        }
+    }
+}
@@ -0,0 +1,263 @@
+//! Supervision of coroutines with restart policies
+//!
+//! A `Supervisor` owns a set of children, each created by a factory closure, and drives them
+//! in round-robin order. Children which finish are restarted according to their `Restart`
+//! policy. If children have to be restarted more than `max_restarts` times within a time
+//! window, the supervisor gives up; run as a coroutine, it then panics itself, so failures
+//! escalate to a supervisor further up the tree.
+//!
+//! ```rust
+//! use std::cell::Cell;
+//! use std::rc::Rc;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::supervisor::{Restart, Supervisor};
+//!
+//! let attempts = Rc::new(Cell::new(0));
+//!
+//! let mut supervisor = Supervisor::new();
+//! let counter = attempts.clone();
+//! supervisor.child(Restart::Transient, move || {
+//!     let counter = counter.clone();
+//!     Coroutine::spawn(move |_, _| {
+//!         counter.set(counter.get() + 1);
+//!         if counter.get() < 3 {
+//!             panic!("flaky");
+//!         }
+//!         0
+//!     })
+//! });
+//!
+//! supervisor.run().unwrap();
+//! assert_eq!(attempts.get(), 3);
+//! ```
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use asymmetric::{Coroutine, Handle};
+
+/// When a finished child is started again
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Restart {
+    /// Always restarted
+    Permanent,
+    /// Restarted only if it panicked
+    Transient,
+    /// Never restarted
+    Temporary,
+}
+
+/// The children needed more restarts within the window than allowed
+#[derive(Debug)]
+pub struct RestartIntensityExceeded;
+
+impl fmt::Display for RestartIntensityExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "restart intensity exceeded")
+    }
+}
+
+impl error::Error for RestartIntensityExceeded {}
+
+struct Child {
+    policy: Restart,
+    factory: Box<dyn FnMut() -> Handle>,
+    handle: Option<Handle>,
+}
+
+/// Owns child coroutines and restarts them when they finish
+pub struct Supervisor {
+    children: Vec<Child>,
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Supervisor {
+        Supervisor::new()
+    }
+}
+
+impl Supervisor {
+    /// Create a supervisor allowing 3 restarts within 5 seconds
+    pub fn new() -> Supervisor {
+        Supervisor {
+            children: Vec::new(),
+            max_restarts: 3,
+            window: Duration::from_secs(5),
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Allow at most `max_restarts` restarts within every `window`
+    pub fn max_restarts(mut self, max_restarts: usize, window: Duration) -> Supervisor {
+        self.max_restarts = max_restarts;
+        self.window = window;
+        self
+    }
+
+    /// Add a child, `factory` is called to start it and every time it has to be restarted
+    pub fn child<F>(&mut self, policy: Restart, factory: F)
+        where F: FnMut() -> Handle + 'static
+    {
+        let mut factory = Box::new(factory) as Box<dyn FnMut() -> Handle>;
+        let handle = factory();
+        self.children.push(Child {
+            policy,
+            factory,
+            handle: Some(handle),
+        });
+    }
+
+    /// Number of children which are still running or will be restarted
+    pub fn len(&self) -> usize {
+        self.children.iter().filter(|c| c.handle.is_some()).count()
+    }
+
+    /// Whether all children have finished for good
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn record_restart(&mut self) -> Result<(), RestartIntensityExceeded> {
+        let now = Instant::now();
+        while self.restarts.front().is_some_and(|&t| now.duration_since(t) > self.window) {
+            self.restarts.pop_front();
+        }
+
+        if self.restarts.len() >= self.max_restarts {
+            return Err(RestartIntensityExceeded);
+        }
+        self.restarts.push_back(now);
+        Ok(())
+    }
+
+    /// Resume every child once, restarting those which finished
+    ///
+    /// Returns whether any child is left.
+    pub fn step(&mut self) -> Result<bool, RestartIntensityExceeded> {
+        for i in 0..self.children.len() {
+            let (result, policy) = {
+                let child = &mut self.children[i];
+                let handle = match child.handle {
+                    Some(ref mut handle) => handle,
+                    None => continue,
+                };
+
+                let result = handle.resume(0);
+                if !handle.is_finished() {
+                    continue;
+                }
+                (result, child.policy)
+            };
+
+            let restart = matches!((policy, &result),
+                                   (Restart::Permanent, _) | (Restart::Transient, &Err(..)));
+
+            if let Err(ref err) = result {
+                warn!("Coroutine `{}`: supervised child panicked: {:?}",
+                      self.children[i].handle.as_ref().unwrap().debug_name(),
+                      err);
+            }
+
+            if restart {
+                self.record_restart()?;
+                let child = &mut self.children[i];
+                child.handle = Some((child.factory)());
+            } else {
+                self.children[i].handle = None;
+            }
+        }
+
+        Ok(!self.is_empty())
+    }
+
+    /// Drive the children until all of them have finished for good
+    pub fn run(&mut self) -> Result<(), RestartIntensityExceeded> {
+        while self.step()? {}
+        Ok(())
+    }
+
+    /// Run the supervisor in a coroutine of its own which yields after every round
+    ///
+    /// The coroutine panics when the restart intensity is exceeded.
+    #[track_caller]
+    pub fn spawn(mut self) -> Handle {
+        Coroutine::spawn(move |coro, _| {
+            loop {
+                match self.step() {
+                    Ok(true) => {
+                        coro.yield_with(0);
+                    }
+                    Ok(false) => return 0,
+                    Err(err) => panic!("{}", err),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn flaky(runs: Rc<Cell<usize>>, panics: bool) -> impl FnMut() -> Handle {
+        move || {
+            let runs = runs.clone();
+            Coroutine::spawn(move |coro, _| {
+                runs.set(runs.get() + 1);
+                coro.yield_with(0);
+                if panics {
+                    panic!("child failed");
+                }
+                0
+            })
+        }
+    }
+
+    #[test]
+    fn policies() {
+        let temporary = Rc::new(Cell::new(0));
+        let transient = Rc::new(Cell::new(0));
+
+        let mut supervisor = Supervisor::new().max_restarts(10, Duration::from_secs(60));
+        supervisor.child(Restart::Temporary, flaky(temporary.clone(), true));
+        supervisor.child(Restart::Transient, flaky(transient.clone(), false));
+        supervisor.run().unwrap();
+
+        assert_eq!(temporary.get(), 1);
+        assert_eq!(transient.get(), 1);
+    }
+
+    #[test]
+    fn intensity() {
+        let runs = Rc::new(Cell::new(0));
+
+        let mut supervisor = Supervisor::new().max_restarts(2, Duration::from_secs(60));
+        supervisor.child(Restart::Permanent, flaky(runs.clone(), true));
+
+        assert!(supervisor.run().is_err());
+        assert_eq!(runs.get(), 3);
+    }
+
+    #[test]
+    fn escalate() {
+        let runs = Rc::new(Cell::new(0));
+
+        let mut inner = Supervisor::new().max_restarts(1, Duration::from_secs(60));
+        inner.child(Restart::Permanent, flaky(runs.clone(), true));
+
+        let mut outer = Supervisor::new().max_restarts(0, Duration::from_secs(60));
+        let mut inner = Some(inner);
+        outer.child(Restart::Transient, move || inner.take().unwrap().spawn());
+
+        assert!(outer.run().is_err());
+        assert_eq!(runs.get(), 2);
+    }
+}
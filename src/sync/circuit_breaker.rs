@@ -0,0 +1,287 @@
+//! Failing fast while a downstream service is unhealthy
+//!
+//! A `CircuitBreaker` watches the outcome of the last `window` calls made through it. Once
+//! the share of failures among them reaches `failure_rate` the breaker opens and rejects
+//! every call with `CallError::Open` without running it. After `open_for` has passed it is
+//! half open: the next call goes through as a probe, the others are still rejected, and the
+//! outcome of the probe either closes the breaker again or keeps it open for another round.
+//! The time is checked on every call, no timer is involved.
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use coroutine::sync::CircuitBreaker;
+//! use coroutine::sync::circuit_breaker::{CallError, State};
+//!
+//! let breaker = CircuitBreaker::new().window(4).open_for(Duration::from_secs(60));
+//! for _ in 0..4 {
+//!     let _ = breaker.call(|| Err::<(), _>("connection refused"));
+//! }
+//! assert_eq!(breaker.state(), State::Open);
+//! assert_eq!(breaker.call(|| Ok::<_, ()>(1)), Err(CallError::Open));
+//! ```
+//!
+//! The breaker is `Sync`, coroutines on several threads may share it through an `Arc`.
+
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Whether calls go through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Calls go through, their outcomes are recorded
+    Closed,
+    /// Calls are rejected
+    Open,
+    /// The next call goes through as a probe
+    HalfOpen,
+}
+
+/// Why a call through a `CircuitBreaker` failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallError<E> {
+    /// The breaker is open, the operation has not been run
+    Open,
+    /// The operation has been run and failed
+    Failed(E),
+}
+
+impl<E: fmt::Display> fmt::Display for CallError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CallError::Open => write!(f, "circuit breaker is open"),
+            CallError::Failed(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error> error::Error for CallError<E> {
+    fn description(&self) -> &str {
+        match *self {
+            CallError::Open => "circuit breaker is open",
+            CallError::Failed(..) => "Failed",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Circuit {
+    // `true` for every failure among the last calls, oldest first
+    outcomes: VecDeque<bool>,
+    failures: usize,
+    opened_at: Option<Instant>,
+    probing: bool,
+}
+
+/// Rejects calls while too many of the recent ones have failed
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    circuit: Mutex<Circuit>,
+    failure_rate: f64,
+    window: usize,
+    open_for: Duration,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new()
+    }
+}
+
+impl CircuitBreaker {
+    /// Open when half of the last 20 calls have failed, for 5 seconds
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            circuit: Mutex::new(Circuit {
+                outcomes: VecDeque::new(),
+                failures: 0,
+                opened_at: None,
+                probing: false,
+            }),
+            failure_rate: 0.5,
+            window: 20,
+            open_for: Duration::from_secs(5),
+        }
+    }
+
+    /// Open when at least this share of the calls in the window has failed
+    pub fn failure_rate(mut self, failure_rate: f64) -> CircuitBreaker {
+        assert!(failure_rate > 0.0 && failure_rate <= 1.0,
+                "failure rate out of range");
+        self.failure_rate = failure_rate;
+        self
+    }
+
+    /// Number of recent calls to judge by, the breaker never opens before it has seen as many
+    pub fn window(mut self, window: usize) -> CircuitBreaker {
+        self.window = window.max(1);
+        self
+    }
+
+    /// How long to reject calls before probing
+    pub fn open_for(mut self, open_for: Duration) -> CircuitBreaker {
+        self.open_for = open_for;
+        self
+    }
+
+    /// Current state
+    pub fn state(&self) -> State {
+        let circuit = self.circuit.lock().unwrap();
+        match circuit.opened_at {
+            None => State::Closed,
+            Some(at) if !circuit.probing && at.elapsed() >= self.open_for => State::HalfOpen,
+            Some(..) => State::Open,
+        }
+    }
+
+    /// Close the breaker and forget the recorded calls
+    pub fn reset(&self) {
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.outcomes.clear();
+        circuit.failures = 0;
+        circuit.opened_at = None;
+        circuit.probing = false;
+    }
+
+    /// Run `f` unless the breaker is open, and record whether it failed
+    ///
+    /// A panic inside of `f` counts as a failure.
+    pub fn call<T, E, F>(&self, f: F) -> Result<T, CallError<E>>
+        where F: FnOnce() -> Result<T, E>
+    {
+        // Records a failure unless it is told otherwise, also when `f` unwinds
+        struct Outcome<'a> {
+            breaker: &'a CircuitBreaker,
+            probe: bool,
+            failed: bool,
+        }
+
+        impl<'a> Drop for Outcome<'a> {
+            fn drop(&mut self) {
+                self.breaker.record(self.probe, self.failed);
+            }
+        }
+
+        let probe = self.admit()?;
+        let mut outcome = Outcome {
+            breaker: self,
+            probe,
+            failed: true,
+        };
+        let result = f();
+        outcome.failed = result.is_err();
+        result.map_err(CallError::Failed)
+    }
+
+    // Whether the call may go through and is a probe
+    fn admit<E>(&self) -> Result<bool, CallError<E>> {
+        let mut circuit = self.circuit.lock().unwrap();
+        match circuit.opened_at {
+            None => Ok(false),
+            Some(at) if !circuit.probing && at.elapsed() >= self.open_for => {
+                circuit.probing = true;
+                Ok(true)
+            }
+            Some(..) => Err(CallError::Open),
+        }
+    }
+
+    fn record(&self, probe: bool, failed: bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+
+        if probe {
+            circuit.probing = false;
+            if failed {
+                circuit.opened_at = Some(Instant::now());
+            } else {
+                circuit.outcomes.clear();
+                circuit.failures = 0;
+                circuit.opened_at = None;
+            }
+            return;
+        }
+        if circuit.opened_at.is_some() {
+            // Started before the breaker opened
+            return;
+        }
+
+        circuit.outcomes.push_back(failed);
+        circuit.failures += failed as usize;
+        if circuit.outcomes.len() > self.window {
+            let oldest = circuit.outcomes.pop_front().unwrap();
+            circuit.failures -= oldest as usize;
+        }
+
+        let full = circuit.outcomes.len() == self.window;
+        if full && circuit.failures as f64 >= self.failure_rate * self.window as f64 {
+            debug!("circuit breaker opens after {} of {} calls failed",
+                   circuit.failures,
+                   self.window);
+            circuit.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn opens_and_recovers() {
+        let breaker = CircuitBreaker::new()
+            .window(4)
+            .failure_rate(0.5)
+            .open_for(Duration::from_millis(10));
+        let fail = || breaker.call(|| Err::<(), _>(()));
+
+        // One failure in four stays below the rate
+        fail().unwrap_err();
+        for _ in 0..3 {
+            breaker.call(|| Ok::<_, ()>(())).unwrap();
+        }
+        assert_eq!(breaker.state(), State::Closed);
+
+        // A panic counts as well, with the next failure half of the window has failed
+        assert!(panic::catch_unwind(|| breaker.call(|| -> Result<(), ()> { panic!("call") }))
+            .is_err());
+        assert_eq!(breaker.state(), State::Closed);
+        fail().unwrap_err();
+        assert_eq!(breaker.state(), State::Open);
+        assert_eq!(fail(), Err(CallError::Open));
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(breaker.state(), State::HalfOpen);
+        assert_eq!(fail(), Err(CallError::Failed(())));
+        assert_eq!(breaker.state(), State::Open);
+
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(breaker.call(|| Ok::<_, ()>(7)), Ok(7));
+        assert_eq!(breaker.state(), State::Closed);
+
+        // The window has been cleared
+        fail().unwrap_err();
+        fail().unwrap_err();
+        assert_eq!(breaker.state(), State::Closed);
+        breaker.reset();
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[test]
+    fn single_probe() {
+        let breaker = CircuitBreaker::new().window(1).open_for(Duration::from_secs(0));
+        breaker.call(|| Err::<(), _>(())).unwrap_err();
+
+        // Calls made while the probe is running are rejected
+        let result = breaker.call(|| {
+            assert_eq!(breaker.state(), State::Open);
+            Ok::<_, ()>(breaker.call(|| Ok::<_, ()>(())))
+        });
+        assert_eq!(result, Ok(Err(CallError::Open)));
+        assert_eq!(breaker.state(), State::Closed);
+    }
+}
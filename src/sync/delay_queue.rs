@@ -0,0 +1,262 @@
+//! Values which become available once their deadline has passed
+//!
+//! Any coroutine or thread may `insert` into a `DelayQueue`, the coroutine taking values out
+//! with `poll_expired` parks in `fd::poll_fds` until the earliest deadline, or until an insert
+//! moves that deadline forward. This is the building block for idle connection tracking,
+//! retry backoff and the like: `reset` pushes the deadline of an entry back, `remove` drops it.
+//!
+//! ```rust
+//! use std::time::{Duration, Instant};
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::fd;
+//! use coroutine::sync::DelayQueue;
+//!
+//! let queue = DelayQueue::new().unwrap();
+//! let now = Instant::now();
+//! queue.insert("later", now + Duration::from_millis(20));
+//! queue.insert("sooner", now + Duration::from_millis(10));
+//!
+//! let mut coro = Coroutine::spawn(move |coro, _| {
+//!     assert_eq!(queue.poll_expired(coro).unwrap(), "sooner");
+//!     assert_eq!(queue.poll_expired(coro).unwrap(), "later");
+//!     0
+//! });
+//! fd::run(&mut coro).unwrap();
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use asymmetric::Coroutine;
+use fd::{self, Fd, Interest};
+use super::wake::Wake;
+
+/// Refers to an entry of a `DelayQueue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key(u64);
+
+struct Entries<T> {
+    values: HashMap<u64, (Instant, T)>,
+    // Deadlines of the entries, stale ones are skipped when they come up and thrown out as a
+    // whole once they outnumber the live ones
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    next_key: u64,
+}
+
+impl<T> Entries<T> {
+    // Earliest deadline of a live entry, dropping the stale ones in front of it
+    fn earliest(&mut self) -> Option<(Instant, u64)> {
+        while let Some(&Reverse((deadline, key))) = self.deadlines.peek() {
+            match self.values.get(&key) {
+                Some(&(current, _)) if current == deadline => return Some((deadline, key)),
+                _ => {
+                    self.deadlines.pop();
+                }
+            }
+        }
+        None
+    }
+
+    fn push(&mut self, key: u64, deadline: Instant) -> bool {
+        let earlier = self.earliest().is_none_or(|(first, _)| deadline < first);
+        self.deadlines.push(Reverse((deadline, key)));
+        self.compact();
+        earlier
+    }
+
+    fn remove(&mut self, key: u64) -> Option<T> {
+        let value = self.values.remove(&key).map(|(_, value)| value);
+        self.compact();
+        value
+    }
+
+    // Rebuild the heap from the live entries, in time linear to the stale ones pushed since
+    fn compact(&mut self) {
+        if self.deadlines.len() > 2 * self.values.len() {
+            self.deadlines = self.values
+                .iter()
+                .map(|(&key, &(deadline, _))| Reverse((deadline, key)))
+                .collect();
+        }
+    }
+}
+
+/// Queue of values ordered by deadline
+pub struct DelayQueue<T> {
+    entries: Mutex<Entries<T>>,
+    wake: Wake,
+}
+
+impl<T> fmt::Debug for DelayQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DelayQueue {{ len: {} }}", self.len())
+    }
+}
+
+impl<T> DelayQueue<T> {
+    /// Create an empty queue
+    pub fn new() -> io::Result<DelayQueue<T>> {
+        Ok(DelayQueue {
+            entries: Mutex::new(Entries {
+                values: HashMap::new(),
+                deadlines: BinaryHeap::new(),
+                next_key: 0,
+            }),
+            wake: Wake::new()?,
+        })
+    }
+
+    /// Add `value` to come out at `deadline`
+    pub fn insert(&self, value: T, deadline: Instant) -> Key {
+        let (key, earlier) = {
+            let mut entries = self.entries.lock().unwrap();
+            let key = entries.next_key;
+            entries.next_key += 1;
+            entries.values.insert(key, (deadline, value));
+            (key, entries.push(key, deadline))
+        };
+
+        // The waiting coroutine has to shorten its timeout
+        if earlier {
+            self.wake.notify();
+        }
+        Key(key)
+    }
+
+    /// Move the deadline of the entry, returns whether it is still in the queue
+    pub fn reset(&self, key: Key, deadline: Instant) -> bool {
+        let earlier = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.values.get_mut(&key.0) {
+                Some(entry) => entry.0 = deadline,
+                None => return false,
+            }
+            entries.push(key.0, deadline)
+        };
+
+        if earlier {
+            self.wake.notify();
+        }
+        true
+    }
+
+    /// Take the entry out before it expires
+    pub fn remove(&self, key: Key) -> Option<T> {
+        self.entries.lock().unwrap().remove(key.0)
+    }
+
+    /// Number of entries, expired or not
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().values.len()
+    }
+
+    /// Check if there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Earliest deadline in the queue
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.entries.lock().unwrap().earliest().map(|(deadline, _)| deadline)
+    }
+
+    /// Take out the entry with the earliest deadline if it has passed
+    pub fn try_expired(&self) -> Option<T> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.earliest() {
+            Some((deadline, key)) if deadline <= Instant::now() => {
+                entries.deadlines.pop();
+                entries.values.remove(&key).map(|(_, value)| value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Take out the entry with the earliest deadline, parking `coro` until it has passed
+    ///
+    /// Also waits while the queue is empty. Fails if the event loop fails the wait.
+    pub fn poll_expired(&self, coro: &mut Coroutine) -> io::Result<T> {
+        loop {
+            // Drained before looking, an insert after this point makes the fd readable again
+            self.wake.drain();
+            if let Some(value) = self.try_expired() {
+                return Ok(value);
+            }
+
+            let timeout = self.next_deadline()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            fd::poll_fds(coro, &[Fd::new(self.wake.read, Interest::READABLE)], timeout)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn order() {
+        let queue = DelayQueue::new().unwrap();
+        let now = Instant::now();
+        let far = now + Duration::from_secs(3600);
+
+        let a = queue.insert(1, far);
+        let b = queue.insert(2, far + Duration::from_secs(1));
+        queue.insert(3, now);
+        assert_eq!(queue.len(), 3);
+
+        // Pulling `b` forward makes it expire as well, ties come out in insertion order
+        assert!(queue.reset(b, now));
+        assert_eq!(queue.next_deadline(), Some(now));
+        assert_eq!(queue.try_expired(), Some(2));
+        assert_eq!(queue.try_expired(), Some(3));
+        assert_eq!(queue.try_expired(), None);
+        assert!(!queue.reset(b, now));
+
+        assert_eq!(queue.next_deadline(), Some(far));
+        assert_eq!(queue.remove(a), Some(1));
+        assert_eq!(queue.remove(a), None);
+        assert!(queue.is_empty());
+        assert_eq!(queue.next_deadline(), None);
+    }
+
+    #[test]
+    fn reset_bounded() {
+        let queue = DelayQueue::new().unwrap();
+        let now = Instant::now();
+        let first = queue.insert(0, now);
+        let key = queue.insert(1, now);
+
+        for i in 0..1000 {
+            assert!(queue.reset(key, now + Duration::from_millis(i)));
+            assert!(queue.entries.lock().unwrap().deadlines.len() <= 4);
+        }
+        assert_eq!(queue.next_deadline(), Some(now));
+        assert_eq!(queue.remove(first), Some(0));
+        assert_eq!(queue.next_deadline(), Some(now + Duration::from_millis(999)));
+        assert_eq!(queue.entries.lock().unwrap().deadlines.len(), 1);
+    }
+
+    #[test]
+    fn insert_wakes_up() {
+        let queue = Arc::new(DelayQueue::new().unwrap());
+        queue.insert(0, Instant::now() + Duration::from_secs(3600));
+
+        let inserter = queue.clone();
+        let mut coro = Coroutine::spawn(move |coro, _| queue.poll_expired(coro).unwrap());
+
+        // Parked on the distant deadline, an earlier insert from another thread cuts it short
+        coro.resume(0).unwrap();
+        assert!(fd::pending(&mut coro).unwrap().timeout().unwrap() > Duration::from_secs(3000));
+        thread::spawn(move || inserter.insert(42, Instant::now()));
+        assert_eq!(fd::run(&mut coro).unwrap(), 42);
+    }
+}
@@ -0,0 +1,15 @@
+//! Synchronization between coroutines and the rest of the program
+
+pub use self::circuit_breaker::CircuitBreaker;
+#[cfg(unix)]
+pub use self::delay_queue::DelayQueue;
+pub use self::mutex::{HybridMutex, HybridMutexGuard};
+
+pub mod circuit_breaker;
+#[cfg(unix)]
+pub mod delay_queue;
+#[cfg(unix)]
+pub mod mpsc;
+mod mutex;
+#[cfg(unix)]
+mod wake;
@@ -0,0 +1,310 @@
+//! Channels fed by plain threads and drained by a coroutine
+//!
+//! The `Sender` of `channel_with_external_senders` is an ordinary thread-safe handle, usable
+//! from threads which know nothing about coroutines: callbacks of C libraries, GUI threads and
+//! the like. The receiving coroutine waits in `fd::poll_fds` on a file descriptor which every
+//! send makes readable (an eventfd on Linux, a pipe elsewhere), so whatever drives it with
+//! `fd::run` or its own event loop wakes it up without polling.
+//!
+//! ```rust
+//! use std::thread;
+//! use coroutine::asymmetric::Coroutine;
+//! use coroutine::fd;
+//! use coroutine::sync::mpsc;
+//!
+//! let (tx, rx) = mpsc::channel_with_external_senders().unwrap();
+//! let producer = thread::spawn(move || {
+//!     for i in 1..=10 {
+//!         tx.send(i).unwrap();
+//!     }
+//! });
+//!
+//! let mut consumer = Coroutine::spawn(move |coro, _| {
+//!     let mut sum = 0;
+//!     while let Ok(n) = rx.recv(coro) {
+//!         sum += n;
+//!     }
+//!     sum
+//! });
+//!
+//! assert_eq!(fd::run(&mut consumer).unwrap(), 55);
+//! producer.join().unwrap();
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+pub use std::sync::mpsc::{RecvError, SendError, TryRecvError};
+
+use asymmetric::Coroutine;
+use fd::{self, Fd, Interest};
+use super::wake::Wake;
+
+struct Queue<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver: bool,
+}
+
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    wake: Wake,
+}
+
+/// Sending half, may be cloned and used from any thread
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receiving half, owned by the consuming coroutine
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Create an unbounded channel whose senders may live on plain threads
+pub fn channel_with_external_senders<T>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            items: VecDeque::new(),
+            senders: 1,
+            receiver: true,
+        }),
+        wake: Wake::new()?,
+    });
+
+    Ok((Sender { shared: shared.clone() }, Receiver { shared }))
+}
+
+impl<T> Sender<T> {
+    /// Queue `value` and wake the receiver, fails if the receiver is gone
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let was_empty = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            if !queue.receiver {
+                return Err(SendError(value));
+            }
+            queue.items.push_back(value);
+            queue.items.len() == 1
+        };
+
+        // Later sends find the receiver woken up already
+        if was_empty {
+            self.shared.wake.notify();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.queue.lock().unwrap().senders += 1;
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.senders -= 1;
+            queue.senders == 0
+        };
+        if last {
+            self.shared.wake.notify();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Take the next value if there is one
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        match queue.items.pop_front() {
+            Some(value) => Ok(value),
+            None if queue.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Take the next value, parking `coro` in `fd::poll_fds` until there is one
+    ///
+    /// Fails once all senders are gone and the queue is empty.
+    pub fn recv(&self, coro: &mut Coroutine) -> Result<T, RecvError> {
+        loop {
+            // Drained before looking, a send after this point makes the fd readable again
+            self.shared.wake.drain();
+            match self.try_recv() {
+                Ok(value) => return Ok(value),
+                Err(TryRecvError::Disconnected) => return Err(RecvError),
+                Err(TryRecvError::Empty) => {}
+            }
+
+            let fds = [Fd::new(self.shared.wake.read, Interest::READABLE)];
+            if fd::poll_fds(coro, &fds, None).is_err() {
+                // The event loop gave up on us, treat it like the senders going away
+                return Err(RecvError);
+            }
+        }
+    }
+}
+
+impl<T> AsRawFd for Receiver<T> {
+    /// Readable when the queue may have changed
+    fn as_raw_fd(&self) -> RawFd {
+        self.shared.wake.read
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let items = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.receiver = false;
+            ::std::mem::take(&mut queue.items)
+        };
+        drop(items);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::thread;
+
+    use libc;
+
+    use super::*;
+    use asymmetric::{Coroutine, Handle};
+    use rand::Rng;
+
+    #[test]
+    fn many_senders() {
+        let (tx, rx) = channel_with_external_senders().unwrap();
+        let producers = (0..4)
+            .map(|t| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    for i in 0..250 {
+                        tx.send(t * 1000 + i).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(tx);
+
+        let mut consumer = Coroutine::spawn(move |coro, _| {
+            let mut count = 0;
+            while rx.recv(coro).is_ok() {
+                count += 1;
+            }
+            count
+        });
+        assert_eq!(fd::run(&mut consumer).unwrap(), 1000);
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn receiver_gone() {
+        let (tx, rx) = channel_with_external_senders().unwrap();
+        tx.send(1).unwrap();
+        assert_eq!(rx.try_recv(), Ok(1));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        drop(rx);
+        assert_eq!(tx.send(2), Err(SendError(2)));
+    }
+
+    fn readable(fd: RawFd) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+    }
+
+    #[test]
+    fn random_interleavings() {
+        // Senders and the receiver are resumed in an order drawn from the seed, every value
+        // arrives exactly once and in the order of its sender
+        for seed in 0..100 {
+            let mut rng = Rng::new(seed);
+            let (tx, rx) = channel_with_external_senders().unwrap();
+            let wake = rx.as_raw_fd();
+            let sent = Rc::new(Cell::new(0));
+            let received = Rc::new(RefCell::new(Vec::new()));
+
+            let senders = rng.gen_range(1, 4) as usize;
+            let mut counts = Vec::new();
+            let mut coros = Vec::new();
+            for sender in 0..senders {
+                let (tx, sent) = (tx.clone(), sent.clone());
+                let count = rng.gen_range(0, 20) as usize;
+                let burst = rng.gen_range(1, 4) as usize;
+                counts.push(count);
+                coros.push(Coroutine::spawn(move |coro, _| {
+                    for i in 0..count {
+                        tx.send((sender, i)).unwrap();
+                        sent.set(sent.get() + 1);
+                        if i % burst == 0 {
+                            coro.yield_with(0);
+                        }
+                    }
+                    0
+                }));
+            }
+            drop(tx);
+
+            let log = received.clone();
+            let mut receiver: Option<Handle> = Some(Coroutine::spawn(move |coro, _| {
+                while let Ok(value) = rx.recv(coro) {
+                    log.borrow_mut().push(value);
+                }
+                0
+            }));
+
+            while !coros.is_empty() || receiver.is_some() {
+                let i = rng.gen_range(0, coros.len() as u64 + 1) as usize;
+                if i < coros.len() {
+                    coros[i].resume(0).unwrap();
+                    if coros[i].is_finished() {
+                        coros.swap_remove(i);
+                    }
+                    continue;
+                }
+
+                let handle = match receiver.as_mut() {
+                    Some(handle) => handle,
+                    None => continue,
+                };
+                let pending = sent.get() > received.borrow().len() || coros.is_empty();
+                if let Some(wait) = fd::pending(handle) {
+                    if !pending {
+                        continue;
+                    }
+                    // No wake-up may get lost while it is parked
+                    assert!(readable(wake), "seed {}", seed);
+                    wait.set_ready(0, fd::Ready::from_revents(libc::POLLIN));
+                }
+                handle.resume(0).unwrap();
+                if handle.is_finished() {
+                    receiver = None;
+                }
+            }
+
+            let received = received.borrow();
+            assert_eq!(received.len(), counts.iter().sum::<usize>(), "seed {}", seed);
+            for (sender, &count) in counts.iter().enumerate() {
+                let order = received.iter()
+                    .filter(|value| value.0 == sender)
+                    .map(|value| value.1)
+                    .collect::<Vec<_>>();
+                assert_eq!(order, (0..count).collect::<Vec<_>>(), "seed {}", seed);
+            }
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, LockResult, Mutex, PoisonError, TryLockError, TryLockResult};
+use std::thread;
+
+use asymmetric;
+
+// Attempts before giving up the processor
+const SPINS: usize = 100;
+
+/// Mutual exclusion lock which suspends coroutines instead of blocking their thread
+///
+/// `lock` spins briefly, then parks the calling coroutine with the reason
+/// `"HybridMutex::lock"` and tries again once it is resumed, so the thread driving it can run
+/// the coroutine holding the lock in the meantime. Outside of coroutines it blocks the thread
+/// like `std::sync::Mutex`, so data shared with plain threads is protected by it as well.
+///
+/// ```rust
+/// use std::rc::Rc;
+/// use coroutine::asymmetric::{Coroutine, State};
+/// use coroutine::sync::HybridMutex;
+///
+/// let lock = Rc::new(HybridMutex::new(0));
+///
+/// let holder = lock.clone();
+/// let mut a = Coroutine::spawn(move |coro, _| {
+///     let mut guard = holder.lock().unwrap();
+///     coro.yield_with(0);
+///     *guard += 1;
+///     0
+/// });
+/// let waiter = lock.clone();
+/// let mut b = Coroutine::spawn(move |_, _| {
+///     *waiter.lock().unwrap() += 10;
+///     0
+/// });
+///
+/// a.resume(0).unwrap();
+/// b.resume(0).unwrap();
+/// assert_eq!(b.state(), State::Parked);
+/// a.resume(0).unwrap();
+/// b.resume(0).unwrap();
+/// assert_eq!(*lock.lock().unwrap(), 11);
+/// ```
+///
+/// Unlike the std mutex the lock is not tied to the thread which has taken it, a coroutine
+/// which moves to another thread, e.g. on a `ThreadPoolRunner`, may keep holding the guard
+/// across yields and drop it over there.
+pub struct HybridMutex<T: ?Sized> {
+    locked: AtomicBool,
+    poisoned: AtomicBool,
+    // Plain threads blocked in `lock`, woken through `released` under `sleeping`
+    sleepers: AtomicUsize,
+    sleeping: Mutex<()>,
+    released: Condvar,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for HybridMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for HybridMutex<T> {}
+
+/// Access to the data of a locked `HybridMutex`, unlocks it when dropped
+#[must_use = "the lock is released right away if the guard is not kept"]
+pub struct HybridMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a HybridMutex<T>,
+    // Whether the thread was already panicking when the lock was taken
+    panicking: bool,
+    // Send and Sync follow the data, see below
+    _marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<'a, T: ?Sized + Send> Send for HybridMutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync> Sync for HybridMutexGuard<'a, T> {}
+
+impl<T> HybridMutex<T> {
+    /// Create an unlocked mutex
+    pub fn new(value: T) -> HybridMutex<T> {
+        HybridMutex {
+            locked: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            sleeping: Mutex::new(()),
+            released: Condvar::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Consume the mutex, returning the data
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.is_poisoned();
+        let value = self.data.into_inner();
+        if poisoned { Err(PoisonError::new(value)) } else { Ok(value) }
+    }
+}
+
+impl<T: ?Sized> HybridMutex<T> {
+    /// Acquire the lock, see the type documentation for how it waits
+    pub fn lock(&self) -> LockResult<HybridMutexGuard<'_, T>> {
+        for _ in 0..SPINS {
+            if self.acquire() {
+                return self.guard();
+            }
+            hint::spin_loop();
+        }
+
+        let in_coroutine = asymmetric::with_current(|current| current.is_some());
+        if !in_coroutine {
+            self.block();
+            return self.guard();
+        }
+
+        while !self.acquire() {
+            asymmetric::with_current(|current| {
+                if let Some(coro) = current {
+                    coro.park_with_reason(0, "HybridMutex::lock");
+                }
+            });
+        }
+        self.guard()
+    }
+
+    /// Acquire the lock if it is free
+    pub fn try_lock(&self) -> TryLockResult<HybridMutexGuard<'_, T>> {
+        if !self.acquire() {
+            return Err(TryLockError::WouldBlock);
+        }
+        self.guard().map_err(TryLockError::Poisoned)
+    }
+
+    /// Check if a holder of the lock has panicked
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Mutable access to the data, no locking is needed
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.is_poisoned();
+        let value = unsafe { &mut *self.data.get() };
+        if poisoned { Err(PoisonError::new(value)) } else { Ok(value) }
+    }
+
+    // Sequentially consistent along with `sleepers`, so that either a blocking thread sees
+    // the lock released or the releasing side sees the thread and wakes it
+    fn acquire(&self) -> bool {
+        self.locked.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok()
+    }
+
+    fn block(&self) {
+        let mut sleeping = self.sleeping.lock().unwrap_or_else(|e| e.into_inner());
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        while !self.acquire() {
+            sleeping = self.released.wait(sleeping).unwrap_or_else(|e| e.into_inner());
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn release(&self) {
+        self.locked.store(false, Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            // Taken for a blocking thread to be inside of `wait` rather than about to
+            drop(self.sleeping.lock().unwrap_or_else(|e| e.into_inner()));
+            self.released.notify_one();
+        }
+    }
+
+    fn guard(&self) -> LockResult<HybridMutexGuard<'_, T>> {
+        let guard = HybridMutexGuard {
+            lock: self,
+            panicking: thread::panicking(),
+            _marker: PhantomData,
+        };
+        if self.is_poisoned() { Err(PoisonError::new(guard)) } else { Ok(guard) }
+    }
+}
+
+impl<T: Default> Default for HybridMutex<T> {
+    fn default() -> HybridMutex<T> {
+        HybridMutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for HybridMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Ok(guard) => f.debug_struct("HybridMutex").field("data", &&*guard).finish(),
+            Err(TryLockError::Poisoned(err)) => {
+                f.debug_struct("HybridMutex").field("data", &&**err.get_ref()).finish()
+            }
+            Err(TryLockError::WouldBlock) => f.write_str("HybridMutex { data: <locked> }"),
+        }
+    }
+}
+
+impl<T> From<T> for HybridMutex<T> {
+    fn from(value: T) -> HybridMutex<T> {
+        HybridMutex::new(value)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for HybridMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for HybridMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized> Drop for HybridMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        if !self.panicking && thread::panicking() {
+            self.lock.poisoned.store(true, Ordering::Relaxed);
+        }
+        self.lock.release();
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for HybridMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use asymmetric::Coroutine;
+    use rand::Rng;
+
+    #[cfg(feature = "thread-pool")]
+    #[test]
+    fn pool_and_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        use thread_pool::ThreadPoolRunner;
+
+        let counter = Arc::new(HybridMutex::new(0));
+
+        let pool = ThreadPoolRunner::new(2);
+        for _ in 0..8 {
+            let counter = counter.clone();
+            pool.spawn(move |coro, _| {
+                for _ in 0..100 {
+                    *counter.lock().unwrap() += 1;
+                    coro.yield_with(0);
+                }
+                0
+            });
+        }
+
+        let threads = (0..2)
+            .map(|_| {
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        *counter.lock().unwrap() += 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        pool.join_all();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*counter.lock().unwrap(), 1000);
+    }
+
+    #[test]
+    fn held_across_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let lock = Arc::new(HybridMutex::new(0));
+
+        let holder = lock.clone();
+        let mut coro = Coroutine::spawn_send(move |coro, _| {
+            let mut guard = holder.lock().unwrap();
+            coro.yield_with(0);
+            *guard += 1;
+            0
+        });
+        coro.resume(0).unwrap();
+
+        // Blocked while the coroutine keeps the lock, which it releases on another thread
+        let waiter = lock.clone();
+        let blocked = thread::spawn(move || *waiter.lock().unwrap() += 10);
+        thread::sleep(::std::time::Duration::from_millis(10));
+        assert!(lock.try_lock().is_err());
+        thread::spawn(move || coro.resume(0).unwrap()).join().unwrap();
+
+        blocked.join().unwrap();
+        assert_eq!(*lock.lock().unwrap(), 11);
+    }
+
+    #[test]
+    fn poisoned() {
+        let lock = HybridMutex::new(1);
+        let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+            let _guard = lock.lock().unwrap();
+            panic!("poisoning");
+        }));
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+        assert_eq!(*lock.lock().unwrap_err().into_inner(), 1);
+        assert!(lock.into_inner().is_err());
+    }
+
+    #[test]
+    fn random_interleavings() {
+        // Coroutines hold the lock across yields and are resumed in an order drawn from the
+        // seed, nobody gets in while it is held and no update is lost
+        for seed in 0..200 {
+            let mut rng = Rng::new(seed);
+            let lock = Rc::new(HybridMutex::new(0));
+            let inside = Rc::new(Cell::new(0));
+
+            let mut coros = (0..rng.gen_range(2, 6))
+                .map(|_| {
+                    let (lock, inside) = (lock.clone(), inside.clone());
+                    let rounds = rng.gen_range(1, 5) as usize;
+                    let holds = rng.gen_range(0, 3);
+                    Coroutine::spawn(move |coro, _| {
+                        for _ in 0..rounds {
+                            let mut guard = lock.lock().unwrap();
+                            inside.set(inside.get() + 1);
+                            assert_eq!(inside.get(), 1, "lock held twice");
+                            for _ in 0..holds {
+                                coro.yield_with(0);
+                            }
+                            *guard += 1;
+                            inside.set(inside.get() - 1);
+                            drop(guard);
+                            coro.yield_with(0);
+                        }
+                        rounds
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut expected = 0;
+            while !coros.is_empty() {
+                let i = rng.gen_range(0, coros.len() as u64) as usize;
+                let rounds = coros[i].resume(0).unwrap();
+                if coros[i].is_finished() {
+                    expected += rounds;
+                    coros.swap_remove(i);
+                }
+            }
+            assert_eq!(*lock.lock().unwrap(), expected, "seed {}", seed);
+        }
+    }
+}
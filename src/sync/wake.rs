@@ -0,0 +1,65 @@
+//! Wake-up file descriptor shared by the queues of this module
+
+use std::io;
+use std::os::unix::io::RawFd;
+
+use libc;
+
+/// Readable whenever the waiting coroutine should look at its queue again
+pub(crate) struct Wake {
+    pub(crate) read: RawFd,
+    write: RawFd,
+}
+
+impl Wake {
+    #[cfg(target_os = "linux")]
+    pub(crate) fn new() -> io::Result<Wake> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Wake { read: fd, write: fd })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn new() -> io::Result<Wake> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for &fd in &fds {
+            unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+        }
+        Ok(Wake {
+            read: fds[0],
+            write: fds[1],
+        })
+    }
+
+    pub(crate) fn notify(&self) {
+        // A full pipe or a saturated counter already wakes the receiver
+        let one = 1u64;
+        unsafe {
+            libc::write(self.write, &one as *const u64 as *const _, 8);
+        }
+    }
+
+    pub(crate) fn drain(&self) {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.read, buf.as_mut_ptr() as *mut _, buf.len()) } > 0 {}
+    }
+}
+
+impl Drop for Wake {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            if self.write != self.read {
+                libc::close(self.write);
+            }
+        }
+    }
+}
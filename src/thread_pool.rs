@@ -0,0 +1,394 @@
+//! Multiplexing CPU-bound coroutines over a fixed number of threads
+//!
+//! No I/O reactor is involved: workers pull coroutines from a shared run queue, resume them
+//! once, and put them back at the end of the queue if they yielded. A coroutine may therefore
+//! be resumed by a different thread after every yield.
+//!
+//! ```rust
+//! use coroutine::thread_pool::ThreadPoolRunner;
+//!
+//! let pool = ThreadPoolRunner::new(4);
+//! for i in 0..8 {
+//!     pool.spawn(move |coro, _| {
+//!         let mut sum = 0;
+//!         for j in 0..i {
+//!             sum += j;
+//!             coro.yield_with(0);
+//!         }
+//!         sum
+//!     });
+//! }
+//!
+//! let results = pool.join_all();
+//! assert_eq!(results[4].as_ref().unwrap(), &6);
+//! ```
+//!
+//! A worker which runs out of coroutines calls the `on_idle` callback before it goes to sleep,
+//! which is a good place for incremental cleanup:
+//!
+//! ```rust
+//! use std::time::Duration;
+//! use coroutine::stack::{StackPool, Trim};
+//! use coroutine::thread_pool::ThreadPoolRunner;
+//!
+//! let pool = ThreadPoolRunner::new(2)
+//!     .on_idle(Duration::from_millis(1), |_budget| StackPool::trim(Trim::Advise));
+//! pool.spawn(|_, _| 0);
+//! pool.join_all();
+//! ```
+
+use std::collections::VecDeque;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe, Location};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use asymmetric::{self, Coroutine, Handle, Sendable};
+use options::Options;
+
+// A coroutine is only ever resumed by one worker at a time, see `Sendable` for what the
+// coroutine must not do across yields
+struct Task {
+    id: usize,
+    handle: Handle<Sendable>,
+}
+
+type IdleFn = dyn Fn(Duration) + Send + Sync;
+
+struct State {
+    queue: VecDeque<Task>,
+    results: Vec<Option<::Result<usize>>>,
+    outstanding: usize,
+    shutdown: bool,
+    leaked: Vec<Leaked>,
+    on_idle: Option<(Duration, Arc<IdleFn>)>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    work: Condvar,
+    done: Condvar,
+}
+
+/// A coroutine which had not finished when its pool shut down
+#[derive(Debug, Clone)]
+pub struct Leaked {
+    /// Id of the coroutine
+    pub id: usize,
+    /// Name of the coroutine
+    pub name: Option<String>,
+    /// Where it has been spawned
+    pub spawn_location: &'static Location<'static>,
+    /// State it was left in
+    pub state: asymmetric::State,
+}
+
+/// Runs coroutines on a pool of worker threads
+pub struct ThreadPoolRunner {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPoolRunner {
+    /// Start a pool with `threads` worker threads
+    pub fn new(threads: usize) -> ThreadPoolRunner {
+        assert!(threads > 0, "a thread pool needs at least one thread");
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                results: Vec::new(),
+                outstanding: 0,
+                shutdown: false,
+                leaked: Vec::new(),
+                on_idle: None,
+            }),
+            work: Condvar::new(),
+            done: Condvar::new(),
+        });
+
+        let workers = (0..threads)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("coroutine-pool-{}", i))
+                    .spawn(move || worker(&shared))
+                    .expect("failed to spawn a pool thread")
+            })
+            .collect();
+
+        ThreadPoolRunner { shared, workers }
+    }
+
+    /// Call `f` on a worker which has run out of coroutines, before it goes to sleep
+    ///
+    /// `f` gets `budget`, the time it should return within; coroutines spawned meanwhile wait
+    /// for it. It is called once per idle period, not again until the worker has resumed a
+    /// coroutine. A panic inside of `f` is logged and otherwise ignored.
+    pub fn on_idle<F>(self, budget: Duration, f: F) -> ThreadPoolRunner
+        where F: Fn(Duration) + Send + Sync + 'static
+    {
+        self.shared.state.lock().unwrap().on_idle = Some((budget, Arc::new(f)));
+        self
+    }
+
+    /// Spawn a coroutine with default options onto the pool
+    #[track_caller]
+    pub fn spawn<F>(&self, f: F)
+        where F: FnOnce(&mut Coroutine, usize) -> usize + Send + 'static
+    {
+        self.spawn_opts(f, Options::default())
+    }
+
+    /// Spawn a coroutine with `Options` onto the pool
+    #[track_caller]
+    pub fn spawn_opts<F>(&self, f: F, opts: Options)
+        where F: FnOnce(&mut Coroutine, usize) -> usize + Send + 'static
+    {
+        let handle = Coroutine::spawn_send_opts(f, opts);
+
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.results.len();
+        state.results.push(None);
+        state.outstanding += 1;
+        state.queue.push_back(Task { id, handle });
+        self.shared.work.notify_one();
+    }
+
+    /// Wait until all spawned coroutines have finished
+    ///
+    /// Returns the result of the final resume of every coroutine spawned since the last call,
+    /// in spawn order.
+    pub fn join_all(&self) -> Vec<::Result<usize>> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.outstanding > 0 {
+            state = self.shared.done.wait(state).unwrap();
+        }
+
+        mem::take(&mut state.results)
+            .into_iter()
+            .map(|r| r.expect("coroutine finished without a result"))
+            .collect()
+    }
+
+    /// Stop the workers, unwinding the coroutines which have not finished
+    ///
+    /// Returns those coroutines, an empty list means nothing has leaked. Dropping the pool
+    /// does the same and logs them as warnings.
+    pub fn shutdown(mut self) -> Vec<Leaked> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Vec<Leaked> {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.work.notify_all();
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        mem::take(&mut self.shared.state.lock().unwrap().leaked)
+    }
+}
+
+impl Drop for ThreadPoolRunner {
+    /// Unfinished coroutines are unwound on the worker threads
+    fn drop(&mut self) {
+        for leaked in self.stop() {
+            warn!("Coroutine `{}` (spawned at {}): unfinished at pool shutdown in {:?}",
+                  leaked.name.as_ref().map_or("<unnamed>", |n| &n[..]),
+                  leaked.spawn_location,
+                  leaked.state);
+        }
+    }
+}
+
+fn worker(shared: &Shared) {
+    let mut idle = false;
+    loop {
+        let mut task = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.shutdown {
+                    // Drop the remaining coroutines outside of the lock
+                    let queue = mem::take(&mut state.queue);
+                    let leaked = queue.iter().map(|task| {
+                        Leaked {
+                            id: task.handle.id(),
+                            name: task.handle.name().cloned(),
+                            spawn_location: task.handle.spawn_location(),
+                            state: task.handle.state(),
+                        }
+                    });
+                    state.leaked.extend(leaked);
+                    drop(state);
+                    drop(queue);
+                    return;
+                }
+
+                if let Some(task) = state.queue.pop_front() {
+                    break task;
+                }
+
+                match state.on_idle.clone() {
+                    Some((budget, f)) if !idle => {
+                        idle = true;
+                        drop(state);
+                        if panic::catch_unwind(AssertUnwindSafe(|| f(budget))).is_err() {
+                            warn!("Thread pool idle callback panicked");
+                        }
+                        state = shared.state.lock().unwrap();
+                    }
+                    _ => state = shared.work.wait(state).unwrap(),
+                }
+            }
+        };
+        idle = false;
+
+        let result = task.handle.resume(0);
+
+        let mut state = shared.state.lock().unwrap();
+        if task.handle.is_finished() {
+            state.results[task.id] = Some(result);
+            state.outstanding -= 1;
+            if state.outstanding == 0 {
+                shared.done.notify_all();
+            }
+            drop(state);
+            // Release the stack outside of the lock
+            drop(task);
+        } else {
+            state.queue.push_back(task);
+        }
+    }
+}
+
+// Driver of `#[coroutine::main(threads = N)]`
+#[doc(hidden)]
+pub fn __run_main<R: Send + 'static>(f: fn(&mut Coroutine) -> R, threads: usize) -> R {
+    let result = Arc::new(Mutex::new(None));
+    let slot = result.clone();
+
+    let pool = ThreadPoolRunner::new(threads);
+    pool.spawn(move |coro, _| {
+        *slot.lock().unwrap() = Some(f(coro));
+        0
+    });
+    let outcome = pool.join_all().pop().unwrap();
+    drop(pool);
+
+    if let Err(::Error::Panicking(err)) = outcome {
+        panic::resume_unwind(err);
+    }
+    let result = result.lock().unwrap().take();
+    result.expect("root coroutine failed")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn join_all() {
+        let pool = ThreadPoolRunner::new(3);
+        for i in 0..16 {
+            pool.spawn(move |coro, _| {
+                for _ in 0..i {
+                    coro.yield_with(0);
+                }
+                i * 2
+            });
+        }
+
+        let results = pool.join_all()
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results, (0..16).map(|i| i * 2).collect::<Vec<_>>());
+        assert!(pool.join_all().is_empty());
+    }
+
+    #[test]
+    fn panicked() {
+        let pool = ThreadPoolRunner::new(2);
+        pool.spawn(|_, _| panic!("in pool"));
+        pool.spawn(|_, _| 1);
+
+        let results = pool.join_all();
+        assert!(results[0].is_err());
+        assert_eq!(results[1].as_ref().unwrap(), &1);
+    }
+
+    #[test]
+    fn on_idle() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let pool = ThreadPoolRunner::new(1).on_idle(Duration::from_millis(1), move |budget| {
+            assert_eq!(budget, Duration::from_millis(1));
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("in idle callback");
+            }
+        });
+
+        // Once per idle period, the panic does not take the worker down
+        for _ in 0..3 {
+            let before = calls.load(Ordering::SeqCst);
+            pool.spawn(|_, _| 1);
+            pool.join_all();
+            while calls.load(Ordering::SeqCst) == before {
+                thread::yield_now();
+            }
+        }
+        assert_eq!(pool.shutdown().len(), 0);
+    }
+
+    #[test]
+    fn shutdown() {
+        let pool = ThreadPoolRunner::new(2);
+        pool.spawn(|_, _| 1);
+        pool.join_all();
+
+        let opts = Options {
+            name: Some("leaky".to_owned()),
+            ..Options::default()
+        };
+        pool.spawn_opts(|coro, _| {
+                            loop {
+                                coro.yield_with(0);
+                            }
+                        },
+                        opts);
+
+        let leaked = pool.shutdown();
+        assert_eq!(leaked.len(), 1);
+        assert_eq!(leaked[0].name, Some("leaky".to_owned()));
+        assert_eq!(leaked[0].spawn_location.file(), file!());
+    }
+
+    #[test]
+    fn drop_unfinished() {
+        struct Guard(Arc<AtomicUsize>);
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let dropped = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPoolRunner::new(1);
+            let guard = Guard(dropped.clone());
+            pool.spawn(move |coro, _| {
+                let _guard = guard;
+                loop {
+                    coro.yield_with(0);
+                }
+            });
+        }
+
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,54 @@
+//! Background thread raising preemption flags once their deadline has passed
+
+use std::sync::{Arc, Condvar, Mutex, Once, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Instant;
+
+static DEADLINES: Mutex<Vec<(Instant, Weak<AtomicBool>)>> = Mutex::new(Vec::new());
+static CHANGED: Condvar = Condvar::new();
+static START: Once = Once::new();
+
+/// Returns a flag which will be set to `true` at `deadline`
+///
+/// Dropping the returned flag cancels the deadline.
+pub fn arm(deadline: Instant) -> Arc<AtomicBool> {
+    START.call_once(|| {
+        thread::Builder::new()
+            .name("coroutine-watchdog".to_owned())
+            .spawn(run)
+            .expect("failed to spawn the watchdog thread");
+    });
+
+    let flag = Arc::new(AtomicBool::new(false));
+    DEADLINES.lock().unwrap().push((deadline, Arc::downgrade(&flag)));
+    CHANGED.notify_one();
+    flag
+}
+
+fn run() {
+    let mut deadlines = DEADLINES.lock().unwrap();
+    loop {
+        let now = Instant::now();
+
+        deadlines.retain(|&(deadline, ref flag)| {
+            match flag.upgrade() {
+                Some(flag) => {
+                    if deadline <= now {
+                        flag.store(true, Ordering::Release);
+                        false
+                    } else {
+                        true
+                    }
+                }
+                // Cancelled
+                None => false,
+            }
+        });
+
+        deadlines = match deadlines.iter().map(|&(deadline, _)| deadline).min() {
+            Some(next) => CHANGED.wait_timeout(deadlines, next - now).unwrap().0,
+            None => CHANGED.wait(deadlines).unwrap(),
+        };
+    }
+}
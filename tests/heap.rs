@@ -0,0 +1,49 @@
+extern crate coroutine;
+
+use std::mem;
+
+use coroutine::asymmetric::Coroutine;
+use coroutine::heap::{self, TrackingAllocator};
+
+#[global_allocator]
+static ALLOC: TrackingAllocator = TrackingAllocator::new();
+
+#[test]
+fn attributes_live_bytes() {
+    let mut kept = Coroutine::spawn(|coro, _| {
+        let mut buf = Vec::<u8>::with_capacity(100);
+        coro.yield_with(0);
+        // Grown and still owned by the coroutine which made it
+        buf.reserve_exact(1000);
+        coro.yield_with(0);
+        mem::forget(buf);
+        0
+    });
+    let mut freed = Coroutine::spawn(|_, _| {
+        drop(vec![0u8; 1 << 20]);
+        0
+    });
+
+    kept.resume(0).unwrap();
+    assert_eq!(heap::live(kept.id()), 100);
+    kept.resume(0).unwrap();
+    assert_eq!(heap::live(kept.id()), 1000);
+    kept.resume(0).unwrap();
+    freed.resume(0).unwrap();
+
+    // Leaked past the end of the coroutine
+    assert_eq!(heap::live(kept.id()), 1000);
+    assert_eq!(heap::live(freed.id()), 0);
+    assert!(heap::by_coroutine().contains(&(kept.id(), 1000)));
+}
+
+#[test]
+fn freed_elsewhere() {
+    let mut coro = Coroutine::spawn(|_, _| Box::into_raw(Box::new([0u64; 64])) as usize);
+    let id = coro.id();
+    let ptr = coro.resume(0).unwrap() as *mut [u64; 64];
+    assert_eq!(heap::live(id), 512);
+
+    drop(unsafe { Box::from_raw(ptr) });
+    assert_eq!(heap::live(id), 0);
+}
@@ -0,0 +1,63 @@
+extern crate coroutine;
+
+use std::time::{Duration, Instant};
+
+use coroutine::asymmetric::Coroutine;
+use coroutine::fd;
+
+#[coroutine::test]
+fn without_coroutine() {
+    assert_eq!(1 + 1, 2);
+}
+
+#[coroutine::test(timeout_ms = 5_000)]
+fn sleeps(coro: &mut Coroutine) {
+    let start = Instant::now();
+    fd::sleep(coro, Duration::from_millis(10)).unwrap();
+    coro.yield_with(0);
+    assert!(start.elapsed() >= Duration::from_millis(10));
+}
+
+#[coroutine::test]
+fn returns_result(coro: &mut Coroutine) -> Result<(), String> {
+    fd::sleep(coro, Duration::from_millis(1)).map_err(|e| e.to_string())
+}
+
+#[coroutine::test(timeout_ms = 20)]
+#[should_panic(expected = "timed out")]
+fn times_out(coro: &mut Coroutine) {
+    fd::sleep(coro, Duration::from_secs(3600)).unwrap();
+}
+
+// Not interrupted while it does not wait in `fd`, only waiting afterwards would fail it
+#[coroutine::test(timeout_ms = 20)]
+fn busy_past_timeout(coro: &mut Coroutine) {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(50) {
+        coro.yield_with(0);
+    }
+}
+
+#[coroutine::test]
+#[should_panic(expected = "inside")]
+fn panics() {
+    panic!("inside");
+}
+
+#[coroutine::main]
+fn on_this_thread(coro: &mut Coroutine) -> usize {
+    fd::sleep(coro, Duration::from_millis(1)).unwrap();
+    1
+}
+
+#[coroutine::main(threads = 2)]
+fn on_pool(coro: &mut Coroutine) -> usize {
+    coro.yield_with(0);
+    2
+}
+
+#[test]
+fn main_runs_root() {
+    assert_eq!(on_this_thread(), 1);
+    assert_eq!(on_pool(), 2);
+}